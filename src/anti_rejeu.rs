@@ -0,0 +1,107 @@
+use std::error::Error;
+
+use log::{debug, warn};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::chrono::{Duration, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
+use millegrilles_common_rust::mongo_dao::{MongoDao, verifier_erreur_duplication_mongo};
+
+use crate::constantes::*;
+
+/// Fenetre d'acceptation d'un message inter-millegrille selon son estampille.
+const FENETRE_REJEU_HEURES: i64 = 72;
+/// Tolerance pour une estampille dans le futur (horloges desynchronisees).
+const DECALAGE_FUTUR_MINUTES: i64 = 5;
+
+pub const RAISON_REJEU_EXPIRE: &str = "expire";
+pub const RAISON_REJEU_DEJA_RECU: &str = "dejaRecu";
+
+/// Verifie l'estampille du message signe par l'expediteur. L'enveloppe de transfert est
+/// regeneree a chaque transmission, elle ne peut pas etre utilisee.
+pub fn verifier_estampille(message: &MessageMilleGrille) -> Option<&'static str> {
+    let now = Utc::now();
+    let estampille = message.estampille.get_datetime();
+    if *estampille < now - Duration::hours(FENETRE_REJEU_HEURES) ||
+        *estampille > now + Duration::minutes(DECALAGE_FUTUR_MINUTES) {
+        warn!("verifier_estampille Message {} estampille {:?} hors de la fenetre d'acceptation", message.id, estampille);
+        return Some(RAISON_REJEU_EXPIRE)
+    }
+    None
+}
+
+/// Verifie l'estampille du message signe et s'il a deja ete accepte pour un des destinataires.
+/// La cle est (message, destinataire) : les enveloppes bcc d'un meme message sont acceptees,
+/// un message deja recu emballe dans une nouvelle enveloppe de transfert est refuse.
+pub async fn verifier_rejeu<M>(middleware: &M, message: &MessageMilleGrille, destinataires: &Vec<String>)
+    -> Result<Option<&'static str>, Box<dyn Error>>
+    where M: MongoDao
+{
+    if let Some(raison) = verifier_estampille(message) {
+        return Ok(Some(raison))
+    }
+
+    let filtre = doc! {CHAMP_MESSAGE_ID: &message.id, "destinataire": {"$in": destinataires}};
+    let collection = middleware.get_collection(NOM_COLLECTION_ANTI_REJEU)?;
+    if collection.count_documents(filtre, None).await? > 0 {
+        warn!("verifier_rejeu Message {} deja recu pour un destinataire de {:?}", message.id, destinataires);
+        return Ok(Some(RAISON_REJEU_DEJA_RECU))
+    }
+
+    Ok(None)
+}
+
+/// Conserve la reception du message pour chaque destinataire. Retourne false si le message a deja
+/// ete conserve pour un destinataire (reception concurrente), les entrees ajoutees sont retirees.
+pub async fn enregistrer_reception<M>(middleware: &M, message: &MessageMilleGrille, destinataires: &Vec<String>)
+    -> Result<bool, Box<dyn Error>>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_ANTI_REJEU)?;
+    let mut ajoutes = Vec::new();
+    for destinataire in destinataires {
+        let doc_rejeu = doc! {
+            CHAMP_MESSAGE_ID: &message.id,
+            "destinataire": destinataire,
+            "idmg": message.origine.clone(),
+            "estampille": message.estampille.get_datetime().clone(),
+            CHAMP_CREATION: Utc::now(),
+        };
+        match collection.insert_one(doc_rejeu, None).await {
+            Ok(_) => ajoutes.push(destinataire.to_owned()),
+            Err(e) => {
+                if ! ajoutes.is_empty() {
+                    retirer_reception(middleware, message.id.as_str(), &ajoutes).await?;
+                }
+                if verifier_erreur_duplication_mongo(&*e.kind) {
+                    warn!("enregistrer_reception Message {} deja recu pour {} (concurrent)", message.id, destinataire);
+                    return Ok(false)
+                }
+                Err(e)?
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Retire la reception d'un message (echec de traitement, permet un retry).
+pub async fn retirer_reception<M>(middleware: &M, message_id: &str, destinataires: &Vec<String>)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! {CHAMP_MESSAGE_ID: message_id, "destinataire": {"$in": destinataires}};
+    let collection = middleware.get_collection(NOM_COLLECTION_ANTI_REJEU)?;
+    collection.delete_many(filtre, None).await?;
+    Ok(())
+}
+
+/// Retire les messages hors de la fenetre d'acceptation, ils sont refuses par l'estampille.
+pub async fn entretien_anti_rejeu<M>(middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let date_expiration = Utc::now() - Duration::hours(FENETRE_REJEU_HEURES) - Duration::minutes(DECALAGE_FUTUR_MINUTES);
+    let collection = middleware.get_collection(NOM_COLLECTION_ANTI_REJEU)?;
+    let resultat = collection.delete_many(doc! {"estampille": {"$lt": date_expiration}}, None).await?;
+    debug!("entretien_anti_rejeu {} messages retires", resultat.deleted_count);
+    Ok(())
+}
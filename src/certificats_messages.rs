@@ -0,0 +1,68 @@
+use std::error::Error;
+
+use log::debug;
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{EnveloppeCertificat, ValidateurX509};
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+
+use crate::constantes::*;
+
+/// Certificat (chaine PEM) ayant signe un message recu ou emis. Conserve pour l'affichage des
+/// messages apres l'expiration du certificat ou son retrait de la cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocCertificatMessage {
+    pub fingerprint: String,
+    pub certificat: Vec<String>,
+    /// Certificat CA d'une millegrille tierce.
+    pub millegrille: Option<String>,
+}
+
+/// Conserve le certificat d'un message. Le fingerprint correspond a la pubkey du message.
+pub async fn conserver_certificat_message<M>(middleware: &M, certificat: &EnveloppeCertificat)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let fingerprint = certificat.fingerprint.as_str();
+    let filtre = doc! { "fingerprint": fingerprint };
+    let ops = doc! {
+        "$setOnInsert": {
+            "fingerprint": fingerprint,
+            "certificat": certificat.get_pem_vec_extracted(),
+            "millegrille": certificat.get_pem_ca()?,
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_CERTIFICATS)?;
+    collection.update_one(filtre, ops, Some(options)).await?;
+    Ok(())
+}
+
+/// Charge le certificat d'un message. Utilise la cache du middleware et le certificat conserve
+/// dans Messagerie en dernier recours.
+pub async fn charger_certificat_message<M>(middleware: &M, fingerprint: &str)
+    -> Result<Option<DocCertificatMessage>, Box<dyn Error>>
+    where M: ValidateurX509 + MongoDao
+{
+    if let Some(inner) = middleware.get_certificat(fingerprint).await {
+        return Ok(Some(DocCertificatMessage {
+            fingerprint: fingerprint.to_owned(),
+            certificat: inner.get_pem_vec_extracted(),
+            millegrille: inner.get_pem_ca()?,
+        }))
+    }
+
+    let collection = middleware.get_collection(NOM_COLLECTION_CERTIFICATS)?;
+    match collection.find_one(doc! { "fingerprint": fingerprint }, None).await? {
+        Some(d) => {
+            debug!("charger_certificat_message Certificat {} charge de {}", fingerprint, NOM_COLLECTION_CERTIFICATS);
+            Ok(Some(convertir_bson_deserializable(d)?))
+        },
+        None => Ok(None)
+    }
+}
@@ -0,0 +1,149 @@
+use std::error::Error;
+
+use log::info;
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::constantes::Securite::L2Prive;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use millegrilles_common_rust::serde::Deserialize;
+use millegrilles_common_rust::serde_json::json;
+
+use crate::constantes::*;
+use crate::quarantaine::calculer_index_adresse;
+
+#[derive(Clone, Debug, Deserialize)]
+struct DocCleExpediteur {
+    fingerprint: String,
+    fingerprint_nouveau: Option<String>,
+}
+
+/// Index de l'expediteur dans les cles epinglees de l'usager (sel user_id).
+pub fn calculer_index_cle_expediteur(user_id: &str, adresse: &str) -> String {
+    calculer_index_adresse(user_id, adresse)
+}
+
+/// Retourne true si la cle du message est differente de la cle epinglee.
+fn cle_changee(doc_cle: &DocCleExpediteur, fingerprint: &str) -> bool {
+    doc_cle.fingerprint.as_str() != fingerprint
+}
+
+/// Verifie la cle (fingerprint) d'un expediteur pour un usager. La premiere cle vue pour
+/// l'adresse est epinglee (trust on first use). Retourne le fingerprint epingle lorsque la cle
+/// du message est differente. L'adresse est conservee sous forme d'index (sel user_id).
+pub async fn verifier_cle_expediteur<M>(
+    middleware: &M, user_id: &str, adresse: &str, fingerprint: &str, estampille: &DateTime<Utc>
+)
+    -> Result<Option<String>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let index_adresse = calculer_index_cle_expediteur(user_id, adresse);
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_INDEX_EXPEDITEUR: &index_adresse };
+    let collection = middleware.get_collection(NOM_COLLECTION_CLES_EXPEDITEURS)?;
+
+    // Epingler la cle si l'expediteur est inconnu. L'upsert evite la course entre deux receptions
+    // simultanees (index unique user_id/index_expediteur).
+    let ops = doc! {
+        "$setOnInsert": {
+            "fingerprint": fingerprint,
+            CHAMP_CREATION: estampille.clone(),
+        },
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let doc_cle: DocCleExpediteur = match collection.find_one_and_update(filtre.clone(), ops, Some(options)).await? {
+        Some(d) => convertir_bson_deserializable(d)?,
+        None => Err(format!("cles_expediteurs.verifier_cle_expediteur Aucun document apres upsert pour expediteur {}", index_adresse))?
+    };
+
+    if ! cle_changee(&doc_cle, fingerprint) {
+        return Ok(None)
+    }
+
+    info!("verifier_cle_expediteur Usager {} cle changee pour expediteur {} ({} -> {})",
+        user_id, index_adresse, doc_cle.fingerprint, fingerprint);
+    if doc_cle.fingerprint_nouveau.as_ref().map(|f| f.as_str()) != Some(fingerprint) {
+        let ops = doc! {
+            "$set": {"fingerprint_nouveau": fingerprint},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        };
+        collection.update_one(filtre, ops, None).await?;
+    }
+
+    Ok(Some(doc_cle.fingerprint))
+}
+
+/// Accepte la nouvelle cle d'un expediteur, elle remplace la cle epinglee. Seul l'expediteur
+/// indique est modifie (une meme cle peut etre presentee pour plusieurs adresses).
+pub async fn accepter_cle_expediteur<M>(middleware: &M, user_id: &str, index_expediteur: &str, fingerprint: &str)
+    -> Result<u64, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_INDEX_EXPEDITEUR: index_expediteur,
+        "fingerprint_nouveau": fingerprint,
+    };
+    let ops = doc! {
+        "$set": {"fingerprint": fingerprint},
+        "$unset": {"fingerprint_nouveau": true},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_CLES_EXPEDITEURS)?;
+    let resultat = collection.update_one(filtre, ops, None).await?;
+    Ok(resultat.modified_count)
+}
+
+/// Evenement pour l'usager (front-end, notifications) lorsqu'un message est signe par une
+/// cle differente de la cle epinglee de l'expediteur.
+pub async fn emettre_evenement_cle_changee<M>(
+    middleware: &M, user_id: &str, message_id: &str, fingerprint: &str, fingerprint_precedent: &str
+)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_CLE_EXPEDITEUR_CHANGEE)
+        .exchanges(vec![L2Prive])
+        .partition(user_id)
+        .build();
+    let evenement = json!({
+        "message_id": message_id,
+        "fingerprint": fingerprint,
+        "fingerprint_precedent": fingerprint_precedent,
+    });
+    middleware.emettre_evenement(routage, &evenement).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_cles_expediteurs {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    #[test]
+    fn test_cle_epinglee() {
+        setup("test_cle_epinglee");
+        // Document retourne par l'upsert, cle epinglee par la reception courante ou concurrente
+        let doc_cle: DocCleExpediteur = convertir_bson_deserializable(doc! {
+            CHAMP_USER_ID: "zUsager1",
+            CHAMP_INDEX_EXPEDITEUR: "zIndex1",
+            "fingerprint": "zFingerprint1",
+        }).expect("convertir");
+        assert!(! cle_changee(&doc_cle, "zFingerprint1"));
+        assert!(cle_changee(&doc_cle, "zFingerprint2"));
+    }
+
+    #[test]
+    fn test_index_cle_expediteur() {
+        setup("test_index_cle_expediteur");
+        let index_1 = calculer_index_cle_expediteur("zUsager1", "@expediteur/millegrille2.com");
+        let index_2 = calculer_index_cle_expediteur("zUsager2", "@expediteur/millegrille2.com");
+        assert_eq!(index_1, calculer_index_cle_expediteur("zUsager1", "@expediteur/millegrille2.com"));
+        assert_ne!(index_1, index_2);
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::chiffrage_cle::CommandeSauvegarderCle;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::common_messages::MessageReponse;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
+use millegrilles_common_rust::recepteur_messages::TypeMessage;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::commandes::{PreuvePossessionCles, verifier_preuves_cles};
+use crate::constantes::*;
+use crate::pompe_messages::emettre_evenement_pompe;
+
+pub const TYPE_CLE_MESSAGE: &str = "message";
+pub const TYPE_CLE_ATTACHMENT: &str = "attachment";
+
+/// Delai maximal entre deux tentatives de sauvegarde d'une cle (minutes).
+const DELAI_RETRY_MAX_MINUTES: i64 = 60;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocCleOutbox {
+    pub cle_id: String,
+    pub type_cle: String,
+    pub message_id: Option<String>,
+    pub partition: Option<String>,
+    pub commande: Document,
+    pub confirme: bool,
+    pub tentatives: Option<i64>,
+    pub verification_preuve: Option<VerificationPreuveOutbox>,
+}
+
+/// Preuve de possession d'une cle d'attachment qui n'a pu etre verifiee lors de la reception
+/// (MaitreDesCles non disponible). Elle est verifiee avant de soumettre la cle.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VerificationPreuveOutbox {
+    pub fingerprint: String,
+    pub preuves: HashMap<String, PreuvePossessionCles>,
+}
+
+/// Conserve une cle dans l'outbox locale lorsque MaitreDesCles ne repond pas. La cle sera
+/// soumise de nouveau par traiter_cles_outbox.
+pub async fn conserver_cle_outbox<M, S>(
+    middleware: &M, cle_id: &str, type_cle: &str, message_id: Option<&str>, partition: Option<String>, commande: &S
+)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao, S: Serialize
+{
+    debug!("conserver_cle_outbox Conserver cle {} (type {}) dans l'outbox", cle_id, type_cle);
+    let commande = convertir_to_bson(commande)?;
+    let now = Utc::now();
+
+    let filtre = doc! { CHAMP_CLE_ID: cle_id };
+    let ops = doc! {
+        "$set": {
+            "type_cle": type_cle,
+            CHAMP_MESSAGE_ID: message_id,
+            "partition": partition,
+            "commande": commande,
+            CHAMP_CONFIRME: false,
+            CHAMP_PROCHAINE_TENTATIVE: now + Duration::minutes(1),
+        },
+        "$setOnInsert": {
+            CHAMP_CREATION: now,
+            "tentatives": 0,
+        },
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_CLES_OUTBOX)?;
+    collection.update_one(filtre, ops, Some(options)).await?;
+
+    Ok(())
+}
+
+/// Retourne true si la cle du message n'est pas encore confirmee par MaitreDesCles.
+pub async fn cle_message_en_attente<M>(middleware: &M, message_id: &str) -> Result<bool, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_MESSAGE_ID: message_id, "type_cle": TYPE_CLE_MESSAGE, CHAMP_CONFIRME: false };
+    let collection = middleware.get_collection(NOM_COLLECTION_CLES_OUTBOX)?;
+    Ok(collection.find_one(filtre, None).await?.is_some())
+}
+
+/// Conserve la preuve de possession a verifier pour une cle d'attachment de l'outbox.
+pub async fn conserver_preuve_outbox<M>(middleware: &M, cle_id: &str, verification: &VerificationPreuveOutbox)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_CLE_ID: cle_id };
+    let ops = doc! {
+        "$set": {"verification_preuve": convertir_to_bson(verification)?},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_CLES_OUTBOX)?;
+    collection.update_one(filtre, ops, None).await?;
+    Ok(())
+}
+
+/// Retourne true si au moins une cle d'attachment (fuuid) n'est pas encore confirmee par MaitreDesCles.
+pub async fn cles_attachments_en_attente<M>(middleware: &M, fuuids: Option<&Vec<String>>) -> Result<bool, Box<dyn Error>>
+    where M: MongoDao
+{
+    let fuuids = match fuuids {
+        Some(f) if !f.is_empty() => f,
+        _ => return Ok(false)
+    };
+    let filtre = doc! { CHAMP_CLE_ID: {"$in": fuuids}, "type_cle": TYPE_CLE_ATTACHMENT, CHAMP_CONFIRME: false };
+    let collection = middleware.get_collection(NOM_COLLECTION_CLES_OUTBOX)?;
+    Ok(collection.find_one(filtre, None).await?.is_some())
+}
+
+/// Marque le message (outgoing) avec le statut cle en attente.
+pub async fn marquer_message_cle_pending<M>(middleware: &M, message_id: &str, pending: bool) -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let ops = match pending {
+        true => doc! {
+            "$set": {CHAMP_CLE_PENDING: true},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        },
+        false => doc! {
+            "$unset": {CHAMP_CLE_PENDING: true},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        }
+    };
+
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+    collection.update_one(doc! {"message.id": message_id}, ops.clone(), None).await?;
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    collection.update_one(doc! {CHAMP_MESSAGE_ID: message_id}, ops, None).await?;
+
+    Ok(())
+}
+
+/// Soumet de nouveau les cles de l'outbox a MaitreDesCles.
+pub async fn traiter_cles_outbox<M>(middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("traiter_cles_outbox Debut");
+    let now = Utc::now();
+    let filtre = doc! { CHAMP_CONFIRME: false, CHAMP_PROCHAINE_TENTATIVE: {"$lte": now} };
+    let options = FindOptions::builder()
+        .sort(doc! {CHAMP_PROCHAINE_TENTATIVE: 1})
+        .limit(100)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_CLES_OUTBOX)?;
+
+    let mut messages_confirmes = false;
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let doc_cle: DocCleOutbox = match convertir_bson_deserializable(r?) {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("traiter_cles_outbox Document de cle invalide, skip : {:?}", e);
+                continue
+            }
+        };
+        let cle_id = doc_cle.cle_id.as_str();
+
+        // Une cle de type inconnu est traitee comme un echec (backoff), le traitement continue
+        let resultat = match doc_cle.type_cle.as_str() {
+            TYPE_CLE_MESSAGE => soumettre_cle_message(middleware, &doc_cle).await,
+            TYPE_CLE_ATTACHMENT => soumettre_cle_attachment(middleware, &doc_cle).await,
+            _ => Err(format!("cles_outbox.traiter_cles_outbox Type de cle inconnu : {}", doc_cle.type_cle).into())
+        };
+
+        let filtre = doc! { CHAMP_CLE_ID: cle_id };
+        match resultat {
+            Ok(()) => {
+                info!("traiter_cles_outbox Cle {} confirmee par MaitreDesCles", cle_id);
+                let ops = doc! {
+                    "$set": {CHAMP_CONFIRME: true},
+                    "$unset": {CHAMP_PROCHAINE_TENTATIVE: true},
+                    "$currentDate": {CHAMP_MODIFICATION: true},
+                };
+                collection.update_one(filtre, ops, None).await?;
+
+                if let Some(message_id) = doc_cle.message_id.as_ref() {
+                    if doc_cle.type_cle.as_str() == TYPE_CLE_MESSAGE {
+                        marquer_message_cle_pending(middleware, message_id, false).await?;
+                    }
+                }
+                messages_confirmes = true;
+            },
+            Err(e) => {
+                warn!("traiter_cles_outbox Echec sauvegarde cle {} : {:?}", cle_id, e);
+                let ops = ops_echec_cle(&doc_cle, format!("{:?}", e), &now);
+                collection.update_one(filtre, ops, None).await?;
+            }
+        }
+    }
+
+    if messages_confirmes {
+        // Debloquer la livraison des messages
+        if let Err(e) = emettre_evenement_pompe(middleware, None).await {
+            error!("traiter_cles_outbox Erreur declencher pompe de messages : {:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Conserve l'erreur et planifie la prochaine tentative. Backoff exponentiel, maximum
+/// DELAI_RETRY_MAX_MINUTES.
+fn ops_echec_cle(doc_cle: &DocCleOutbox, erreur: String, now: &DateTime<Utc>) -> Document {
+    let tentatives = doc_cle.tentatives.unwrap_or(0) + 1;
+    let delai = std::cmp::min(2_i64.pow(std::cmp::min(tentatives, 6) as u32), DELAI_RETRY_MAX_MINUTES);
+    doc! {
+        "$set": {
+            "tentatives": tentatives,
+            "derniere_erreur": erreur,
+            CHAMP_PROCHAINE_TENTATIVE: *now + Duration::minutes(delai),
+        },
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    }
+}
+
+async fn soumettre_cle_message<M>(middleware: &M, doc_cle: &DocCleOutbox) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let cle_message: MessageMilleGrille = convertir_bson_deserializable(doc_cle.commande.clone())?;
+    let partition = match doc_cle.partition.as_ref() {
+        Some(p) => p.to_owned(),
+        None => Err(format!("cles_outbox.soumettre_cle_message Partition manquante pour cle {}", doc_cle.cle_id))?
+    };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, COMMANDE_SAUVEGARDER_CLE)
+        .exchanges(vec![Securite::L3Protege])
+        .partition(partition)
+        .build();
+    match middleware.emettre_message_millegrille(routage, true, TypeMessageOut::Commande, cle_message).await? {
+        Some(TypeMessage::Valide(reponse)) => {
+            let resultat: MessageReponse = reponse.message.parsed.map_contenu()?;
+            match resultat.ok {
+                Some(true) => Ok(()),
+                _ => Err(format!("cles_outbox.soumettre_cle_message Sauvegarde cle {} refusee (ok==false)", doc_cle.cle_id))?
+            }
+        },
+        _ => Err(format!("cles_outbox.soumettre_cle_message Aucune reponse pour cle {}", doc_cle.cle_id))?
+    }
+}
+
+async fn soumettre_cle_attachment<M>(middleware: &M, doc_cle: &DocCleOutbox) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    if let Some(verification) = doc_cle.verification_preuve.as_ref() {
+        let reponse_preuves = verifier_preuves_cles(
+            middleware, verification.fingerprint.clone(), &verification.preuves).await?;
+        if Some(&true) == reponse_preuves.get(doc_cle.cle_id.as_str()) {
+            debug!("soumettre_cle_attachment Preuve cle {} verifiee, cle deja connue", doc_cle.cle_id);
+            return Ok(())
+        }
+    }
+
+    let cle: CommandeSauvegarderCle = convertir_bson_deserializable(doc_cle.commande.clone())?;
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, COMMANDE_SAUVEGARDER_CLE)
+        .exchanges(vec![Securite::L4Secure])
+        .timeout_blocking(5000)
+        .build();
+    match middleware.transmettre_commande(routage, &cle, true).await? {
+        Some(TypeMessage::Valide(_)) => Ok(()),
+        _ => Err(format!("cles_outbox.soumettre_cle_attachment Aucune reponse pour cle {}", doc_cle.cle_id))?
+    }
+}
+
+#[cfg(test)]
+mod test_cles_outbox {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    fn doc_cle(type_cle: &str, tentatives: Option<i64>) -> DocCleOutbox {
+        let mut doc_cle = doc! {
+            CHAMP_CLE_ID: "zCle1",
+            "type_cle": type_cle,
+            "message_id": "zMessage1",
+            "commande": {},
+            CHAMP_CONFIRME: false,
+        };
+        if let Some(t) = tentatives {
+            doc_cle.insert("tentatives", t);
+        }
+        convertir_bson_deserializable(doc_cle).expect("convertir")
+    }
+
+    #[test]
+    fn test_echec_type_cle_inconnu() {
+        setup("test_echec_type_cle_inconnu");
+        let now = Utc::now();
+        let doc_cle = doc_cle("inconnu", None);
+        let ops = ops_echec_cle(&doc_cle, "Type de cle inconnu : inconnu".to_string(), &now);
+        let set_ops = ops.get_document("$set").expect("$set");
+        assert_eq!(1, set_ops.get_i64("tentatives").expect("tentatives"));
+        assert_eq!("Type de cle inconnu : inconnu", set_ops.get_str("derniere_erreur").expect("erreur"));
+        let prochaine = set_ops.get_datetime(CHAMP_PROCHAINE_TENTATIVE).expect("prochaine");
+        assert_eq!((now + Duration::minutes(2)).timestamp_millis(), prochaine.timestamp_millis());
+    }
+
+    #[test]
+    fn test_echec_delai_maximal() {
+        setup("test_echec_delai_maximal");
+        let now = Utc::now();
+        let doc_cle = doc_cle(TYPE_CLE_MESSAGE, Some(10));
+        let ops = ops_echec_cle(&doc_cle, "erreur".to_string(), &now);
+        let set_ops = ops.get_document("$set").expect("$set");
+        let prochaine = set_ops.get_datetime(CHAMP_PROCHAINE_TENTATIVE).expect("prochaine");
+        assert_eq!((now + Duration::minutes(DELAI_RETRY_MAX_MINUTES)).timestamp_millis(), prochaine.timestamp_millis());
+    }
+}
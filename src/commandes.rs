@@ -42,7 +42,19 @@ use crate::gestionnaire::GestionnaireMessagerie;
 use crate::constantes::*;
 use crate::transactions::*;
 use crate::message_structs::*;
-use crate::pompe_messages::{marquer_outgoing_resultat, verifier_fin_transferts_attachments};
+use crate::anti_rejeu::{enregistrer_reception, RAISON_REJEU_DEJA_RECU, retirer_reception, verifier_estampille, verifier_rejeu};
+use crate::certificats_messages::conserver_certificat_message;
+use crate::cles_outbox::{conserver_cle_outbox, conserver_preuve_outbox, marquer_message_cle_pending, TYPE_CLE_ATTACHMENT, TYPE_CLE_MESSAGE, VerificationPreuveOutbox};
+use crate::conversations::verifier_thread;
+use crate::labels::verifier_label;
+use crate::limites_reception::verifier_limites_reception;
+use crate::politique_federation::{charger_politique_federation, DIRECTION_ENTRANT, emettre_evenement_federation_refusee};
+use crate::regles_transfert::traiter_regles_transfert;
+use crate::reponses_automatiques::traiter_reponses_automatiques;
+use crate::reputation::{COMPTEUR_ABUS, COMPTEUR_ANOMALIES_CERTIFICAT, COMPTEUR_ECHECS_SORTANTS, COMPTEUR_ENVOYES, enregistrer_reception_reputation, maj_reputation, verifier_quarantaine_reputation};
+use crate::signalements::{conserver_signalement, DESTINATAIRE_REJEU_SIGNALEMENT, TAILLE_MAX_SIGNALEMENT, transmettre_signalement, TYPE_SIGNALEMENT_EXTERNE};
+use crate::usage_boites::verifier_quotas_destinataires;
+use crate::pompe_messages::{maj_sante_url, marquer_outgoing_resultat, verifier_fin_transferts_attachments};
 
 const REQUETE_MAITREDESCLES_VERIFIER_PREUVE: &str = "verifierPreuve";
 const WEBPUSH_TTL: u32 = 12 * 3600;
@@ -81,6 +93,7 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValideAction, gesti
         COMMANDE_GENERER_CLEWEBPUSH_NOTIFICATIONS => generer_clewebpush_notifications(middleware, m, gestionnaire).await,
         COMMANDE_EMETTRE_NOTIFICATIONS_USAGER => emettre_notifications_usager(middleware, m, gestionnaire).await,
         COMMANDE_RECEVOIR_EXTERNE => commande_recevoir_externe(middleware, m, gestionnaire).await,
+        COMMANDE_RECEVOIR_SIGNALEMENT_EXTERNE => commande_recevoir_signalement_externe(middleware, m).await,
 
         // Transactions
         TRANSACTION_POSTER => commande_poster(middleware, m, gestionnaire).await,
@@ -88,13 +101,34 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValideAction, gesti
         TRANSACTION_INITIALISER_PROFIL => commande_initialiser_profil(middleware, m, gestionnaire).await,
         TRANSACTION_MAJ_CONTACT => commande_maj_contact(middleware, m, gestionnaire).await,
         TRANSACTION_LU => commande_lu(middleware, m, gestionnaire).await,
+        TRANSACTION_ETOILE => commande_etoile(middleware, m, gestionnaire).await,
         TRANSACTION_SUPPRIMER_MESSAGES => commande_supprimer_message(middleware, m, gestionnaire).await,
+        TRANSACTION_RESTAURER_MESSAGES => commande_restaurer_messages(middleware, m, gestionnaire).await,
         TRANSACTION_SUPPRIMER_CONTACTS => commande_supprimer_contacts(middleware, m, gestionnaire).await,
         TRANSACTION_CONSERVER_CONFIGURATION_NOTIFICATIONS => commande_conserver_configuration_notifications(middleware, m, gestionnaire).await,
         TRANSACTION_SAUVEGARDER_USAGER_CONFIG_NOTIFICATIONS => commande_sauvegarder_usager_config_notifications(middleware, m, gestionnaire).await,
         TRANSACTION_SAUVEGARDER_SUBSCRIPTION_WEBPUSH => commande_sauvegarder_subscription_webpush(middleware, m, gestionnaire).await,
         TRANSACTION_RETIRER_SUBSCRIPTION_WEBPUSH => commande_retirer_subscription_webpush(middleware, m, gestionnaire).await,
         TRANSACTION_NOTIFIER => commande_notifier(middleware, m, gestionnaire).await,
+        TRANSACTION_MAJ_FILTRE_EXPEDITEURS => commande_maj_filtre_expediteurs(middleware, m, gestionnaire).await,
+        TRANSACTION_CONSERVER_CONFIGURATION_LIMITES_RECEPTION => commande_conserver_configuration_limites_reception(middleware, m, gestionnaire).await,
+        TRANSACTION_CONSERVER_CONFIGURATION_QUOTAS => commande_conserver_configuration_quotas(middleware, m, gestionnaire).await,
+        TRANSACTION_CONSERVER_CONFIGURATION_CORBEILLE => commande_conserver_configuration_corbeille(middleware, m, gestionnaire).await,
+        TRANSACTION_CONSERVER_POLITIQUE_FEDERATION => commande_conserver_politique_federation(middleware, m, gestionnaire).await,
+        TRANSACTION_MAJ_REPONSE_AUTOMATIQUE => commande_maj_reponse_automatique(middleware, m, gestionnaire).await,
+        TRANSACTION_MAJ_REGLES_TRANSFERT => commande_maj_regles_transfert(middleware, m, gestionnaire).await,
+        TRANSACTION_MAJ_REGLES_MESSAGES => commande_maj_regles_messages(middleware, m, gestionnaire).await,
+        TRANSACTION_MAJ_QUARANTAINE => commande_maj_quarantaine(middleware, m, gestionnaire).await,
+        TRANSACTION_TRAITER_QUARANTAINE => commande_traiter_quarantaine(middleware, m, gestionnaire).await,
+        TRANSACTION_TRAITER_QUARANTAINE_MESSAGES => commande_traiter_quarantaine_messages(middleware, m, gestionnaire).await,
+        TRANSACTION_SIGNALER_MESSAGE => commande_signaler_message(middleware, m, gestionnaire).await,
+        TRANSACTION_ACCEPTER_CLE_EXPEDITEUR => commande_accepter_cle_expediteur(middleware, m, gestionnaire).await,
+        TRANSACTION_MAJ_LABEL => commande_maj_label(middleware, m, gestionnaire).await,
+        TRANSACTION_SUPPRIMER_LABEL => commande_supprimer_label(middleware, m, gestionnaire).await,
+        TRANSACTION_AJOUTER_LABEL_MESSAGES => commande_label_messages(middleware, m, gestionnaire).await,
+        TRANSACTION_RETIRER_LABEL_MESSAGES => commande_label_messages(middleware, m, gestionnaire).await,
+        TRANSACTION_ARCHIVER_MESSAGES => commande_archiver_messages(middleware, m, gestionnaire).await,
+        TRANSACTION_DESARCHIVER_MESSAGES => commande_archiver_messages(middleware, m, gestionnaire).await,
 
         // Commandes inconnues
         _ => Err(format!("core_backup.consommer_commande: Commande {} inconnue : {}, message dropped", DOMAINE_NOM, m.action))?,
@@ -137,9 +171,31 @@ async fn commande_poster<M>(middleware: &M, mut m: MessageValideAction, gestionn
         if resultat.valide() == false {
             Err(format!("commandes.commande_poster: Message dans la commande {:?} invalide : {:?}", m.correlation_id, resultat))?
         }
+        if let Some(certificat) = m.message.certificat.as_ref() {
+            if let Err(e) = conserver_certificat_message(middleware, certificat.as_ref()).await {
+                warn!("commandes.commande_poster Erreur conservation certificat message {} : {:?}", commande.message.id, e);
+            }
+        }
+    }
+
+    // L'adresse declaree (from) d'un usager doit etre sa propre adresse
+    if let (Some(user_id), Some(from)) = (user_id.as_ref(), commande.from.as_ref()) {
+        if ! verifier_adresse_usager(middleware, from.as_str(), user_id.as_str()).await? {
+            warn!("commandes.commande_poster Adresse from {} ne correspond pas a l'usager {}", from, user_id);
+            let reponse = json!({"ok": false, "err": "Adresse from ne correspond pas a l'usager", "code": 403});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+    }
+
+    if let Some(thread) = commande.thread.as_ref() {
+        if ! verifier_thread(thread.as_str()) {
+            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Thread invalide"}), None)?))
+        }
     }
 
-    // Sauvegarer la cle
+    // Sauvegarer la cle. Si MaitreDesCles ne repond pas, la cle est conservee dans l'outbox
+    // et le message est accepte avec le statut cle en attente.
+    let mut cle_pending = false;
     match attachements {
         Some(mut attachements) => {
             match attachements.remove("cle") {
@@ -155,12 +211,19 @@ async fn commande_poster<M>(middleware: &M, mut m: MessageValideAction, gestionn
                         },
                         None => Err(format!("commandes.commande_poster: Erreur sauvegarde cle (attachements cle absents) pour message {:?}", m.correlation_id))?
                     };
+                    let partition_cle = partition.clone();
                     let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, COMMANDE_SAUVEGARDER_CLE)
                         .exchanges(vec![Securite::L3Protege])
                         .partition(partition)
                         .build();
                     debug!("commandes.commande_poster: Sauvegarder cle message aupres de {:?} : {:?}", routage, cle_message);
-                    let reponse = middleware.emettre_message_millegrille(routage, true, TypeMessageOut::Commande, cle_message).await?;
+                    let reponse = match middleware.emettre_message_millegrille(routage, true, TypeMessageOut::Commande, cle_message.clone()).await {
+                        Ok(inner) => inner,
+                        Err(e) => {
+                            warn!("commandes.commande_poster: Erreur sauvegarde cle pour message {:?}, conserver dans l'outbox : {:?}", m.correlation_id, e);
+                            None
+                        }
+                    };
                     if let Some(TypeMessage::Valide(reponse)) = reponse {
                         debug!("commandes.commande_poster Reponse sauvegarde cle : {:?}", reponse);
                         let resultat: MessageReponse = reponse.message.parsed.map_contenu()?;
@@ -171,7 +234,11 @@ async fn commande_poster<M>(middleware: &M, mut m: MessageValideAction, gestionn
                             Err(format!("commandes.commande_poster: Erreur sauvegarde cle (ok==false) pour message {:?}", m.correlation_id))?
                         }
                     } else {
-                        Err(format!("commandes.commande_poster: Erreur sauvegarde cle pour message {:?}", m.correlation_id))?
+                        // Aucune reponse de MaitreDesCles, conserver la cle pour retry
+                        conserver_cle_outbox(
+                            middleware, cle_message.id.as_str(), TYPE_CLE_MESSAGE, Some(commande.message.id.as_str()),
+                            Some(partition_cle), &cle_message).await?;
+                        cle_pending = true;
                     }
                 },
                 None => Err(format!("commandes.commande_poster: Cle manquante des attachements pour message {:?}", m.correlation_id))?
@@ -181,15 +248,23 @@ async fn commande_poster<M>(middleware: &M, mut m: MessageValideAction, gestionn
     }
 
     // Traiter la transaction
-    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+    let reponse = sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?;
+
+    if cle_pending {
+        marquer_message_cle_pending(middleware, commande.message.id.as_str(), true).await?;
+        let reponse = json!({"ok": true, "message_id": &commande.message.id, "cle_pending": true});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    Ok(reponse)
 }
 
 async fn commande_recevoir<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
-    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
 {
     debug!("commandes.commande_recevoir Consommer commande : {:?}", & m.message);
-    let mut commande: CommandeRecevoirPost = m.message.get_msg().map_contenu()?;
+    let commande: CommandeRecevoirPost = m.message.get_msg().map_contenu()?;
     debug!("commandes.commande_recevoir Commande nouvelle versions parsed : {:?}", commande);
 
     // let user_id = m.get_user_id();
@@ -210,7 +285,39 @@ async fn commande_recevoir<M>(middleware: &M, m: MessageValideAction, gestionnai
         }
     }
 
+    recevoir_message(middleware, commande, gestionnaire).await
+}
+
+/// Valide le message encapsule, resout les destinataires et sauvegarde la transaction recevoir.
+/// Utilise par la commande recevoir (MQ) et directement par la pompe pour la livraison locale.
+pub async fn recevoir_message<M>(middleware: &M, commande: CommandeRecevoirPost, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
+{
+    match preparer_recevoir_message(middleware, commande).await? {
+        PreparationRecevoir::Refuse(reponse) => Ok(Some(reponse)),
+        PreparationRecevoir::Pret(document, certificat_usager) => {
+            sauvegarder_recevoir_message(middleware, gestionnaire, document, certificat_usager).await
+        }
+    }
+}
+
+/// Resultat de la validation d'une commande recevoir, avant la sauvegarde de la transaction.
+enum PreparationRecevoir {
+    /// Message refuse, reponse d'erreur a retourner.
+    Refuse(MessageMilleGrille),
+    /// Document pret a sauvegarder. Le flag indique un message emis par un certificat usager.
+    Pret(DocumentRecevoirPost, bool),
+}
+
+/// Valide le message encapsule et prepare le document de la transaction recevoir. Aucune
+/// transaction n'est sauvegardee.
+async fn preparer_recevoir_message<M>(middleware: &M, commande: CommandeRecevoirPost)
+    -> Result<PreparationRecevoir, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
+{
     let mut message = MessageSerialise::from_parsed(commande.message)?;
+    let message_id = message.parsed.id.clone();
 
     // Charger les certificats pour valider le message encapsule
     let est_local = message.parsed.origine == Some(middleware.get_enveloppe_signature().idmg()?);
@@ -222,7 +329,7 @@ async fn commande_recevoir<M>(middleware: &M, m: MessageValideAction, gestionnai
                 Some(inner) => {
                     Some(middleware.charger_enveloppe(&vec![inner], None, None).await?)
                 },
-                None => Err(format!("commandes.commande_recevoir: Certificat CA absent du message encapsule tiers {:?}", m.correlation_id))?
+                None => Err(format!("commandes.commande_recevoir: Certificat CA absent du message encapsule tiers {}", message_id))?
             }
         }
     };
@@ -238,9 +345,9 @@ async fn commande_recevoir<M>(middleware: &M, m: MessageValideAction, gestionnai
             match middleware.get_certificat(message.parsed.pubkey.as_str()).await {
                 Some(inner) => inner,
                 None => {
-                    error!("commandes.commande_recevoir: Certificat absent du message encapsule {:?}", m.correlation_id);
+                    error!("commandes.commande_recevoir: Certificat absent du message encapsule {}", message_id);
                     let reponse_erreur = json!({"ok": false, "err": "Certificat absent du message encapsule"});
-                    return Ok(Some(middleware.formatter_reponse(&reponse_erreur, None)?));
+                    return Ok(PreparationRecevoir::Refuse(middleware.formatter_reponse(&reponse_erreur, None)?));
                 }
             }
         }
@@ -253,15 +360,27 @@ async fn commande_recevoir<M>(middleware: &M, m: MessageValideAction, gestionnai
     match resultat_verification.valide() {
         true => debug!("commande_recevoir Message encapsule dans la commande recevoir est valide"),
         false => {
-            error!("commandes.commande_recevoir: Message encapsule est invalide {:?} : {:?}", m.correlation_id, resultat_verification);
+            error!("commandes.commande_recevoir: Message encapsule est invalide {} : {:?}", message_id, resultat_verification);
             let reponse_erreur = json!({"ok": false, "err": "Erreur validation message", "detail": format!("{:?}", resultat_verification)});
-            return Ok(Some(middleware.formatter_reponse(&reponse_erreur, None)?));
+            return Ok(PreparationRecevoir::Refuse(middleware.formatter_reponse(&reponse_erreur, None)?));
         }
     }
 
     // Resolve users
     let destinataires = extraire_destinataires(middleware, &commande.destinataires).await?;
 
+    // Appliquer le filtre expediteurs des destinataires
+    let idmg_origine = match message.parsed.origine.as_ref() {
+        Some(inner) => inner.to_owned(),
+        None => middleware.get_enveloppe_signature().idmg()?
+    };
+    let destinataires = filtrer_destinataires_expediteur(
+        middleware, destinataires, commande.from.as_ref().map(|f| f.as_str()),
+        message.parsed.pubkey.as_str(), idmg_origine.as_str()).await?;
+
+    // Refuser les destinataires dont la boite est pleine (quota dur)
+    let destinataires = verifier_quotas_destinataires(middleware, destinataires).await?;
+
     // if let Some(cle) = commande.cle.take() {
     if let Some(mut attachements) = message.parsed.attachements.take() {
         if let Some(cle) = attachements.remove("cle") {
@@ -280,6 +399,12 @@ async fn commande_recevoir<M>(middleware: &M, m: MessageValideAction, gestionnai
         }
     }
 
+    // Les messages systeme (certificat sans user_id) ne declenchent pas de reponse automatique
+    let certificat_usager = match message.certificat.as_ref() {
+        Some(c) => c.get_user_id()?.is_some(),
+        None => false
+    };
+
     // Cleanup, retirer certificat du message (stocke externe)
     message.parsed.retirer_certificats();
 
@@ -288,12 +413,123 @@ async fn commande_recevoir<M>(middleware: &M, m: MessageValideAction, gestionnai
         destinataires_user_id: destinataires,
         fuuids: commande.fuuids,
         fichiers: None,
+        from: commande.from,
+        type_envoi: commande.type_envoi,
+        transfert: commande.transfert,
+        quarantaine: None,
+        thread: commande.thread.filter(|t| verifier_thread(t.as_str())),
     };
 
+    Ok(PreparationRecevoir::Pret(commande_maj, certificat_usager))
+}
+
+/// Sauvegarde la transaction recevoir et execute les traitements qui suivent la reception.
+async fn sauvegarder_recevoir_message<M>(
+    middleware: &M, gestionnaire: &GestionnaireMessagerie, commande_maj: DocumentRecevoirPost, certificat_usager: bool
+)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
+{
     // Traiter la transaction
     //Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
-    Ok(sauvegarder_traiter_transaction_serializable(
-        middleware, &commande_maj, gestionnaire, DOMAINE_NOM, TRANSACTION_RECEVOIR).await?)
+    let reponse = sauvegarder_traiter_transaction_serializable(
+        middleware, &commande_maj, gestionnaire, DOMAINE_NOM, TRANSACTION_RECEVOIR).await?;
+
+    traiter_suites_reception(middleware, gestionnaire, &commande_maj, certificat_usager, reponse.as_ref()).await;
+
+    Ok(reponse)
+}
+
+/// Traitements qui suivent la sauvegarde de la transaction recevoir (reponses automatiques,
+/// regles de transfert). Ils ne sont pas executes lors de la regeneration des transactions.
+/// Seuls les destinataires qui ont recu le message (nouveau, hors quarantaine et non supprime)
+/// sont consideres.
+async fn traiter_suites_reception<M>(
+    middleware: &M, gestionnaire: &GestionnaireMessagerie, document: &DocumentRecevoirPost,
+    certificat_usager: bool, reponse: Option<&MessageMilleGrille>
+)
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
+{
+    let message_id = document.message.id.as_str();
+    let destinataires = match charger_destinataires_livres(middleware, document, reponse).await {
+        Ok(inner) => inner,
+        Err(e) => {
+            warn!("traiter_suites_reception Erreur chargement destinataires message {} : {:?}", message_id, e);
+            return
+        }
+    };
+    if destinataires.is_empty() {
+        return
+    }
+
+    if let Err(e) = traiter_reponses_automatiques(
+        middleware, gestionnaire, &destinataires, message_id, certificat_usager,
+        document.from.as_ref().map(|f| f.as_str()),
+        document.type_envoi.as_ref().map(|t| t.as_str())
+    ).await {
+        warn!("traiter_suites_reception Erreur reponses automatiques message {} : {:?}", message_id, e);
+    }
+
+    if let Err(e) = traiter_regles_transfert(
+        middleware, gestionnaire, &destinataires, &document.message,
+        document.from.as_ref().map(|f| f.as_str()),
+        document.type_envoi.as_ref().map(|t| t.as_str()),
+        document.fuuids.as_ref(),
+        document.transfert.as_ref()
+    ).await {
+        warn!("traiter_suites_reception Erreur regles de transfert message {} : {:?}", message_id, e);
+    }
+}
+
+async fn charger_destinataires_livres<M>(middleware: &M, document: &DocumentRecevoirPost, reponse: Option<&MessageMilleGrille>)
+    -> Result<Vec<DestinataireInfo>, Box<dyn Error>>
+    where M: MongoDao
+{
+    // Adresses pour lesquelles la transaction a cree un nouveau message (201)
+    let reponse_recevoir: ReponseTransactionRecevoir = match reponse {
+        Some(inner) => inner.map_contenu()?,
+        None => return Ok(Vec::new())
+    };
+    let adresses_creees: HashSet<String> = match reponse_recevoir.usagers {
+        Some(inner) => inner.into_iter().filter(|(_, code)| *code == 201).map(|(a, _)| a).collect(),
+        None => return Ok(Vec::new())
+    };
+    let destinataires: Vec<&DestinataireInfo> = document.destinataires_user_id.iter()
+        .filter(|d| match d.adresse.as_ref() {
+            Some(a) => adresses_creees.contains(a),
+            None => false
+        })
+        .collect();
+    let user_ids: Vec<&String> = destinataires.iter().filter_map(|d| d.user_id.as_ref()).collect();
+    if user_ids.is_empty() {
+        return Ok(Vec::new())
+    }
+
+    // Retirer les copies en quarantaine ou supprimees par une regle de l'usager
+    let filtre = doc! {
+        CHAMP_USER_ID: {"$in": user_ids},
+        "message.id": &document.message.id,
+        CHAMP_SUPPRIME: false,
+        CHAMP_QUARANTAINE: {"$ne": true},
+    };
+    let options = FindOptions::builder().projection(doc! {CHAMP_USER_ID: 1}).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    let mut user_ids_livres = HashSet::new();
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let d = r?;
+        if let Ok(user_id) = d.get_str(CHAMP_USER_ID) {
+            user_ids_livres.insert(user_id.to_owned());
+        }
+    }
+
+    Ok(destinataires.into_iter()
+        .filter(|d| match d.user_id.as_ref() {
+            Some(u) => user_ids_livres.contains(u),
+            None => false
+        })
+        .map(|d| d.to_owned())
+        .collect())
 }
 
 async fn extraire_destinataires<M>(middleware: &M, adresses_destinataires: &Vec<String>)
@@ -334,7 +570,8 @@ async fn extraire_destinataires<M>(middleware: &M, adresses_destinataires: &Vec<
                 if let Some(uo) = user_id_option {
                     destinataires_user_id.push(DestinataireInfo {
                         adresse: Some(adresse.to_owned()),
-                        user_id: uo.to_owned()
+                        user_id: uo.to_owned(),
+                        code: None,
                     })
                 }
             },
@@ -345,6 +582,102 @@ async fn extraire_destinataires<M>(middleware: &M, adresses_destinataires: &Vec<
     Ok(destinataires_user_id)
 }
 
+/// Retourne true si l'adresse correspond a l'usager local (user_id du certificat).
+/// Le hostname n'est pas verifie, il est valide par les destinataires tiers (idmg d'origine).
+async fn verifier_adresse_usager<M>(middleware: &M, adresse: &str, user_id: &str)
+    -> Result<bool, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    let destinataires = extraire_destinataires(middleware, &vec![adresse.to_owned()]).await?;
+    Ok(destinataires.iter().any(|d| d.user_id.as_ref().map(|u| u.as_str()) == Some(user_id)))
+}
+
+/// Retourne true si le hostname de l'adresse est resolu (CoreTopologie) vers l'idmg.
+async fn verifier_adresse_idmg<M>(middleware: &M, adresse: &str, idmg: &str)
+    -> Result<bool, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let hostname = match AdresseMessagerie::new(adresse) {
+        Ok(a) => match a.dns {
+            Some(d) => d,
+            None => return Ok(false)
+        },
+        Err(e) => {
+            info!("verifier_adresse_idmg Adresse {} invalide : {:?}", adresse, e);
+            return Ok(false)
+        }
+    };
+
+    let routage = RoutageMessageAction::builder("CoreTopologie", "resolveIdmg")
+        .exchanges(vec!(Securite::L2Prive))
+        .build();
+    let requete = RequeteTopologieResolveIdmg { dns: Some(vec![hostname.clone()]) };
+    let reponse: ReponseTopologieResolveIdmg = match middleware.transmettre_requete(routage, &requete).await? {
+        TypeMessage::Valide(r) => r.message.parsed.map_contenu()?,
+        _ => Err(format!("commandes.verifier_adresse_idmg Erreur resolve idmg, mauvais type de reponse"))?
+    };
+
+    let idmg_resolu = reponse.dns.as_ref()
+        .and_then(|d| d.get(&hostname))
+        .and_then(|i| i.as_ref());
+    Ok(idmg_resolu.map(|i| i.as_str()) == Some(idmg))
+}
+
+/// Applique le filtre expediteurs de chaque destinataire. Les destinataires qui refusent
+/// l'expediteur sont retires (user_id: None) avec le code CODE_DESTINATAIRE_REFUSE.
+/// Le contenu du message n'est pas dechiffre : le filtre utilise le certificat (fingerprint)
+/// et l'idmg de la millegrille d'origine du message signe. L'adresse declaree (from) est
+/// verifiee avant l'appel (certificat usager local, hostname de l'idmg tiers).
+async fn filtrer_destinataires_expediteur<M>(
+    middleware: &M, destinataires: Vec<DestinataireInfo>, from: Option<&str>, fingerprint: &str, idmg: &str
+)
+    -> Result<Vec<DestinataireInfo>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let user_ids: Vec<&String> = destinataires.iter().filter_map(|d| d.user_id.as_ref()).collect();
+    if user_ids.is_empty() {
+        return Ok(destinataires)
+    }
+
+    // Charger les filtres des usagers
+    let mut filtres = HashMap::new();
+    {
+        let filtre = doc! {
+            CHAMP_USER_ID: {"$in": user_ids},
+            CHAMP_FILTRE_EXPEDITEURS: {"$exists": true},
+        };
+        let options = FindOptions::builder()
+            .projection(doc! {CHAMP_USER_ID: 1, CHAMP_FILTRE_EXPEDITEURS: 1})
+            .build();
+        let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+        let mut curseur = collection.find(filtre, Some(options)).await?;
+        while let Some(r) = curseur.next().await {
+            let d = r?;
+            let user_id = d.get_str(CHAMP_USER_ID)?.to_owned();
+            let filtre_usager: FiltreExpediteurs = convertir_bson_deserializable(d.get_document(CHAMP_FILTRE_EXPEDITEURS)?.to_owned())?;
+            filtres.insert(user_id, filtre_usager);
+        }
+    }
+
+    let destinataires = destinataires.into_iter().map(|mut d| {
+        let refuse = match d.user_id.as_ref() {
+            Some(u) => match filtres.get(u) {
+                Some(f) => ! f.accepter(from, fingerprint, idmg),
+                None => false
+            },
+            None => false
+        };
+        if refuse {
+            info!("filtrer_destinataires_expediteur Expediteur {:?} (certificat {}, idmg {}) refuse par destinataire {:?}", from, fingerprint, idmg, d.adresse);
+            d.user_id = None;
+            d.code = Some(CODE_DESTINATAIRE_REFUSE);
+        }
+        d
+    }).collect();
+
+    Ok(destinataires)
+}
+
 async fn commande_initialiser_profil<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: GenerateurMessages + MongoDao + ValidateurX509 + ChiffrageFactoryTrait + VerificateurMessage
@@ -477,6 +810,433 @@ async fn commande_lu<M>(middleware: &M, m: MessageValideAction, gestionnaire: &G
     Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
 }
 
+async fn commande_maj_filtre_expediteurs<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_maj_filtre_expediteurs Consommer commande : {:?}", & m.message);
+    let commande: TransactionMajFiltreExpediteurs = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_maj_filtre_expediteurs Commande nouvelle versions parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_maj_filtre_expediteurs: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_maj_reponse_automatique<M>(middleware: &M, mut m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_maj_reponse_automatique Consommer commande : {:?}", & m.message);
+    let commande: TransactionMajReponseAutomatique = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_maj_reponse_automatique Commande nouvelle versions parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_maj_reponse_automatique: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let config = &commande.reponse_automatique;
+    if config.actif && config.message.is_none() {
+        let reponse = json!({"ok": false, "err": "Message de reponse automatique manquant"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    // Sauvegarder la cle de reponse (message modele chiffre)
+    if let Some(mut attachements) = m.message.parsed.attachements.take() {
+        if let Some(cle) = attachements.remove("cle") {
+            sauvegarde_attachement_cle(middleware, cle).await?
+        }
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_maj_regles_transfert<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_maj_regles_transfert Consommer commande : {:?}", & m.message);
+    let commande: TransactionMajReglesTransfert = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_maj_regles_transfert Commande nouvelle versions parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_maj_regles_transfert: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    for regle in &commande.regles {
+        if let Err(e) = AdresseMessagerie::new(regle.adresse_cible.as_str()) {
+            let reponse = json!({"ok": false, "err": format!("Adresse cible invalide : {:?}", e)});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_maj_regles_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_maj_regles_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionMajReglesMessages = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_maj_regles_messages Commande nouvelle versions parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_maj_regles_messages: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    for regle in &commande.regles {
+        let conditions = &regle.conditions;
+        if conditions.heure_debut.unwrap_or(0) > 23 || conditions.heure_fin.unwrap_or(0) > 23 {
+            let reponse = json!({"ok": false, "err": format!("Heure invalide pour regle {:?}", regle.nom)});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_maj_quarantaine<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_maj_quarantaine Consommer commande : {:?}", & m.message);
+    let commande: TransactionMajQuarantaine = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_maj_quarantaine Commande nouvelle versions parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_maj_quarantaine: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.quarantaine.actif && commande.quarantaine.sel_index.is_none() {
+        let reponse = json!({"ok": false, "err": "Sel d'index manquant pour activer la quarantaine"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_traiter_quarantaine<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_traiter_quarantaine Consommer commande : {:?}", & m.message);
+    let commande: TransactionTraiterQuarantaine = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_traiter_quarantaine Commande nouvelle versions parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_traiter_quarantaine: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.contact.is_some() && ! commande.accepter {
+        let reponse = json!({"ok": false, "err": "Contact fourni pour un expediteur refuse"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+    if commande.accepter && commande.contact.is_none() {
+        // Sans contact, les prochains messages de l'expediteur accepte retourneraient en quarantaine
+        let reponse = json!({"ok": false, "err": "Contact requis pour accepter un expediteur"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_traiter_quarantaine_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_traiter_quarantaine_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionTraiterQuarantaineMessages = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_traiter_quarantaine_messages Commande nouvelle versions parsed : {:?}", commande);
+
+    if m.get_user_id().is_none() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    }
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_traiter_quarantaine_messages: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.message_ids.is_empty() {
+        let reponse = json!({"ok": false, "err": "message_ids requis"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_signaler_message<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_signaler_message Consommer commande : {:?}", & m.message);
+    let commande: TransactionSignalerMessage = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_signaler_message Commande nouvelle versions parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_signaler_message: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let doc_incoming: DocumentIncoming = {
+        let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": &commande.message_id};
+        let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+        match collection.find_one(filtre, None).await? {
+            Some(d) => convertir_bson_deserializable(d)?,
+            None => {
+                let reponse = json!({"ok": false, "code": 404, "err": "Message inconnu"});
+                return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+            }
+        }
+    };
+    let deja_signale = doc_incoming.signale == Some(true);
+
+    // Traiter la transaction
+    let reponse = sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?;
+
+    // Reputation et transmission a la millegrille d'origine, une seule fois par message
+    let idmg_origine = match doc_incoming.message.origine.as_ref() {
+        Some(inner) => inner.as_str(),
+        None => middleware.idmg()
+    };
+    if ! deja_signale && idmg_origine != middleware.idmg() {
+        if let Err(e) = maj_reputation(middleware, idmg_origine, &[(COMPTEUR_ABUS, 1.0)]).await {
+            warn!("commande_signaler_message Erreur maj reputation idmg {} : {:?}", idmg_origine, e);
+        }
+
+        if commande.transmettre == Some(true) {
+            let signalement = SignalementAbus {
+                message_id: commande.message_id.clone(),
+                raison: commande.raison.clone(),
+                message: doc_incoming.message.clone(),
+            };
+            if let Err(e) = transmettre_signalement(middleware, idmg_origine, &signalement).await {
+                warn!("commande_signaler_message Erreur transmission signalement message {} : {:?}", commande.message_id, e);
+            }
+        }
+    }
+
+    Ok(reponse)
+}
+
+async fn commande_accepter_cle_expediteur<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_accepter_cle_expediteur Consommer commande : {:?}", & m.message);
+    let commande: TransactionAccepterCleExpediteur = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_accepter_cle_expediteur Commande nouvelle versions parsed : {:?}", commande);
+
+    if m.get_user_id().is_none() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    }
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_accepter_cle_expediteur: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_maj_label<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_maj_label Consommer commande : {:?}", & m.message);
+    let commande: TransactionMajLabel = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_maj_label Commande parsed : {:?}", commande);
+
+    if m.get_user_id().is_none() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    }
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_maj_label: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.nom.trim().is_empty() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Nom de label vide"}), None)?))
+    }
+    if let Some(couleur) = commande.couleur.as_ref() {
+        let couleur_valide = couleur.len() == 7 && couleur.starts_with('#') &&
+            couleur[1..].chars().all(|c| c.is_ascii_hexdigit());
+        if ! couleur_valide {
+            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Couleur invalide (format #rrggbb)"}), None)?))
+        }
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_supprimer_label<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_supprimer_label Consommer commande : {:?}", & m.message);
+    let commande: TransactionSupprimerLabel = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_supprimer_label Commande parsed : {:?}", commande);
+
+    if m.get_user_id().is_none() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    }
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_supprimer_label: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+/// Ajout ou retrait d'un label sur des messages (ajouterLabelMessages, retirerLabelMessages).
+async fn commande_label_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_label_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionLabelMessages = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_label_messages Commande parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_label_messages: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.message_ids.is_empty() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Aucun message"}), None)?))
+    }
+
+    // Le retrait est permis pour un label supprime
+    if m.action.as_str() == TRANSACTION_AJOUTER_LABEL_MESSAGES &&
+        ! verifier_label(middleware, user_id.as_str(), commande.label_id.as_str()).await? {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "code": 404, "err": "Label inconnu"}), None)?))
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
 async fn commande_confirmer_transmission<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: ValidateurX509 + MongoDao + GenerateurMessages
@@ -497,7 +1257,7 @@ async fn commande_confirmer_transmission<M>(middleware: &M, m: MessageValideActi
 
     let result_code = commande.code as u32;
     let processed = match &commande.code {
-        200 | 201 | 202 | 404 => true,
+        200 | 201 | 202 | 403 | 404 | 409 | 451 | 507 => true,
         _ => false
     };
 
@@ -518,6 +1278,25 @@ async fn commande_confirmer_transmission<M>(middleware: &M, m: MessageValideActi
 
     marquer_outgoing_resultat(middleware, message_id, idmg, vec_destinataires, processed, Some(result_code)).await?;
 
+    // Suivi de sante de l'url de l'application messagerie tierce
+    if idmg != middleware.idmg() {
+        let url = match m.message.get_msg().map_contenu::<ConfirmationTransmissionUrl>() {
+            Ok(inner) => inner.url,
+            Err(_) => None
+        };
+        if let Err(e) = maj_sante_url(middleware, message_id, idmg, url, result_code).await {
+            warn!("commande_confirmer_transmission Erreur maj sante url pour idmg {} : {:?}", idmg, e);
+        }
+
+        let compteur = match processed {
+            true => COMPTEUR_ENVOYES,
+            false => COMPTEUR_ECHECS_SORTANTS
+        };
+        if let Err(e) = maj_reputation(middleware, idmg, &[(compteur, 1.0)]).await {
+            warn!("commande_confirmer_transmission Erreur maj reputation idmg {} : {:?}", idmg, e);
+        }
+    }
+
     Ok(None)
 }
 
@@ -575,37 +1354,124 @@ async fn commande_prochain_attachment<M>(middleware: &M, m: MessageValideAction,
         }
     };
 
-    // Marquer l'attachment comme en cours
-    let ts_courant = Utc::now().timestamp();
-    let ops = doc! {
-        "$pull": {
-            format!("idmgs_mapping.{}.attachments_restants", idmg): fuuid_attachment,
-        },
-        "$set": {
-            format!("idmgs_mapping.{}.attachments_en_cours.{}", idmg, fuuid_attachment): {
-                "last_update": ts_courant,
-            },
-        }
-    };
-    collection.update_one(filtre, ops, None).await?;
+    // Marquer l'attachment comme en cours
+    let ts_courant = Utc::now().timestamp();
+    let ops = doc! {
+        "$pull": {
+            format!("idmgs_mapping.{}.attachments_restants", idmg): fuuid_attachment,
+        },
+        "$set": {
+            format!("idmgs_mapping.{}.attachments_en_cours.{}", idmg, fuuid_attachment): {
+                "last_update": ts_courant,
+            },
+        }
+    };
+    collection.update_one(filtre, ops, None).await?;
+
+    // Repondre avec le fuuid
+    let reponse = ReponseProchainAttachment { fuuid: Some(fuuid_attachment.into()), ok: true };
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+async fn commande_etoile<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_etoile Consommer commande : {:?}", & m.message);
+    let commande: TransactionEtoile = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_etoile Commande parsed : {:?}", commande);
+
+    if m.get_user_id().is_none() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    }
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_etoile: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.message_ids.is_empty() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Aucun message"}), None)?))
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_supprimer_message<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_supprimer_message Consommer commande : {:?}", & m.message);
+    let commande: TransactionSupprimerMessage = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_supprimer_message Commande parsed : {:?}", commande);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    };
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_supprimer_message: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+/// Archiver ou desarchiver des messages recus (archiverMessages, desarchiverMessages).
+async fn commande_archiver_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_archiver_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionArchiverMessages = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_archiver_messages Commande parsed : {:?}", commande);
+
+    if m.get_user_id().is_none() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    }
+
+    // Autorisation: Action usager avec compte prive ou delegation globale
+    let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
+    if role_prive {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_archiver_messages: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.message_ids.is_empty() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Aucun message"}), None)?))
+    }
 
-    // Repondre avec le fuuid
-    let reponse = ReponseProchainAttachment { fuuid: Some(fuuid_attachment.into()), ok: true };
-    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    // Traiter la transaction
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
 }
 
-async fn commande_supprimer_message<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+async fn commande_restaurer_messages<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
 {
-    debug!("commandes.commande_supprimer_message Consommer commande : {:?}", & m.message);
-    let commande: TransactionSupprimerMessage = m.message.get_msg().map_contenu()?;
-    debug!("commandes.commande_supprimer_message Commande parsed : {:?}", commande);
+    debug!("commandes.commande_restaurer_messages Consommer commande : {:?}", & m.message);
+    let commande: TransactionRestaurerMessages = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_restaurer_messages Commande parsed : {:?}", commande);
 
-    let user_id = match m.get_user_id() {
-        Some(u) => u,
-        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
-    };
+    if m.get_user_id().is_none() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "userId manquant", "code": 403}), None)?))
+    }
 
     // Autorisation: Action usager avec compte prive ou delegation globale
     let role_prive = m.verifier_roles(vec![RolesCertificats::ComptePrive]);
@@ -614,7 +1480,11 @@ async fn commande_supprimer_message<M>(middleware: &M, m: MessageValideAction, g
     } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
         // Ok
     } else {
-        Err(format!("commandes.commande_supprimer_message: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+        Err(format!("commandes.commande_restaurer_messages: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if commande.message_ids.is_empty() {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Aucun message"}), None)?))
     }
 
     // Traiter la transaction
@@ -680,6 +1550,115 @@ async fn commande_conserver_configuration_notifications<M>(middleware: &M, mut m
     Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
 }
 
+async fn commande_conserver_configuration_limites_reception<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_conserver_configuration_limites_reception Consommer commande : {:?}", & m.message);
+    let commande: TransactionConserverConfigurationLimitesReception = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_conserver_configuration_limites_reception Commande parsed : {:?}", commande);
+
+    // Autorisation: delegation globale
+    if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_conserver_configuration_limites_reception: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    for limite in vec![commande.idmg.as_ref(), commande.certificat.as_ref()] {
+        if let Some(l) = limite {
+            if l.capacite < 1.0 || l.taux_minute < 0.0 {
+                let reponse = json!({"ok": false, "err": "Limite invalide (capacite >= 1, taux_minute >= 0)"});
+                return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+            }
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_conserver_configuration_quotas<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_conserver_configuration_quotas Consommer commande : {:?}", & m.message);
+    let commande: TransactionConserverConfigurationQuotas = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_conserver_configuration_quotas Commande parsed : {:?}", commande);
+
+    // Autorisation: delegation globale
+    if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_conserver_configuration_quotas: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    // Le quota souple doit etre inferieur au quota dur
+    let quotas_invalides = match (commande.quota_souple_octets, commande.quota_dur_octets) {
+        (Some(s), Some(d)) => s > d,
+        _ => false
+    } || match (commande.quota_souple_messages, commande.quota_dur_messages) {
+        (Some(s), Some(d)) => s > d,
+        _ => false
+    };
+    if quotas_invalides {
+        let reponse = json!({"ok": false, "err": "Quota souple superieur au quota dur"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_conserver_configuration_corbeille<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_conserver_configuration_corbeille Consommer commande : {:?}", & m.message);
+    let commande: TransactionConserverConfigurationCorbeille = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_conserver_configuration_corbeille Commande parsed : {:?}", commande);
+
+    // Autorisation: delegation globale
+    if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_conserver_configuration_corbeille: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    if let Some(retention_jours) = commande.retention_jours {
+        if retention_jours < 1 {
+            let reponse = json!({"ok": false, "err": "La retention doit etre d'au moins 1 jour"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
+async fn commande_conserver_politique_federation<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_conserver_politique_federation Consommer commande : {:?}", & m.message);
+    let commande: TransactionConserverPolitiqueFederation = m.message.get_msg().map_contenu()?;
+    debug!("commandes.commande_conserver_politique_federation Commande parsed : {:?}", commande);
+
+    // Autorisation: delegation globale
+    if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        Err(format!("commandes.commande_conserver_politique_federation: Commande autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    // Un idmg ne peut etre a la fois autorise et refuse
+    if let (Some(autorises), Some(refuses)) = (commande.idmgs_autorises.as_ref(), commande.idmgs_refuses.as_ref()) {
+        if let Some(idmg) = autorises.iter().find(|i| refuses.contains(i)) {
+            let reponse = json!({"ok": false, "err": format!("idmg {} autorise et refuse", idmg)});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction(middleware, m, gestionnaire).await?)
+}
+
 // async fn sauvegarde_attachement_cle<M>(middleware: &M, smtp: Value) -> Result<(), Box<dyn Error>>
 //     where M: GenerateurMessages
 // {
@@ -852,36 +1831,18 @@ async fn commande_conserver_cles_attachments<M>(middleware: &M, m: MessageValide
     };
 
     // Verifier aupres du maitredescles si les cles sont valides
-    let reponse_preuves = {
-        let requete_preuves = json!({"fingerprint": fingerprint_client, "preuves": &commande.preuves});
-        let routage_maitrecles = RoutageMessageAction::builder(
-            DOMAINE_NOM_MAITREDESCLES, REQUETE_MAITREDESCLES_VERIFIER_PREUVE)
-            .exchanges(vec![Securite::L4Secure])
-            .build();
-        debug!("commande_conserver_cles_attachments Requete preuve possession cles : {:?}", requete_preuves);
-        let reponse_preuve = match middleware.transmettre_requete(routage_maitrecles, &requete_preuves).await? {
-            TypeMessage::Valide(m) => {
-                match m.message.certificat.as_ref() {
-                    Some(c) => {
-                        if c.verifier_roles(vec![RolesCertificats::MaitreDesCles]) {
-                            debug!("commande_conserver_cles_attachments Reponse preuve : {:?}", m);
-                            let preuve_value: ReponsePreuvePossessionCles = m.message.get_msg().map_contenu()?;
-                            Ok(preuve_value)
-                        } else {
-                            Err(format!("commandes.commande_conserver_cles_attachments Erreur chargement certificat de reponse verification preuve, certificat n'est pas de role maitre des cles"))
-                        }
-                    },
-                    None => Err(format!("commandes.commande_conserver_cles_attachments Erreur chargement certificat de reponse verification preuve, certificat inconnu"))
-                }
-            },
-            m => Err(format!("commandes.commande_conserver_cles_attachments Erreur reponse message verification cles, mauvais type : {:?}", m))
-        }?;
-        debug!("commande_conserver_cles_attachments Reponse verification preuve : {:?}", reponse_preuve);
-
-        reponse_preuve.verification
+    let (reponse_preuves, preuves_verifiees) = match verifier_preuves_cles(middleware, fingerprint_client.clone(), &commande.preuves).await {
+        Ok(inner) => (inner, true),
+        Err(e) => {
+            // MaitreDesCles non disponible, les cles vont etre conservees dans l'outbox. La preuve
+            // sera verifiee avant de soumettre la cle.
+            warn!("commande_conserver_cles_attachments Erreur verification preuves, utiliser outbox : {:?}", e);
+            (HashMap::new(), false)
+        }
     };
 
     let mut resultat_fichiers = HashMap::new();
+    let mut cles_pending = Vec::new();
     for mut hachage_bytes in commande.cles.keys() {
         let fuuid = hachage_bytes.as_str();
 
@@ -896,13 +1857,36 @@ async fn commande_conserver_cles_attachments<M>(middleware: &M, m: MessageValide
                     .exchanges(vec![Securite::L4Secure])
                     .timeout_blocking(5000)
                     .build();
-                let reponse_cle = middleware.transmettre_commande(routage, &cle, true).await?;
+                let reponse_cle = match middleware.transmettre_commande(routage, &cle, true).await {
+                    Ok(inner) => inner,
+                    Err(e) => {
+                        warn!("commande_conserver_cles_attachments Erreur sauvegarde cle fuuid {} : {:?}", fuuid, e);
+                        None
+                    }
+                };
                 debug!("commande_conserver_cles_attachments Reponse sauvegarde cle : {:?}", reponse_cle);
-                if let Some(reponse) = reponse_cle {
-                    if let TypeMessage::Valide(mva) = reponse {
+                match reponse_cle {
+                    Some(TypeMessage::Valide(mva)) => {
                         debug!("Reponse valide : {:?}", mva);
                         let reponse_mappee: ReponseCle = mva.message.get_msg().map_contenu()?;
                         etat_cle = true;
+                    },
+                    Some(_) => (),
+                    None => {
+                        // Aucune reponse de MaitreDesCles, conserver la cle pour retry
+                        conserver_cle_outbox(
+                            middleware, fuuid, TYPE_CLE_ATTACHMENT, None, cle.partition.clone(), cle).await?;
+                        if !preuves_verifiees {
+                            if let Some(preuve) = commande.preuves.get(fuuid) {
+                                let verification = VerificationPreuveOutbox {
+                                    fingerprint: fingerprint_client.clone(),
+                                    preuves: HashMap::from([(fuuid.to_string(), preuve.to_owned())]),
+                                };
+                                conserver_preuve_outbox(middleware, fuuid, &verification).await?;
+                            }
+                        }
+                        cles_pending.push(fuuid.to_string());
+                        etat_cle = true;
                     }
                 }
             } else {
@@ -919,10 +1903,46 @@ async fn commande_conserver_cles_attachments<M>(middleware: &M, m: MessageValide
         }
     }
 
-    let reponse = json!({"resultat": resultat_fichiers});
+    let reponse = json!({"resultat": resultat_fichiers, "cles_pending": cles_pending});
     Ok(Some(middleware.formatter_reponse(&reponse, None)?))
 }
 
+pub async fn verifier_preuves_cles<M>(middleware: &M, fingerprint_client: String, preuves: &HashMap<String, PreuvePossessionCles>)
+    -> Result<HashMap<String, bool>, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let reponse_preuves = {
+        let requete_preuves = json!({"fingerprint": fingerprint_client, "preuves": preuves});
+        let routage_maitrecles = RoutageMessageAction::builder(
+            DOMAINE_NOM_MAITREDESCLES, REQUETE_MAITREDESCLES_VERIFIER_PREUVE)
+            .exchanges(vec![Securite::L4Secure])
+            .build();
+        debug!("commande_conserver_cles_attachments Requete preuve possession cles : {:?}", requete_preuves);
+        let reponse_preuve = match middleware.transmettre_requete(routage_maitrecles, &requete_preuves).await? {
+            TypeMessage::Valide(m) => {
+                match m.message.certificat.as_ref() {
+                    Some(c) => {
+                        if c.verifier_roles(vec![RolesCertificats::MaitreDesCles]) {
+                            debug!("commande_conserver_cles_attachments Reponse preuve : {:?}", m);
+                            let preuve_value: ReponsePreuvePossessionCles = m.message.get_msg().map_contenu()?;
+                            Ok(preuve_value)
+                        } else {
+                            Err(format!("commandes.commande_conserver_cles_attachments Erreur chargement certificat de reponse verification preuve, certificat n'est pas de role maitre des cles"))
+                        }
+                    },
+                    None => Err(format!("commandes.commande_conserver_cles_attachments Erreur chargement certificat de reponse verification preuve, certificat inconnu"))
+                }
+            },
+            m => Err(format!("commandes.commande_conserver_cles_attachments Erreur reponse message verification cles, mauvais type : {:?}", m))
+        }?;
+        debug!("commande_conserver_cles_attachments Reponse verification preuve : {:?}", reponse_preuve);
+
+        reponse_preuve.verification
+    };
+
+    Ok(reponse_preuves)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CommandeGenererClewebpushNotifications {}
 
@@ -1013,6 +2033,7 @@ async fn emettre_notifications_usager<M>(middleware: &M, m: MessageValideAction,
             CHAMP_NOTIFICATIONS_PENDING: false,
             CHAMP_EXPIRATION_LOCK_NOTIFICATIONS: Utc::now() + Duration::seconds(60),
             CHAMP_MESSAGE_ID_NOTIFICATIONS: [],  // Vider notifications
+            CHAMP_AVERTISSEMENT_QUOTA: false,
         },
         "$currentDate": { CHAMP_MODIFICATION: true },
     };
@@ -1192,6 +2213,10 @@ async fn generer_notification_usager<M>(middleware: &M, notifications: UsagerNot
     Ok(())
 }
 
+const NIVEAU_NOTIFICATION_CLE_CHANGEE: &str = "cle expediteur changee";
+const TITRE_AVERTISSEMENT_QUOTA: &str = "Boite de messages presque pleine";
+const TEXTE_AVERTISSEMENT_QUOTA: &str = "Votre boite de messages depasse le quota. Supprimez des messages pour continuer a en recevoir.";
+
 struct ContenuNotification {
     email_from: String,
     title: String,
@@ -1232,7 +2257,10 @@ async fn dechiffrer_notifications<M>(
         let d = r?;
         let di: DocumentIncoming = convertir_bson_deserializable(d)?;
         debug!("dechiffrer_notifications Message (notification) charge : {:?}", di);
-        let niveau = di.niveau.clone();
+        let niveau = match di.cle_changee {
+            Some(true) => Some(String::from(NIVEAU_NOTIFICATION_CLE_CHANGEE)),
+            _ => di.niveau.clone()
+        };
 
         let message_inter = MessageInterMillegrille::try_from(di.message)?;
         debug!("dechiffrer_notifications Message inter : {:?}", message_inter);
@@ -1309,15 +2337,21 @@ async fn generer_contenu_notification<M>(
     -> Result<ContenuNotification, Box<dyn Error>>
     where M: GenerateurMessages + MongoDao + CleChiffrageHandler
 {
+    let avertissement_quota = notifications.avertissement_quota == Some(true);
     let liste_message_ids = match notifications.message_id_notifications.as_ref() {
-        Some(inner) => inner,
-        None => Err(format!("Aucunes notifications fournies"))?
+        Some(inner) => inner.to_owned(),
+        None => Vec::new()
     };
+    if liste_message_ids.is_empty() && ! avertissement_quota {
+        Err(format!("Aucunes notifications fournies"))?
+    }
     let nombre_notifications = liste_message_ids.len();
 
-    let detail_notifications = if nombre_notifications <= 4 {
+    let detail_notifications = if nombre_notifications == 0 {
+        None
+    } else if nombre_notifications <= 4 {
         // Aller chercher les details des notifications et les dechiffrer
-        Some(dechiffrer_notifications(middleware, notifications.user_id.as_str(), liste_message_ids).await?)
+        Some(dechiffrer_notifications(middleware, notifications.user_id.as_str(), &liste_message_ids).await?)
     } else {
         None
     };
@@ -1374,6 +2408,11 @@ async fn generer_contenu_notification<M>(
                 (title, body_email, body_webpush)
             }
         },
+        None if nombre_notifications == 0 => {
+            debug!("generer_contenu_notification Generer notification avertissement quota");
+            let title = String::from(TITRE_AVERTISSEMENT_QUOTA);
+            (title, String::new(), String::new())
+        },
         None => {
             debug!("generer_contenu_notification Generer notification generique pour {} messages", nombre_notifications);
 
@@ -1415,6 +2454,18 @@ async fn generer_contenu_notification<M>(
     // let body_email = format!("{} nouveaux messages sont disponibles.\nAccedez au contenu sur la page web MilleGrilles.", nombre_notifications);
     // let body_webpush = format!("{} nouveaux messages sont disponibles.\nAccedez au contenu sur la page web MilleGrilles.", nombre_notifications);
 
+    let (body_email, body_webpush) = match avertissement_quota {
+        true => {
+            let separateur = match body_email.is_empty() {
+                true => "",
+                false => "\n\n"
+            };
+            (format!("{}{}{}", body_email, separateur, TEXTE_AVERTISSEMENT_QUOTA),
+             format!("{}{}{}", body_webpush, separateur, TEXTE_AVERTISSEMENT_QUOTA))
+        },
+        false => (body_email, body_webpush)
+    };
+
     let email_from = match &configuration_notifications {
         Some(inner) => match inner.email_from.as_ref() {
             Some(inner) => inner.to_owned(),
@@ -1647,7 +2698,7 @@ pub async fn recevoir_notification<M>(
         // Sauvegarder message pour l'usager
         debug!("transaction_recevoir Sauvegarder message pour usager : {}", user_id);
         // map_usagers.insert(user_id.to_owned(), Some(user_id.to_owned()));
-        liste_usagers.push(DestinataireInfo {adresse: None, user_id: Some(user_id.to_owned())});
+        liste_usagers.push(DestinataireInfo {adresse: None, user_id: Some(user_id.to_owned()), code: None});
 
         let doc_user_reception = doc! {
             "user_id": user_id,
@@ -1719,9 +2770,94 @@ pub async fn recevoir_notification<M>(
     Ok(())
 }
 
+/// Signalement d'abus recu d'une millegrille tierce a propos d'un message emis par un usager local.
+async fn commande_recevoir_signalement_externe<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    debug!("commandes.commande_recevoir_signalement_externe Consommer commande : {:?}", & m.message);
+    let commande: CommandeRecevoirSignalementExterne = m.message.get_msg().map_contenu()?;
+
+    let taille_signalement = serde_json::to_vec(&commande.signalement)?.len();
+    if taille_signalement > TAILLE_MAX_SIGNALEMENT {
+        warn!("commande_recevoir_signalement_externe Signalement trop gros ({} bytes)", taille_signalement);
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Signalement trop gros"}), None)?))
+    }
+
+    let options = ValidationOptions::new(true, false, true);
+    let mut enveloppe_signalement = MessageSerialise::from_parsed(commande.signalement)?;
+    let validation = enveloppe_signalement.valider(middleware, Some(&options)).await?;
+    if validation.valide() == false {
+        error!("commande_recevoir_signalement_externe Signalement invalide : {:?}", validation);
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Signalement invalide"}), None)?))
+    }
+
+    let idmg_source = match enveloppe_signalement.parsed.origine.as_ref() {
+        Some(inner) => inner.to_owned(),
+        None => {
+            error!("commande_recevoir_signalement_externe Signalement sans origine (idmg)");
+            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Signalement sans origine"}), None)?))
+        }
+    };
+
+    let politique = charger_politique_federation(middleware).await?;
+    if ! politique.autorise(idmg_source.as_str()) {
+        let reponse = json!({"ok": false, "err": "Millegrille refusee par la politique de federation", "code": CODE_FEDERATION_REFUSEE});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    let fingerprint_certificat = match enveloppe_signalement.certificat.as_ref() {
+        Some(inner) => Some(inner.fingerprint.as_str()),
+        None => None
+    };
+    if let Some(retry_after) = verifier_limites_reception(middleware, idmg_source.as_str(), fingerprint_certificat).await? {
+        let reponse = json!({"ok": false, "err": "Limite de reception atteinte", "code": CODE_LIMITE_RECEPTION, "retry_after": retry_after});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    let destinataires_rejeu = vec![DESTINATAIRE_REJEU_SIGNALEMENT.to_string()];
+    if let Some(raison) = verifier_rejeu(middleware, &enveloppe_signalement.parsed, &destinataires_rejeu).await? {
+        let reponse = json!({"ok": false, "err": "Signalement rejete (rejeu)", "code": CODE_REJEU, "raison": raison});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    let signalement: SignalementAbus = enveloppe_signalement.parsed.map_contenu()?;
+
+    // La preuve doit etre un message emis par la millegrille locale
+    let mut enveloppe_preuve = MessageSerialise::from_parsed(signalement.message.clone())?;
+    if enveloppe_preuve.parsed.origine.as_ref().map(|o| o.as_str()) != Some(middleware.idmg()) {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Message signale hors de la millegrille"}), None)?))
+    }
+    let validation_preuve = enveloppe_preuve.valider(middleware, Some(&options)).await?;
+    if validation_preuve.valide() == false {
+        error!("commande_recevoir_signalement_externe Message signale invalide : {:?}", validation_preuve);
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Message signale invalide"}), None)?))
+    }
+
+    info!("commande_recevoir_signalement_externe Signalement {} du message {} par idmg {}",
+        enveloppe_signalement.parsed.id, signalement.message_id, idmg_source);
+    let doc_signalement = DocSignalement {
+        signalement_id: enveloppe_signalement.parsed.id.clone(),
+        type_signalement: TYPE_SIGNALEMENT_EXTERNE.to_string(),
+        user_id: None,
+        idmg: Some(idmg_source),
+        message_id: signalement.message_id,
+        raison: signalement.raison,
+        message: signalement.message,
+        date_signalement: DateEpochSeconds::now(),
+    };
+    if ! enregistrer_reception(middleware, &enveloppe_signalement.parsed, &destinataires_rejeu).await? {
+        let reponse = json!({"ok": false, "err": "Signalement rejete (rejeu)", "code": CODE_REJEU, "raison": RAISON_REJEU_DEJA_RECU});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+    conserver_signalement(middleware, &doc_signalement).await?;
+
+    Ok(middleware.reponse_ok()?)
+}
+
 async fn commande_recevoir_externe<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
-    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + CleChiffrageHandler
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + CleChiffrageHandler + ChiffrageFactoryTrait
 {
     debug!("commandes.commande_recevoir_externe Consommer commande : {:?}", & m.message);
     let mut commande: CommandeRecevoirPostExterne = m.message.get_msg().map_contenu()?;
@@ -1732,28 +2868,77 @@ async fn commande_recevoir_externe<M>(middleware: &M, m: MessageValideAction, ge
     let mut enveloppe_transfert = MessageSerialise::from_parsed(commande.transfert)?;
     // let enveloppe_cles = commande.cles;
 
-    // Valider messages
+    // Valider messages. Le transfert est valide en premier : son certificat authentifie la
+    // millegrille qui transmet, une anomalie du message lui est attribuee.
     {
+        let validation_transfert = enveloppe_transfert.valider(middleware, Some(&options)).await?;
+        if validation_transfert.valide() == false {
+            error!("commande_recevoir_externe Message transfert invalide : {:?}", validation_transfert);
+            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Message transfert invalide"}), None)?))
+        }
+
         let validation_message = enveloppe_message.valider(middleware, Some(&options)).await?;
         if validation_message.valide() == false {
             error!("commande_recevoir_externe Message inter-millegrille invalide : {:?}", validation_message);
+            // Penaliser seulement un idmg prouve par une chaine de certificats valide. L'origine
+            // declaree du message n'est pas fiable (falsification contre une millegrille tierce).
+            let certificat_prouve = match validation_message.certificat_valide {
+                true => enveloppe_message.certificat.as_ref(),
+                false => enveloppe_transfert.certificat.as_ref()
+            };
+            if let Some(idmg) = certificat_prouve.and_then(|c| c.idmg().ok()) {
+                if let Err(e) = maj_reputation(middleware, idmg.as_str(), &[(COMPTEUR_ANOMALIES_CERTIFICAT, 1.0)]).await {
+                    warn!("commande_recevoir_externe Erreur maj reputation idmg {} : {:?}", idmg, e);
+                }
+            }
             if validation_message.certificat_valide == false {
                 return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Certificat message invalide"}), None)?))
             }
             return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Hachage/Signature message invalide"}), None)?))
         }
+    }
 
-        let validation_transfert = enveloppe_transfert.valider(middleware, Some(&options)).await?;
-        if validation_transfert.valide() == false {
-            error!("commande_recevoir_externe Message transfert invalide : {:?}", validation_transfert);
-            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Message transfert invalide"}), None)?))
+    // Limites de reception par millegrille source et par certificat expediteur
+    {
+        let idmg_source = match enveloppe_message.parsed.origine.as_ref() {
+            Some(inner) => inner.as_str(),
+            None => {
+                error!("commande_recevoir_externe Message sans origine (idmg)");
+                return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Message sans origine"}), None)?))
+            }
+        };
+
+        // Politique de federation de l'instance
+        let politique = charger_politique_federation(middleware).await?;
+        if ! politique.autorise(idmg_source) {
+            let message_id = enveloppe_message.parsed.id.as_str();
+            if let Err(e) = emettre_evenement_federation_refusee(middleware, idmg_source, DIRECTION_ENTRANT, message_id).await {
+                warn!("commande_recevoir_externe Erreur emission evenement federation refusee : {:?}", e);
+            }
+            let reponse = json!({"ok": false, "err": "Millegrille refusee par la politique de federation", "code": CODE_FEDERATION_REFUSEE});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+
+        // Fenetre d'acceptation selon l'estampille du message signe
+        if let Some(raison) = verifier_estampille(&enveloppe_message.parsed) {
+            let reponse = json!({"ok": false, "err": "Message rejete (rejeu)", "code": CODE_REJEU, "raison": raison});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+
+        let fingerprint_certificat = match enveloppe_message.certificat.as_ref() {
+            Some(inner) => Some(inner.fingerprint.as_str()),
+            None => None
+        };
+        if let Some(retry_after) = verifier_limites_reception(middleware, idmg_source, fingerprint_certificat).await? {
+            let reponse = json!({"ok": false, "err": "Limite de reception atteinte", "code": CODE_LIMITE_RECEPTION, "retry_after": retry_after});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
         }
     }
 
     // Dechiffrer commande poster transfert (destinataires, fuuids, cle message)
-    let commande_transfert = match dechiffrer_cle_message(middleware, &enveloppe_transfert.parsed).await {
+    let mut commande_transfert = match dechiffrer_cle_message(middleware, &enveloppe_transfert.parsed).await {
         Ok(cle_secrete) => {
-            let transfert_inter = MessageInterMillegrille::try_from(enveloppe_transfert.parsed)?;
+            let transfert_inter = MessageInterMillegrille::try_from(enveloppe_transfert.parsed.clone())?;
             let contenu_dechiffre = transfert_inter.dechiffrer_avec_cle(middleware, cle_secrete)?;
             let commande_transfert: CommandeTransfertPoster = serde_json::from_slice(&contenu_dechiffre.data_dechiffre[..])?;
             commande_transfert
@@ -1766,6 +2951,12 @@ async fn commande_recevoir_externe<M>(middleware: &M, m: MessageValideAction, ge
 
     debug!("commande_recevoir_externe Commande transfert dechiffree : {:?}", commande_transfert);
 
+    // Protection contre le rejeu du message signe pour chaque destinataire
+    if let Some(raison) = verifier_rejeu(middleware, &enveloppe_message.parsed, &commande_transfert.to).await? {
+        let reponse = json!({"ok": false, "err": "Message rejete (rejeu)", "code": CODE_REJEU, "raison": raison});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
     // Preparer commande/transaction sauvegarder cle - permet de valider le message
     let commande_sauvegarder_cle = match enveloppe_message.parsed.dechiffrage.as_ref() {
         Some(inner) => {
@@ -1811,9 +3002,33 @@ async fn commande_recevoir_externe<M>(middleware: &M, m: MessageValideAction, ge
     debug!("Commande sauvegarder cles : {:?}", commande_sauvegarder_cle);
 
     // Faire correspondre les destinataires aux usagers locaux
-    let mut destinataires_user_id = extraire_destinataires(
+    let destinataires_user_id = extraire_destinataires(
         middleware, &commande_transfert.to).await?;
 
+    // Appliquer le filtre expediteurs des destinataires
+    let idmg_origine = match enveloppe_message.parsed.origine.as_ref() {
+        Some(inner) => inner.to_owned(),
+        None => {
+            error!("commande_recevoir_externe Message sans origine (idmg)");
+            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Message sans origine"}), None)?))
+        }
+    };
+    // L'adresse declaree (from) est fournie par la millegrille tierce. Elle est conservee
+    // seulement si son hostname correspond a la millegrille d'origine du message signe.
+    if let Some(from) = commande_transfert.from.as_ref() {
+        if ! verifier_adresse_idmg(middleware, from.as_str(), idmg_origine.as_str()).await? {
+            warn!("commande_recevoir_externe Adresse from {} ne correspond pas a l'idmg {}, retiree", from, idmg_origine);
+            commande_transfert.from = None;
+        }
+    }
+
+    let destinataires_user_id = filtrer_destinataires_expediteur(
+        middleware, destinataires_user_id, commande_transfert.from.as_ref().map(|f| f.as_str()),
+        enveloppe_message.parsed.pubkey.as_str(), idmg_origine.as_str()).await?;
+
+    // Refuser les destinataires dont la boite est pleine (quota dur)
+    let mut destinataires_user_id = verifier_quotas_destinataires(middleware, destinataires_user_id).await?;
+
     let destinataires_reponse = {
         let mut destinataires_reponse = HashMap::new();
         let mut au_moins_1_user = false;
@@ -1828,14 +3043,19 @@ async fn commande_recevoir_externe<M>(middleware: &M, m: MessageValideAction, ge
                     destinataires_reponse.insert(adresse.to_owned(), 200 as u32);  // Trouve
                 },
                 None => {
-                    destinataires_reponse.insert(adresse.to_owned(), 404 as u32);  // Inconnu
+                    // Inconnu ou expediteur refuse
+                    destinataires_reponse.insert(adresse.to_owned(), user.code.unwrap_or(404) as u32);
                 }
             }
         }
 
+        if let Err(e) = enregistrer_reception_reputation(middleware, idmg_origine.as_str(), &destinataires_reponse).await {
+            warn!("commande_recevoir_externe Erreur maj reputation idmg {} : {:?}", idmg_origine, e);
+        }
+
         if au_moins_1_user == false {
             error!("commande_recevoir_externe Aucuns destinataires connus localement");
-            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Aucuns destinataires connus"}), None)?))
+            return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": "Aucuns destinataires connus", "destinataires": &destinataires_reponse}), None)?))
         }
 
         destinataires_reponse
@@ -1888,6 +3108,30 @@ async fn commande_recevoir_externe<M>(middleware: &M, m: MessageValideAction, ge
         }
     }
 
+    // Reputation de la millegrille source, quarantaine sous le seuil de la politique
+    let quarantaine = match verifier_quarantaine_reputation(middleware, idmg_origine.as_str()).await {
+        Ok(true) => Some(true),
+        Ok(false) => None,
+        Err(e) => {
+            warn!("commande_recevoir_externe Erreur verification reputation idmg {} : {:?}", idmg_origine, e);
+            None
+        }
+    };
+
+    // Conserver la reception du message par destinataire, refuser une reception concurrente
+    let message_id = enveloppe_message.parsed.id.clone();
+    let destinataires_rejeu = commande_transfert.to.clone();
+    if ! enregistrer_reception(middleware, &enveloppe_message.parsed, &destinataires_rejeu).await? {
+        let reponse = json!({"ok": false, "err": "Message rejete (rejeu)", "code": CODE_REJEU, "raison": RAISON_REJEU_DEJA_RECU});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    // Les messages systeme (certificat sans user_id) ne declenchent pas de reponse automatique
+    let certificat_usager = match enveloppe_message.certificat.as_ref() {
+        Some(c) => c.get_user_id()?.is_some(),
+        None => false
+    };
+
     // Sauvegarder et traiter transaction du message
     enveloppe_message.parsed.retirer_certificats();
     enveloppe_message.parsed.retirer_attachments();
@@ -1896,12 +3140,30 @@ async fn commande_recevoir_externe<M>(middleware: &M, m: MessageValideAction, ge
         destinataires_user_id,
         fuuids: commande_transfert.files,
         fichiers,
+        from: commande_transfert.from,
+        type_envoi: commande_transfert.type_envoi,
+        transfert: commande_transfert.transfert,
+        quarantaine,
+        // Thread d'une millegrille tierce, ignore s'il est invalide
+        thread: commande_transfert.thread.filter(|t| verifier_thread(t.as_str())),
     };
 
-    let resultat_traitement = sauvegarder_traiter_transaction_serializable(
-        middleware, &commande_post, gestionnaire, DOMAINE_NOM, TRANSACTION_RECEVOIR).await?;
+    let resultat_traitement = match sauvegarder_traiter_transaction_serializable(
+        middleware, &commande_post, gestionnaire, DOMAINE_NOM, TRANSACTION_RECEVOIR).await {
+        Ok(inner) => inner,
+        Err(e) => {
+            // Permettre un retry du meme message
+            if let Err(e) = retirer_reception(middleware, message_id.as_str(), &destinataires_rejeu).await {
+                warn!("commande_recevoir_externe Erreur retrait anti-rejeu {} : {:?}", message_id, e);
+            }
+            Err(e)?
+        }
+    };
     debug!("commande_recevoir_externe Resultat traitement transaction : {:?}", resultat_traitement);
 
+    traiter_suites_reception(
+        middleware, gestionnaire, &commande_post, certificat_usager, resultat_traitement.as_ref()).await;
+
     let reponse = json!({"ok": true, "adresses": destinataires_reponse});
     let reponse = middleware.formatter_reponse(&reponse, None)?;
 
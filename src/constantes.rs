@@ -4,6 +4,7 @@ use millegrilles_common_rust::serde::{Deserialize, Serialize};
 
 pub const DOMAINE_NOM: &str = "Messagerie";
 pub const DOMAINE_POSTMASTER: &str = "postmaster";
+pub const REQUETE_POSTMASTER_PING: &str = "ping";
 pub const NOM_COLLECTION_TRANSACTIONS: &str = "Messagerie";
 pub const NOM_COLLECTION_CONFIGURATION: &str = "Messagerie/configuration";
 pub const NOM_COLLECTION_INCOMING: &str = "Messagerie/incoming";
@@ -14,6 +15,20 @@ pub const NOM_COLLECTION_ATTACHMENTS_PROCESSING: &str = "Messagerie/attachments_
 pub const NOM_COLLECTION_PROFILS: &str = "Messagerie/profils";
 pub const NOM_COLLECTION_CONTACTS: &str = "Messagerie/contacts";
 pub const NOM_COLLECTION_NOTIFICATIONS_OUTGOING: &str = "Messagerie/notifications_outgoing";
+pub const NOM_COLLECTION_SANTE_URLS: &str = "Messagerie/sante_urls";
+pub const NOM_COLLECTION_CLES_OUTBOX: &str = "Messagerie/cles_outbox";
+pub const NOM_COLLECTION_SOURCES_RECEPTION: &str = "Messagerie/sources_reception";
+pub const NOM_COLLECTION_USAGE_BOITES: &str = "Messagerie/usage_boites";
+pub const NOM_COLLECTION_REPONSES_AUTOMATIQUES: &str = "Messagerie/reponses_automatiques";
+pub const NOM_COLLECTION_REPUTATION: &str = "Messagerie/reputation";
+pub const NOM_COLLECTION_SIGNALEMENTS: &str = "Messagerie/signalements";
+pub const NOM_COLLECTION_ANTI_REJEU: &str = "Messagerie/anti_rejeu";
+pub const NOM_COLLECTION_CERTIFICATS: &str = "Messagerie/certificats";
+pub const NOM_COLLECTION_CLES_EXPEDITEURS: &str = "Messagerie/cles_expediteurs";
+pub const NOM_COLLECTION_LABELS: &str = "Messagerie/labels";
+pub const NOM_COLLECTION_TRANSFERTS: &str = "Messagerie/transferts";
+/// Code d'erreur MongoDB pour une cle dupliquee (index unique).
+pub const CODE_ERREUR_DUPLICATION_MONGO: i32 = 11000;
 
 pub const DOMAINE_FICHIERS_NOM: &str = "fichiers";
 
@@ -35,6 +50,12 @@ pub const REQUETE_GET_USAGER_ACCES_ATTACHMENTS: &str = "getUsagerAccesAttachment
 pub const REQUETE_GET_CLES_STREAM: &str = "getClesStream";
 pub const REQUETE_GET_CONFIGURATION_NOTIFICATIONS: &str = "getConfigurationNotifications";
 pub const REQUETE_GET_CLEPUBLIQUE_WEBPUSH: &str = "getClepubliqueWebpush";
+pub const REQUETE_GET_SOURCES_RECEPTION: &str = "getSourcesReception";
+pub const REQUETE_GET_USAGE_BOITE: &str = "getUsageBoite";
+pub const REQUETE_GET_REPUTATIONS: &str = "getReputations";
+pub const REQUETE_GET_SIGNALEMENTS: &str = "getSignalements";
+pub const REQUETE_GET_LABELS: &str = "getLabels";
+pub const REQUETE_GET_CONVERSATIONS: &str = "getConversations";
 
 pub const COMMANDE_CONFIRMER_TRANSMISSION: &str = "confirmerTransmission";
 pub const COMMANDE_PROCHAIN_ATTACHMENT: &str = "prochainAttachment";
@@ -44,14 +65,17 @@ pub const COMMANDE_GENERER_CLEWEBPUSH_NOTIFICATIONS: &str = "genererClewebpushNo
 pub const COMMANDE_EMETTRE_NOTIFICATIONS_USAGER: &str = "emettreNotificationsUsager";
 pub const COMMANDE_POST_NOTIFICATION: &str = "postNotification";
 pub const COMMANDE_RECEVOIR_EXTERNE: &str = "recevoirExterne";
+pub const COMMANDE_RECEVOIR_SIGNALEMENT_EXTERNE: &str = "recevoirSignalementExterne";
 
 pub const TRANSACTION_POSTER: &str = "poster";
 pub const TRANSACTION_RECEVOIR: &str = "recevoir";
 pub const TRANSACTION_INITIALISER_PROFIL: &str = "initialiserProfil";
 pub const TRANSACTION_MAJ_CONTACT: &str = "majContact";
 pub const TRANSACTION_LU: &str = "lu";
+pub const TRANSACTION_ETOILE: &str = "etoile";
 pub const TRANSACTION_TRANSFERT_COMPLETE: &str = "transfertComplete";
 pub const TRANSACTION_SUPPRIMER_MESSAGES: &str = "supprimerMessages";
+pub const TRANSACTION_RESTAURER_MESSAGES: &str = "restaurerMessages";
 pub const TRANSACTION_SUPPRIMER_CONTACTS: &str = "supprimerContacts";
 pub const TRANSACTION_TRANSFERT_FICHIERS_COMPLETES: &str = "fichiersCompletes";
 pub const TRANSACTION_CONFIRMER_TRANMISSION_MILLEGRILLE: &str = "confirmerTransmissionMillegrille";
@@ -61,6 +85,26 @@ pub const TRANSACTION_SAUVEGARDER_USAGER_CONFIG_NOTIFICATIONS: &str = "sauvegard
 pub const TRANSACTION_SAUVEGARDER_SUBSCRIPTION_WEBPUSH: &str = "sauvegarderSubscriptionWebpush";
 pub const TRANSACTION_RETIRER_SUBSCRIPTION_WEBPUSH: &str = "retirerSubscriptionWebpush";
 pub const TRANSACTION_NOTIFIER: &str = "notifier";
+pub const TRANSACTION_MAJ_FILTRE_EXPEDITEURS: &str = "majFiltreExpediteurs";
+pub const TRANSACTION_CONSERVER_CONFIGURATION_LIMITES_RECEPTION: &str = "conserverConfigurationLimitesReception";
+pub const TRANSACTION_CONSERVER_CONFIGURATION_QUOTAS: &str = "conserverConfigurationQuotas";
+pub const TRANSACTION_CONSERVER_CONFIGURATION_CORBEILLE: &str = "conserverConfigurationCorbeille";
+pub const TRANSACTION_CONSERVER_POLITIQUE_FEDERATION: &str = "conserverPolitiqueFederation";
+pub const TRANSACTION_MAJ_REPONSE_AUTOMATIQUE: &str = "majReponseAutomatique";
+pub const TRANSACTION_MAJ_REGLES_TRANSFERT: &str = "majReglesTransfert";
+pub const TRANSACTION_MAJ_REGLES_MESSAGES: &str = "majReglesMessages";
+pub const TRANSACTION_MAJ_QUARANTAINE: &str = "majQuarantaine";
+pub const TRANSACTION_TRAITER_QUARANTAINE: &str = "traiterQuarantaine";
+pub const TRANSACTION_TRAITER_QUARANTAINE_MESSAGES: &str = "traiterQuarantaineMessages";
+pub const TRANSACTION_SIGNALER_MESSAGE: &str = "signalerMessage";
+pub const TRANSACTION_ACCEPTER_CLE_EXPEDITEUR: &str = "accepterCleExpediteur";
+pub const TRANSACTION_MAJ_LABEL: &str = "majLabel";
+pub const TRANSACTION_SUPPRIMER_LABEL: &str = "supprimerLabel";
+pub const TRANSACTION_AJOUTER_LABEL_MESSAGES: &str = "ajouterLabelMessages";
+pub const TRANSACTION_RETIRER_LABEL_MESSAGES: &str = "retirerLabelMessages";
+pub const TRANSACTION_ARCHIVER_MESSAGES: &str = "archiverMessages";
+pub const TRANSACTION_DESARCHIVER_MESSAGES: &str = "desarchiverMessages";
+pub const TRANSACTION_PURGER_MESSAGES: &str = "purgerMessages";
 
 
 // pub const COMMANDE_INDEXER: &str = "indexerContenu";
@@ -71,11 +115,21 @@ pub const EVENEMENT_POMPE_POSTE: &str = "pompePoste";
 pub const EVENEMENT_MAJ_CONTACT: &str = "majContact";
 pub const EVENEMENT_NOUVEAU_MESSAGE: &str = "nouveauMessage";
 pub const EVENEMENT_MESSAGE_LU: &str = "messageLu";
+pub const EVENEMENT_MESSAGE_ETOILE: &str = "messageEtoile";
 pub const EVENEMENT_MESSAGES_SUPPRIMES: &str = "messagesSupprimes";
+pub const EVENEMENT_MESSAGES_RESTAURES: &str = "messagesRestaures";
+pub const EVENEMENT_MESSAGES_ARCHIVES: &str = "messagesArchives";
+pub const EVENEMENT_MESSAGES_DESARCHIVES: &str = "messagesDesarchives";
 pub const EVENEMENT_CONTACTS_SUPPRIMES: &str = "contactsSupprimes";
 pub const EVENEMENT_FICHIERS_CONSIGNE: &str = "consigne";
 pub const EVENEMENT_CONFIRMER_ETAT_FUUIDS: &str = "confirmerEtatFuuids";
 pub const EVENEMENT_CONFIRMER_MESSAGE_COMPLETE: &str = "confirmerMessageComplete";
+pub const EVENEMENT_PRESENCE_POSTMASTER: &str = "presence";
+pub const EVENEMENT_AVERTISSEMENT_QUOTA: &str = "avertissementQuota";
+pub const EVENEMENT_FEDERATION_REFUSEE: &str = "federationRefusee";
+pub const EVENEMENT_CLE_EXPEDITEUR_CHANGEE: &str = "cleExpediteurChangee";
+pub const EVENEMENT_MAJ_LABEL: &str = "majLabel";
+pub const EVENEMENT_LABELS_MESSAGES: &str = "labelsMessages";
 
 pub const CHAMP_FUUID: &str = "fuuid";  // UUID fichier
 pub const CHAMP_FUUIDS: &str = "fuuids";
@@ -84,6 +138,7 @@ pub const CHAMP_TUUIDS: &str = "tuuids";
 pub const CHAMP_CUUID: &str = "cuuid";  // UUID collection de tuuids
 pub const CHAMP_CUUIDS: &str = "cuuids";  // Liste de cuuids (e.g. appartenance a plusieurs collections)
 pub const CHAMP_SUPPRIME: &str = "supprime";
+pub const CHAMP_DATE_SUPPRIME: &str = "date_supprime";
 pub const CHAMP_NOM: &str = "nom";
 pub const CHAMP_NOM_USAGER: &str = "nomUsager";
 pub const CHAMP_TITRE: &str = "titre";
@@ -98,6 +153,8 @@ pub const CHAMP_USER_ID: &str = "user_id";
 pub const CHAMP_DATE_RECEPTION: &str = "date_reception";
 pub const CHAMP_DATE_ENVOI: &str = "date_envoi";
 pub const CHAMP_FLAG_LU: &str = "lu";
+/// Jeton opaque (derive par le client) de la conversation d'un message.
+pub const CHAMP_THREAD_MESSAGE: &str = "thread";
 pub const CHAMP_MESSAGE_ID: &str = "message_id";
 pub const CHAMP_UUID_MESSAGE: &str = CHAMP_MESSAGE_ID;
 pub const CHAMP_LAST_PROCESSED: &str = "last_processed";
@@ -114,16 +171,81 @@ pub const CHAMP_EXPIRATION_LOCK_NOTIFICATIONS: &str = "expiration_lock_notificat
 pub const CHAMP_MESSAGE_ID_NOTIFICATIONS: &str = "message_id_notifications";
 pub const CHAMP_UUID_TRANSACTIONS_NOTIFICATIONS: &str = CHAMP_MESSAGE_ID_NOTIFICATIONS;
 pub const CHAMP_NOTIFICATIONS_PENDING: &str = "notifications_pending";
+pub const CHAMP_NOTIFICATIONS_ACTIVES: &str = "notifications_actives";
+pub const CHAMP_DERNIERE_NOTIFICATION: &str = "derniere_notification";
+pub const CHAMP_AVERTISSEMENT_QUOTA: &str = "avertissement_quota";
+pub const CHAMP_URL: &str = "url";
+pub const CHAMP_CLE_ID: &str = "cle_id";
+pub const CHAMP_CLE_PENDING: &str = "cle_pending";
+pub const CHAMP_CONFIRME: &str = "confirme";
+pub const CHAMP_PROCHAINE_TENTATIVE: &str = "prochaine_tentative";
+pub const CHAMP_DNS_FAILURE: &str = "dns_failure";
+pub const CHAMP_DNS_FAILURE_RETRY: &str = "dns_failure_retry";
+pub const CHAMP_DNS_FAILURE_RETRY_COUNT: &str = "dns_failure_retry_count";
+pub const CHAMP_DNS_FAILURE_NEXT_RETRY: &str = "dns_failure_next_retry";
+pub const CHAMP_FILTRE_EXPEDITEURS: &str = "filtre_expediteurs";
+pub const CHAMP_TYPE_SOURCE: &str = "type_source";
+pub const CHAMP_SOURCE: &str = "source";
+pub const CHAMP_TAILLES_FICHIERS: &str = "tailles_fichiers";
+pub const CHAMP_REPONSE_AUTOMATIQUE: &str = "reponse_automatique";
+pub const CHAMP_REGLES_TRANSFERT: &str = "regles_transfert";
+pub const CHAMP_ADRESSE: &str = "adresse";
+pub const CHAMP_REGLES_MESSAGES: &str = "regles_messages";
+pub const CHAMP_LABELS: &str = "labels";
+pub const CHAMP_ARCHIVE: &str = "archive";
+pub const CHAMP_DATE_ARCHIVE: &str = "date_archive";
+
+// Boites pour requete getMessages (messages recus)
+pub const BOITE_RECEPTION: &str = "reception";
+pub const BOITE_ARCHIVE: &str = "archive";
+pub const BOITE_TOUS: &str = "tous";
+pub const CHAMP_ETOILE: &str = "etoile";
+pub const CHAMP_QUARANTAINE: &str = "quarantaine";
+pub const CHAMP_INDEX_ADRESSES: &str = "index_adresses";
+pub const CHAMP_INDEX_EXPEDITEUR: &str = "index_expediteur";
+/// Reference de la cle du message (dechiffrage.hachage), conservee lors des transferts.
+pub const CHAMP_CLE_MESSAGE: &str = "cle_message";
+pub const CHAMP_SIGNALE: &str = "signale";
+pub const CHAMP_DATE_SIGNALEMENT: &str = "date_signalement";
+pub const CHAMP_SIGNALEMENT_ID: &str = "signalement_id";
+pub const CHAMP_CLE_CHANGEE: &str = "cle_changee";
+pub const CHAMP_INDEX_CLE_EXPEDITEUR: &str = "index_cle_expediteur";
+pub const CHAMP_LABEL_ID: &str = "label_id";
 
 pub const CONFIG_KEY_NOTIFICATIONS: &str = "notifications";
 pub const CONFIG_KEY_CLEWEBPUSH: &str = "cle_webpush";
+pub const CONFIG_KEY_LIMITES_RECEPTION: &str = "limites_reception";
+pub const CONFIG_KEY_QUOTAS_BOITES: &str = "quotas_boites";
+pub const CONFIG_KEY_CORBEILLE: &str = "corbeille";
+pub const CONFIG_KEY_POLITIQUE_FEDERATION: &str = "politique_federation";
 
 pub const CODE_UPLOAD_DEBUT: u32 = 1;
 pub const CODE_UPLOAD_ENCOURS: u32 = 2;
 pub const CODE_UPLOAD_TERMINE: u32 = 3;
 pub const CODE_UPLOAD_ERREUR: u32 = 4;
+/// Code de livraison pour un destinataire qui refuse l'expediteur (filtre expediteurs).
+pub const CODE_DESTINATAIRE_REFUSE: i32 = 403;
+/// Code de reponse recevoirExterne lorsque la limite de reception est atteinte (retry_after).
+pub const CODE_LIMITE_RECEPTION: i32 = 429;
+/// Code de livraison pour un destinataire dont la boite a atteint le quota dur.
+pub const CODE_QUOTA_DEPASSE: i32 = 507;
+/// Code de livraison lorsque la millegrille est refusee par la politique de federation.
+pub const CODE_FEDERATION_REFUSEE: i32 = 451;
+/// Code de reponse recevoirExterne pour un message deja recu ou hors de la fenetre d'acceptation.
+pub const CODE_REJEU: i32 = 409;
+/// Code de livraison pour un destinataire dont le DNS n'a pu etre resolu avant l'age maximal.
+pub const CODE_DNS_ECHEC: i32 = 502;
+
+/// Type d'envoi des reponses automatiques. Le repondeur automatique ne repond jamais a un
+/// envoi automatise (type_envoi present).
+pub const TYPE_ENVOI_REPONSE_AUTOMATIQUE: &str = "reponseAutomatique";
+
+/// Nombre maximal de transferts successifs d'un message (regles de transfert).
+pub const CONST_TRANSFERTS_MAX: usize = 5;
 
 pub const CONST_ADRESSE_SEPARATEUR_HOST: &str = ":";
 pub const CONST_ADRESSE_PREFIXE_USAGER: &str = "@";
 
 pub const CONST_EXPIRATION_NOTIFICATION_DEFAUT: i64 = 7 * 24 * 60 * 60;
+/// Delai (secondes) apres lequel postmaster est considere absent sans ping/evenement de presence.
+pub const CONST_DELAI_PRESENCE_POSTMASTER: i64 = 150;
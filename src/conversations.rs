@@ -0,0 +1,109 @@
+use std::error::Error;
+
+use log::debug;
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::AggregateOptions;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+
+/// Longueur maximale d'un thread de conversation.
+const LONGUEUR_MAX_THREAD: usize = 128;
+
+/// Conversation (thread) avec les compteurs de messages recus et envoyes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Conversation {
+    #[serde(rename="_id")]
+    pub thread: String,
+    /// Estampille (epoch secondes) du message le plus recent.
+    pub derniere_activite: i64,
+    pub messages: i64,
+    pub non_lus: i64,
+    pub envoyes: i64,
+}
+
+/// Le thread est un jeton opaque derive par le client. Seuls la longueur et les caracteres
+/// (ascii visibles) sont verifies.
+pub fn verifier_thread(thread: &str) -> bool {
+    ! thread.is_empty() && thread.len() <= LONGUEUR_MAX_THREAD && thread.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// Charge les conversations de l'usager (messages recus et envoyes non supprimes), de la plus
+/// recente a la plus ancienne. Les messages recus en quarantaine sont exclus.
+pub async fn charger_conversations<M>(middleware: &M, user_id: &str, limit: i64, skip: u64)
+    -> Result<Vec<Conversation>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_SUPPRIME: false,
+        CHAMP_THREAD_MESSAGE: {"$exists": true, "$ne": null},
+    };
+    let mut filtre_incoming = filtre.clone();
+    filtre_incoming.insert(CHAMP_QUARANTAINE, doc! {"$ne": true});
+    let pipeline: Vec<Document> = vec![
+        doc! {"$match": filtre_incoming},
+        doc! {"$project": {
+            CHAMP_THREAD_MESSAGE: 1,
+            "estampille": "$message.estampille",
+            "non_lu": {"$cond": [{"$eq": [format!("${}", CHAMP_FLAG_LU), false]}, 1, 0]},
+            "envoye": {"$literal": 0},
+        }},
+        doc! {"$unionWith": {
+            "coll": NOM_COLLECTION_OUTGOING,
+            "pipeline": [
+                {"$match": filtre},
+                {"$project": {
+                    CHAMP_THREAD_MESSAGE: 1,
+                    "estampille": "$message.estampille",
+                    "non_lu": {"$literal": 0},
+                    "envoye": {"$literal": 1},
+                }},
+            ],
+        }},
+        doc! {"$group": {
+            "_id": format!("${}", CHAMP_THREAD_MESSAGE),
+            "derniere_activite": {"$max": "$estampille"},
+            "messages": {"$sum": 1},
+            "non_lus": {"$sum": "$non_lu"},
+            "envoyes": {"$sum": "$envoye"},
+        }},
+        doc! {"$sort": {"derniere_activite": -1, "_id": 1}},
+        doc! {"$skip": (skip as i64)},
+        doc! {"$limit": limit},
+    ];
+    let options = AggregateOptions::builder().build();
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+
+    let mut conversations = Vec::new();
+    let mut curseur = collection.aggregate(pipeline, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let conversation: Conversation = convertir_bson_deserializable(r?)?;
+        conversations.push(conversation);
+    }
+    debug!("charger_conversations Usager {} : {} conversations", user_id, conversations.len());
+
+    Ok(conversations)
+}
+
+#[cfg(test)]
+mod test_conversations {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    #[test]
+    fn test_verifier_thread() {
+        setup("test_verifier_thread");
+        assert!(verifier_thread("zQmThread-1_abc"));
+        assert!(verifier_thread(&"a".repeat(LONGUEUR_MAX_THREAD)));
+        assert!(! verifier_thread(""));
+        assert!(! verifier_thread(&"a".repeat(LONGUEUR_MAX_THREAD + 1)));
+        assert!(! verifier_thread("thread avec espace"));
+        assert!(! verifier_thread("thread\n"));
+        assert!(! verifier_thread("threadé"));
+    }
+}
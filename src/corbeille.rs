@@ -0,0 +1,176 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use log::{debug, info};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono::{Duration, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serializable;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::serde::Deserialize;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use millegrilles_common_rust::verificateur::VerificateurMessage;
+
+use crate::constantes::*;
+use crate::evenements::relacher_fuuids;
+use crate::gestionnaire::GestionnaireMessagerie;
+use crate::message_structs::*;
+
+/// Retention des messages dans la corbeille lorsque la configuration est absente.
+const RETENTION_CORBEILLE_JOURS_DEFAUT: i64 = 30;
+/// Nombre maximal de messages purges a chaque entretien.
+const LIMITE_PURGE_CORBEILLE: i64 = 1000;
+
+#[derive(Clone, Debug, Deserialize)]
+struct MessageIdCorbeille {
+    id: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DocMessageCorbeille {
+    user_id: String,
+    message: MessageIdCorbeille,
+    /// Attachments d'un message recu.
+    fichiers: Option<HashMap<String, bool>>,
+    /// Attachments d'un message envoye.
+    fuuids: Option<Vec<String>>,
+}
+
+impl DocMessageCorbeille {
+    /// Fuuids des attachments du message, recu (fichiers) ou envoye (fuuids).
+    fn extraire_fuuids(&mut self) -> Vec<String> {
+        let mut fuuids = Vec::new();
+        if let Some(fichiers) = self.fichiers.take() {
+            fuuids.extend(fichiers.into_keys());
+        }
+        if let Some(inner) = self.fuuids.take() {
+            fuuids.extend(inner);
+        }
+        fuuids
+    }
+}
+
+pub async fn charger_configuration_corbeille<M>(middleware: &M)
+    -> Result<TransactionConserverConfigurationCorbeille, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_CONFIG_KEY: CONFIG_KEY_CORBEILLE };
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    match collection.find_one(filtre, None).await? {
+        Some(d) => Ok(convertir_bson_deserializable(d)?),
+        None => Ok(TransactionConserverConfigurationCorbeille { retention_jours: None })
+    }
+}
+
+/// Supprime definitivement les messages (recus et envoyes) de la corbeille dont la retention est
+/// expiree. La purge est conservee par transaction. Les fuuids qui ne sont plus references par
+/// aucun message sont ensuite relaches aupres de fichiers/GrosFichiers.
+pub async fn purger_corbeille<M>(gestionnaire: &GestionnaireMessagerie, middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao + VerificateurMessage
+{
+    let configuration = charger_configuration_corbeille(middleware).await?;
+    let retention_jours = configuration.retention_jours.unwrap_or(RETENTION_CORBEILLE_JOURS_DEFAUT);
+    let date_expiration = Utc::now() - Duration::days(retention_jours);
+
+    let mut messages = Vec::new();
+    let mut fuuids_candidats = HashSet::new();
+    for messages_envoyes in [false, true] {
+        let collection = match messages_envoyes {
+            true => middleware.get_collection(NOM_COLLECTION_OUTGOING)?,
+            false => middleware.get_collection(NOM_COLLECTION_INCOMING)?,
+        };
+
+        // Messages supprimes avant l'ajout de la date de suppression, debuter la retention
+        let filtre_sans_date = doc! { CHAMP_SUPPRIME: true, CHAMP_DATE_SUPPRIME: {"$exists": false} };
+        let ops = doc! { "$currentDate": {CHAMP_DATE_SUPPRIME: true, CHAMP_MODIFICATION: true} };
+        collection.update_many(filtre_sans_date, ops, None).await?;
+
+        let filtre = doc! { CHAMP_SUPPRIME: true, CHAMP_DATE_SUPPRIME: {"$lt": date_expiration} };
+        let options = FindOptions::builder()
+            .projection(doc! {CHAMP_USER_ID: 1, "message.id": 1, CHAMP_FICHIERS: 1, "fuuids": 1})
+            .limit(LIMITE_PURGE_CORBEILLE)
+            .build();
+
+        let mut curseur = collection.find(filtre, Some(options)).await?;
+        while let Some(r) = curseur.next().await {
+            let mut message: DocMessageCorbeille = convertir_bson_deserializable(r?)?;
+            fuuids_candidats.extend(message.extraire_fuuids());
+            messages.push(MessagePurge { user_id: message.user_id, message_id: message.message.id, messages_envoyes });
+        }
+    }
+
+    if messages.is_empty() {
+        debug!("purger_corbeille Aucun message a purger");
+        return Ok(())
+    }
+
+    let nombre_messages = messages.len();
+    let transaction = TransactionPurgerMessages { messages };
+    sauvegarder_traiter_transaction_serializable(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, TRANSACTION_PURGER_MESSAGES).await?;
+
+    let fuuids_relaches = trouver_fuuids_non_references(middleware, fuuids_candidats).await?;
+    info!("purger_corbeille {} messages purges, {} fuuids relaches", nombre_messages, fuuids_relaches.len());
+    relacher_fuuids(middleware, fuuids_relaches).await?;
+
+    Ok(())
+}
+
+/// Retourne les fuuids qui ne sont plus references par un message recu ou envoye.
+async fn trouver_fuuids_non_references<M>(middleware: &M, fuuids: HashSet<String>)
+    -> Result<Vec<String>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let collection_incoming = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    let collection_outgoing = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+
+    let mut fuuids_non_references = Vec::new();
+    for fuuid in fuuids {
+        let filtre_incoming = doc! { format!("{}.{}", CHAMP_FICHIERS, fuuid): {"$exists": true} };
+        if collection_incoming.count_documents(filtre_incoming, None).await? > 0 {
+            continue
+        }
+        let filtre_outgoing = doc! { "fuuids": &fuuid };
+        if collection_outgoing.count_documents(filtre_outgoing, None).await? > 0 {
+            continue
+        }
+        debug!("trouver_fuuids_non_references Fuuid {} n'est plus reference", fuuid);
+        fuuids_non_references.push(fuuid);
+    }
+
+    Ok(fuuids_non_references)
+}
+
+#[cfg(test)]
+mod test_corbeille {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    #[test]
+    fn test_fuuids_message_envoye() {
+        setup("test_fuuids_message_envoye");
+        let doc_outgoing = doc! {
+            CHAMP_USER_ID: "zUsager1",
+            "message": {"id": "zMessage1"},
+            "fuuids": ["zFuuid1", "zFuuid2"],
+        };
+        let mut message: DocMessageCorbeille = convertir_bson_deserializable(doc_outgoing).expect("convertir");
+        assert_eq!(vec!["zFuuid1".to_string(), "zFuuid2".to_string()], message.extraire_fuuids());
+    }
+
+    #[test]
+    fn test_fuuids_message_recu() {
+        setup("test_fuuids_message_recu");
+        let doc_incoming = doc! {
+            CHAMP_USER_ID: "zUsager1",
+            "message": {"id": "zMessage1"},
+            CHAMP_FICHIERS: {"zFuuid1": true},
+        };
+        let mut message: DocMessageCorbeille = convertir_bson_deserializable(doc_incoming).expect("convertir");
+        assert_eq!(vec!["zFuuid1".to_string()], message.extraire_fuuids());
+    }
+}
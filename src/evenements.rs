@@ -10,7 +10,7 @@ use millegrilles_common_rust::constantes::Securite;
 use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
-use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, Hint, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, Hint, ReturnDocument};
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
 use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::constantes::*;
@@ -22,7 +22,8 @@ use millegrilles_common_rust::verificateur::VerificateurMessage;
 use crate::constantes::*;
 use crate::message_structs::*;
 use crate::gestionnaire::GestionnaireMessagerie;
-use crate::pompe_messages::{evenement_pompe_poste, verifier_fin_transferts_attachments};
+use crate::pompe_messages::{evenement_pompe_poste, evenement_presence_postmaster, verifier_fin_transferts_attachments};
+use crate::usage_boites::{DeltaUsageBoite, maj_usage_boites};
 
 pub async fn consommer_evenement<M>(gestionnaire: &GestionnaireMessagerie, middleware: &M, m: MessageValideAction)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
@@ -36,6 +37,7 @@ pub async fn consommer_evenement<M>(gestionnaire: &GestionnaireMessagerie, middl
         EVENEMENT_POMPE_POSTE => Ok(Securite::L4Secure),
         EVENEMENT_FICHIERS_CONSIGNE => Ok(Securite::L2Prive),
         EVENEMENT_CONFIRMER_ETAT_FUUIDS => Ok(Securite::L2Prive),
+        EVENEMENT_PRESENCE_POSTMASTER => Ok(Securite::L1Public),
         _ => Err(format!("gestionnaire.consommer_evenement: Action inconnue : {}", m.action.as_str())),
     }?;
 
@@ -45,6 +47,7 @@ pub async fn consommer_evenement<M>(gestionnaire: &GestionnaireMessagerie, middl
             EVENEMENT_POMPE_POSTE => evenement_pompe_poste(gestionnaire, middleware, &m).await,
             EVENEMENT_FICHIERS_CONSIGNE => evenement_fichier_consigne(gestionnaire, middleware, &m).await,
             EVENEMENT_CONFIRMER_ETAT_FUUIDS => evenement_confirmer_etat_fuuids(middleware, m).await,
+            EVENEMENT_PRESENCE_POSTMASTER => evenement_presence_postmaster(gestionnaire, middleware, &m).await,
             _ => Err(format!("gestionnaire.consommer_transaction: Mauvais type d'action pour un evenement 1.public : {}", m.action))?,
         }
     } else {
@@ -187,21 +190,39 @@ pub async fn evenement_fichier_consigne<M>(gestionnaire: &GestionnaireMessagerie
         CHAMP_FICHIERS_COMPLETES: false,
         format!("fichiers.{}", message.hachage_bytes): false,
     };
+    let mut set_ops = doc!{
+        format!("fichiers.{}", message.hachage_bytes): true,
+    };
+    if let Some(taille) = message.taille {
+        // Taille du fichier pour l'usage des boites
+        set_ops.insert(format!("{}.{}", CHAMP_TAILLES_FICHIERS, message.hachage_bytes), taille);
+    }
     let ops = doc!{
-        "$set": {
-            format!("fichiers.{}", message.hachage_bytes): true,
-        },
+        "$set": set_ops,
         "$currentDate": {CHAMP_MODIFICATION: true},
     };
-    let options = UpdateOptions::builder()
+    let options = FindOneAndUpdateOptions::builder()
         .hint(Hint::Name(String::from("fichiers_fuuid")))
+        .projection(doc!{CHAMP_USER_ID: 1, CHAMP_SUPPRIME: 1})
         .build();
     let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
-    let resultat = collection.update_many(filtre, ops, Some(options)).await?;
-    debug!("evenement_fichier_consigne Resultat maj {:?}", resultat);
 
-    if resultat.modified_count > 0 {
-        debug!("evenement_fichier_consigne Verifier si fichiers de {} messages sont completes", resultat.modified_count);
+    // Marquer les messages un a la fois pour ajouter la taille du fichier a l'usage de chaque boite
+    let mut modifies = 0;
+    let mut deltas_usage: HashMap<String, DeltaUsageBoite> = HashMap::new();
+    while let Some(d) = collection.find_one_and_update(filtre.clone(), ops.clone(), Some(options.clone())).await? {
+        modifies += 1;
+        let user_id = d.get_str(CHAMP_USER_ID)?;
+        let supprime = d.get_bool(CHAMP_SUPPRIME).unwrap_or(false);
+        if let (false, Some(taille)) = (supprime, message.taille) {
+            deltas_usage.entry(user_id.to_owned()).or_default().taille_fuuids += taille;
+        }
+    }
+    debug!("evenement_fichier_consigne {} messages modifies", modifies);
+    maj_usage_boites(middleware, &deltas_usage).await;
+
+    if modifies > 0 {
+        debug!("evenement_fichier_consigne Verifier si fichiers de {} messages sont completes", modifies);
         Ok(verifier_fichiers_incoming_completes(middleware, gestionnaire, message).await?)
     } else {
         Ok(None)
@@ -245,10 +266,8 @@ async fn repondre_fuuids<M>(middleware: &M, evenement_fuuids: &Vec<String>)
     for fuuid in evenement_fuuids {
         vec_fuuids.push(doc!{format!("fichiers.{}", fuuid): true});
     }
-    let filtre = doc! {
-        "$or": vec_fuuids,
-        "supprime": false
-    };
+    // Les messages dans la corbeille conservent leurs fichiers jusqu'a la purge
+    let filtre = doc! { "$or": vec_fuuids };
 
     debug!("repondre_fuuids filtre {:?}", filtre);
 
@@ -257,16 +276,10 @@ async fn repondre_fuuids<M>(middleware: &M, evenement_fuuids: &Vec<String>)
     let mut curseur = collection.find(filtre, opts).await?;
     while let Some(d) = curseur.next().await {
         let record: RowEtatFuuid = convertir_bson_deserializable(d?)?;
-        let attachments_traites = record.fichiers_completes;
-        for (fuuid, traite) in record.fichiers.into_iter() {
+        for fuuid in record.fichiers.into_keys() {
             if fuuids.contains(&fuuid) {
                 fuuids.remove(&fuuid);
-                // Note: on ignore les fichiers supprimes == true, on va laisser la chance a
-                //       un autre module d'en garder possession.
-                let conserver = attachments_traites == false || traite == true;
-                if conserver == false || record.supprime == false {
-                    fichiers_confirmation.push(ConfirmationEtatFuuid { fuuid, supprime: record.supprime });
-                }
+                fichiers_confirmation.push(ConfirmationEtatFuuid { fuuid, supprime: false });
             }
         }
     }
@@ -276,7 +289,30 @@ async fn repondre_fuuids<M>(middleware: &M, evenement_fuuids: &Vec<String>)
         return Ok(());
     }
 
-    debug!("repondre_fuuids Repondre fuuids connus et non supprimes : {:?}", fichiers_confirmation);
+    debug!("repondre_fuuids Repondre fuuids connus : {:?}", fichiers_confirmation);
+    transmettre_etat_fuuids(middleware, fichiers_confirmation).await
+}
+
+/// Relache des fuuids qui ne sont plus references par aucun message (purge de la corbeille).
+pub async fn relacher_fuuids<M>(middleware: &M, fuuids: Vec<String>)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    if fuuids.is_empty() {
+        return Ok(());
+    }
+
+    debug!("relacher_fuuids Relacher fuuids : {:?}", fuuids);
+    let fichiers_confirmation = fuuids.into_iter()
+        .map(|fuuid| ConfirmationEtatFuuid { fuuid, supprime: true })
+        .collect();
+    transmettre_etat_fuuids(middleware, fichiers_confirmation).await
+}
+
+async fn transmettre_etat_fuuids<M>(middleware: &M, fichiers_confirmation: Vec<ConfirmationEtatFuuid>)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
     let confirmation = ReponseConfirmerEtatFuuids { fuuids: fichiers_confirmation };
     let routage = RoutageMessageAction::builder(DOMAINE_FICHIERS_NOM, COMMANDE_ACTIVITE_FUUIDS)
         .exchanges(vec![L2Prive])
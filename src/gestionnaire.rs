@@ -40,23 +40,53 @@ use crate::pompe_messages::{MessagePompe, PompeMessages, traiter_cedule as trait
 use crate::requetes::consommer_requete;
 use crate::transactions::*;
 use crate::attachments::*;
+use crate::anti_rejeu::entretien_anti_rejeu;
+use crate::corbeille::purger_corbeille;
+use crate::usage_boites::entretien_usage_boites;
+use crate::cles_outbox::traiter_cles_outbox;
 
 #[derive(Debug)]
 pub struct GestionnaireMessagerie {
     tx_pompe_messages: Mutex<Option<Sender<MessagePompe>>>,
+    /// Date de la derniere confirmation de presence de postmaster (ping ou evenement).
+    presence_postmaster: Mutex<Option<DateTime<Utc>>>,
 }
 
 impl Clone for GestionnaireMessagerie {
     fn clone(&self) -> Self {
         GestionnaireMessagerie {
-            tx_pompe_messages: Mutex::new(Some(self.get_tx_pompe()))
+            tx_pompe_messages: Mutex::new(Some(self.get_tx_pompe())),
+            presence_postmaster: Mutex::new(self.get_presence_postmaster()),
         }
     }
 }
 
 impl GestionnaireMessagerie {
     pub fn new() -> GestionnaireMessagerie {
-        return GestionnaireMessagerie { tx_pompe_messages: Mutex::new(None) }
+        return GestionnaireMessagerie {
+            tx_pompe_messages: Mutex::new(None),
+            presence_postmaster: Mutex::new(None),
+        }
+    }
+    pub fn get_presence_postmaster(&self) -> Option<DateTime<Utc>> {
+        let guard = self.presence_postmaster.lock().expect("lock presence postmaster");
+        guard.clone()
+    }
+    pub fn set_presence_postmaster(&self, presence: Option<DateTime<Utc>>) {
+        let mut guard = self.presence_postmaster.lock().expect("lock presence postmaster");
+        *guard = presence;
+    }
+    /// Retourne true si la presence de postmaster a ete confirmee recemment.
+    pub fn postmaster_present(&self) -> bool {
+        match self.get_presence_postmaster() {
+            Some(d) => Utc::now() - chrono::Duration::seconds(CONST_DELAI_PRESENCE_POSTMASTER) < d,
+            None => false
+        }
+    }
+    /// Retourne true si la pompe doit etre en hold : la presence de postmaster a deja ete observee
+    /// et elle est expiree. Aucun hold tant que la presence n'a jamais ete observee.
+    pub fn postmaster_hold(&self) -> bool {
+        presence_postmaster_expiree(self.get_presence_postmaster(), &Utc::now())
     }
     pub fn get_tx_pompe(&self) -> Sender<MessagePompe> {
         let guard = self.tx_pompe_messages.lock().expect("lock tx pompe");
@@ -76,12 +106,24 @@ impl TraiterTransaction for GestionnaireMessagerie {
     }
 }
 
+fn presence_postmaster_expiree(presence: Option<DateTime<Utc>>, now: &DateTime<Utc>) -> bool {
+    match presence {
+        Some(d) => *now - chrono::Duration::seconds(CONST_DELAI_PRESENCE_POSTMASTER) >= d,
+        None => false
+    }
+}
+
 #[async_trait]
 impl GestionnaireDomaine for GestionnaireMessagerie {
     fn get_nom_domaine(&self) -> String { String::from(DOMAINE_NOM) }
 
     fn get_collection_transactions(&self) -> Option<String> { Some(String::from(NOM_COLLECTION_TRANSACTIONS)) }
 
+    /// Collections de documents videes avant la regeneration, puis reconstruites a partir des
+    /// transactions. Les regles, filtres et reponses automatiques des profils sont ecrits par
+    /// transaction. Les collections d'etat operationnel non regenere (outbox cles, anti-rejeu,
+    /// transferts, reputation, limites, sante URLs, signalements externes recus par commande)
+    /// ne sont pas listees. Les signalements locaux sont conserves par upsert sur signalement_id.
     fn get_collections_documents(&self) -> Vec<String> { vec![
         String::from(NOM_COLLECTION_INCOMING),
         // String::from(NOM_COLLECTION_OUTGOING),
@@ -91,6 +133,9 @@ impl GestionnaireDomaine for GestionnaireMessagerie {
         String::from(NOM_COLLECTION_PROFILS),
         String::from(NOM_COLLECTION_CONTACTS),
         String::from(NOM_COLLECTION_CONFIGURATION),
+        String::from(NOM_COLLECTION_CLES_EXPEDITEURS),
+        String::from(NOM_COLLECTION_LABELS),
+        String::from(NOM_COLLECTION_USAGE_BOITES),  // Usage maintenu par increments
     ] }
 
     fn get_q_transactions(&self) -> Option<String> { Some(String::from(NOM_Q_TRANSACTIONS)) }
@@ -159,7 +204,7 @@ impl GestionnaireDomaine for GestionnaireMessagerie {
             let mut tx_guard = self.tx_pompe_messages.lock().expect("lock tx guard");
             *tx_guard = Some(pompe.get_tx_pompe());
         }
-        futures.push(spawn(pompe.run(middleware.clone())));
+        futures.push(spawn(pompe.run(middleware.clone(), self)));
 
         Ok(futures)
     }
@@ -182,6 +227,9 @@ pub fn preparer_queues() -> Vec<QueueType> {
         REQUETE_GET_MESSAGES_ATTACHMENTS,
         REQUETE_GET_USAGER_ACCES_ATTACHMENTS,
         REQUETE_GET_CLES_STREAM,
+        REQUETE_GET_USAGE_BOITE,
+        REQUETE_GET_LABELS,
+        REQUETE_GET_CONVERSATIONS,
     ];
     for req in requetes_privees {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L2Prive});
@@ -195,11 +243,24 @@ pub fn preparer_queues() -> Vec<QueueType> {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L1Public});
     }
 
+    let requetes_protegees: Vec<&str> = vec![
+        REQUETE_GET_SOURCES_RECEPTION,
+        REQUETE_GET_REPUTATIONS,
+        REQUETE_GET_SIGNALEMENTS,
+    ];
+    for req in requetes_protegees {
+        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L3Protege});
+    }
+
     let commandes_protegees: Vec<&str> = vec![
         COMMANDE_GENERER_CLEWEBPUSH_NOTIFICATIONS,
 
         TRANSACTION_CONSERVER_CONFIGURATION_NOTIFICATIONS,
         TRANSACTION_SAUVEGARDER_CLEWEBPUSH_NOTIFICATIONS,
+        TRANSACTION_CONSERVER_CONFIGURATION_LIMITES_RECEPTION,
+        TRANSACTION_CONSERVER_CONFIGURATION_QUOTAS,
+        TRANSACTION_CONSERVER_CONFIGURATION_CORBEILLE,
+        TRANSACTION_CONSERVER_POLITIQUE_FEDERATION,
     ];
     for cmd in commandes_protegees {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L3Protege});
@@ -208,16 +269,34 @@ pub fn preparer_queues() -> Vec<QueueType> {
     let commandes_privees: Vec<&str> = vec![
         COMMANDE_CONSERVER_CLES_ATTACHMENTS,
         COMMANDE_RECEVOIR_EXTERNE,
+        COMMANDE_RECEVOIR_SIGNALEMENT_EXTERNE,
 
         TRANSACTION_POSTER,
         TRANSACTION_RECEVOIR,
         TRANSACTION_INITIALISER_PROFIL,
         TRANSACTION_MAJ_CONTACT,
         TRANSACTION_LU,
+        TRANSACTION_ETOILE,
         TRANSACTION_SUPPRIMER_MESSAGES,
+        TRANSACTION_RESTAURER_MESSAGES,
         TRANSACTION_SUPPRIMER_CONTACTS,
         TRANSACTION_SAUVEGARDER_USAGER_CONFIG_NOTIFICATIONS,
         TRANSACTION_SAUVEGARDER_SUBSCRIPTION_WEBPUSH,
+        TRANSACTION_MAJ_FILTRE_EXPEDITEURS,
+        TRANSACTION_MAJ_REPONSE_AUTOMATIQUE,
+        TRANSACTION_MAJ_REGLES_TRANSFERT,
+        TRANSACTION_MAJ_REGLES_MESSAGES,
+        TRANSACTION_MAJ_QUARANTAINE,
+        TRANSACTION_TRAITER_QUARANTAINE,
+        TRANSACTION_TRAITER_QUARANTAINE_MESSAGES,
+        TRANSACTION_SIGNALER_MESSAGE,
+        TRANSACTION_ACCEPTER_CLE_EXPEDITEUR,
+        TRANSACTION_MAJ_LABEL,
+        TRANSACTION_SUPPRIMER_LABEL,
+        TRANSACTION_AJOUTER_LABEL_MESSAGES,
+        TRANSACTION_RETIRER_LABEL_MESSAGES,
+        TRANSACTION_ARCHIVER_MESSAGES,
+        TRANSACTION_DESARCHIVER_MESSAGES,
     ];
     for cmd in commandes_privees {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L2Prive});
@@ -249,6 +328,12 @@ pub fn preparer_queues() -> Vec<QueueType> {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L4Secure});
     }
 
+    // Presence de postmaster (hold de la pompe)
+    rk_volatils.push(ConfigRoutingExchange {
+        routing_key: format!("evenement.{}.{}", DOMAINE_POSTMASTER, EVENEMENT_PRESENCE_POSTMASTER),
+        exchange: Securite::L1Public
+    });
+
     let evenements_fichiers = vec![
         EVENEMENT_FICHIERS_CONSIGNE,
         EVENEMENT_CONFIRMER_ETAT_FUUIDS,
@@ -277,14 +362,35 @@ pub fn preparer_queues() -> Vec<QueueType> {
         TRANSACTION_INITIALISER_PROFIL,
         TRANSACTION_MAJ_CONTACT,
         TRANSACTION_LU,
+        TRANSACTION_ETOILE,
         TRANSACTION_TRANSFERT_COMPLETE,
         TRANSACTION_SUPPRIMER_MESSAGES,
+        TRANSACTION_RESTAURER_MESSAGES,
         TRANSACTION_CONFIRMER_TRANMISSION_MILLEGRILLE,
         TRANSACTION_CONSERVER_CONFIGURATION_NOTIFICATIONS,
         TRANSACTION_SAUVEGARDER_CLEWEBPUSH_NOTIFICATIONS,
         TRANSACTION_SAUVEGARDER_USAGER_CONFIG_NOTIFICATIONS,
         TRANSACTION_SAUVEGARDER_SUBSCRIPTION_WEBPUSH,
         TRANSACTION_RETIRER_SUBSCRIPTION_WEBPUSH,
+        TRANSACTION_MAJ_FILTRE_EXPEDITEURS,
+        TRANSACTION_CONSERVER_CONFIGURATION_LIMITES_RECEPTION,
+        TRANSACTION_CONSERVER_CONFIGURATION_QUOTAS,
+        TRANSACTION_CONSERVER_CONFIGURATION_CORBEILLE,
+        TRANSACTION_CONSERVER_POLITIQUE_FEDERATION,
+        TRANSACTION_MAJ_REPONSE_AUTOMATIQUE,
+        TRANSACTION_MAJ_REGLES_TRANSFERT,
+        TRANSACTION_MAJ_REGLES_MESSAGES,
+        TRANSACTION_MAJ_QUARANTAINE,
+        TRANSACTION_TRAITER_QUARANTAINE,
+        TRANSACTION_TRAITER_QUARANTAINE_MESSAGES,
+        TRANSACTION_SIGNALER_MESSAGE,
+        TRANSACTION_ACCEPTER_CLE_EXPEDITEUR,
+        TRANSACTION_MAJ_LABEL,
+        TRANSACTION_SUPPRIMER_LABEL,
+        TRANSACTION_AJOUTER_LABEL_MESSAGES,
+        TRANSACTION_RETIRER_LABEL_MESSAGES,
+        TRANSACTION_ARCHIVER_MESSAGES,
+        TRANSACTION_DESARCHIVER_MESSAGES,
     ];
     for ts in transactions_secures {
         rk_transactions.push(ConfigRoutingExchange {
@@ -464,6 +570,273 @@ pub async fn preparer_index_mongodb_custom<M>(middleware: &M) -> Result<(), Stri
         Some(options_incoming_attachmentstraites)
     ).await?;
 
+    // Index cle_id pour outbox de cles
+    let options_cles_outbox = IndexOptions {
+        nom_index: Some(String::from("cle_id")),
+        unique: true
+    };
+    let champs_cles_outbox = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_CLE_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_CLES_OUTBOX,
+        champs_cles_outbox,
+        Some(options_cles_outbox)
+    ).await?;
+
+    // Index message_id, confirme pour outbox de cles (blocage livraison)
+    let options_cles_outbox_message = IndexOptions {
+        nom_index: Some(String::from("message_confirme")),
+        unique: false
+    };
+    let champs_cles_outbox_message = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_MESSAGE_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_CONFIRME), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_CLES_OUTBOX,
+        champs_cles_outbox_message,
+        Some(options_cles_outbox_message)
+    ).await?;
+
+    // Index url pour sante des applications messagerie tierces
+    let options_sante_urls = IndexOptions {
+        nom_index: Some(String::from("url")),
+        unique: true
+    };
+    let champs_sante_urls = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_URL), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_SANTE_URLS,
+        champs_sante_urls,
+        Some(options_sante_urls)
+    ).await?;
+
+    // Index type_source, source pour limites de reception
+    let options_sources_reception = IndexOptions {
+        nom_index: Some(String::from("type_source")),
+        unique: true
+    };
+    let champs_sources_reception = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_TYPE_SOURCE), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_SOURCE), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_SOURCES_RECEPTION,
+        champs_sources_reception,
+        Some(options_sources_reception)
+    ).await?;
+
+    // Index user_id pour usage des boites
+    let options_usage_boites = IndexOptions {
+        nom_index: Some(String::from("user_id")),
+        unique: true
+    };
+    let champs_usage_boites = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_USAGE_BOITES,
+        champs_usage_boites,
+        Some(options_usage_boites)
+    ).await?;
+
+    // Index user_id, adresse pour les reponses automatiques (une reponse par expediteur)
+    let options_reponses_automatiques = IndexOptions {
+        nom_index: Some(String::from("user_adresse")),
+        unique: true
+    };
+    let champs_reponses_automatiques = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_ADRESSE), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_REPONSES_AUTOMATIQUES,
+        champs_reponses_automatiques,
+        Some(options_reponses_automatiques)
+    ).await?;
+
+    // Index user_id, index_adresses pour la quarantaine des expediteurs inconnus
+    let options_contacts_index_adresses = IndexOptions {
+        nom_index: Some(String::from("user_index_adresses")),
+        unique: false
+    };
+    let champs_contacts_index_adresses = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_INDEX_ADRESSES), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_CONTACTS,
+        champs_contacts_index_adresses,
+        Some(options_contacts_index_adresses)
+    ).await?;
+
+    // Index idmg pour la reputation des millegrilles tierces
+    let options_reputation = IndexOptions {
+        nom_index: Some(String::from("idmg")),
+        unique: true
+    };
+    let champs_reputation = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_REPUTATION,
+        champs_reputation,
+        Some(options_reputation)
+    ).await?;
+
+    // Anti-rejeu des messages recus de millegrilles tierces
+    let options_anti_rejeu = IndexOptions {
+        nom_index: Some(String::from("message_destinataire")),
+        unique: true
+    };
+    let champs_anti_rejeu = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_MESSAGE_ID), direction: 1},
+        ChampIndex {nom_champ: String::from("destinataire"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_ANTI_REJEU,
+        champs_anti_rejeu,
+        Some(options_anti_rejeu)
+    ).await?;
+
+    // Transferts par usager (detection de boucle des regles de transfert)
+    let options_transferts = IndexOptions {
+        nom_index: Some(String::from("user_cle_message")),
+        unique: true
+    };
+    let champs_transferts = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_CLE_MESSAGE), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_TRANSFERTS,
+        champs_transferts,
+        Some(options_transferts)
+    ).await?;
+
+    // Cles epinglees des expediteurs par usager
+    let options_cles_expediteurs = IndexOptions {
+        nom_index: Some(String::from("user_index_expediteur")),
+        unique: true
+    };
+    let champs_cles_expediteurs = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_INDEX_EXPEDITEUR), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_CLES_EXPEDITEURS,
+        champs_cles_expediteurs,
+        Some(options_cles_expediteurs)
+    ).await?;
+
+    // Purge de la corbeille
+    let options_corbeille = IndexOptions {
+        nom_index: Some(String::from("supprime_date")),
+        unique: false
+    };
+    let champs_corbeille = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_SUPPRIME), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_DATE_SUPPRIME), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_INCOMING,
+        champs_corbeille,
+        Some(options_corbeille)
+    ).await?;
+
+    // Conversations (thread) des messages recus et envoyes
+    for nom_collection in [NOM_COLLECTION_INCOMING, NOM_COLLECTION_OUTGOING] {
+        let options_thread = IndexOptions {
+            nom_index: Some(String::from("user_thread")),
+            unique: false
+        };
+        let champs_thread = vec!(
+            ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+            ChampIndex {nom_champ: String::from(CHAMP_THREAD_MESSAGE), direction: 1},
+        );
+        middleware.create_index(
+            middleware,
+            nom_collection,
+            champs_thread,
+            Some(options_thread)
+        ).await?;
+    }
+
+    // Labels de l'usager
+    let options_labels = IndexOptions {
+        nom_index: Some(String::from("user_label")),
+        unique: true
+    };
+    let champs_labels = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_LABEL_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_LABELS,
+        champs_labels,
+        Some(options_labels)
+    ).await?;
+
+    // Certificats des messages recus et emis
+    let options_certificats = IndexOptions {
+        nom_index: Some(String::from("fingerprint")),
+        unique: true
+    };
+    let champs_certificats = vec!(
+        ChampIndex {nom_champ: String::from("fingerprint"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_CERTIFICATS,
+        champs_certificats,
+        Some(options_certificats)
+    ).await?;
+
+    // Signalements (locaux et recus de millegrilles tierces)
+    let options_signalements = IndexOptions {
+        nom_index: Some(String::from(CHAMP_SIGNALEMENT_ID)),
+        unique: true
+    };
+    let champs_signalements = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_SIGNALEMENT_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_SIGNALEMENTS,
+        champs_signalements,
+        Some(options_signalements)
+    ).await?;
+
+    let options_signalements_date = IndexOptions {
+        nom_index: Some(String::from("type_date")),
+        unique: false
+    };
+    let champs_signalements_date = vec!(
+        ChampIndex {nom_champ: String::from("type_signalement"), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_DATE_SIGNALEMENT), direction: -1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_SIGNALEMENTS,
+        champs_signalements_date,
+        Some(options_signalements_date)
+    ).await?;
+
     Ok(())
 }
 
@@ -487,12 +860,18 @@ pub async fn traiter_cedule<M>(gestionnaire: &GestionnaireMessagerie, middleware
 
     let date_epoch = trigger.get_date();
     let minutes = date_epoch.get_datetime().minute();
+    let heure = date_epoch.get_datetime().hour();
 
     // Relai message vers pompe
     if let Err(e) = traiter_cedule_pompe(middleware, trigger).await {
         error!("gestionnaire.traiter_cedule Erreur cedule pompe: {:?}", e);
     }
 
+    // Soumettre les cles en attente (outbox) a MaitreDesCles
+    if let Err(e) = traiter_cles_outbox(middleware).await {
+        error!("gestionnaire.traiter_cedule Erreur traiter_cles_outbox: {:?}", e);
+    }
+
     // Executer a toutes les 5 minutes
     if minutes % 5 == 3 {
         // Entretien des attachments de messages
@@ -501,6 +880,30 @@ pub async fn traiter_cedule<M>(gestionnaire: &GestionnaireMessagerie, middleware
         }
     }
 
+    // Executer a toutes les heures
+    if minutes == 17 {
+        // Retirer les messages hors de la fenetre anti-rejeu
+        if let Err(e) = entretien_anti_rejeu(middleware).await {
+            error!("gestionnaire.traiter_cedule Erreur entretien_anti_rejeu: {:?}", e);
+        }
+    }
+
+    // Executer a toutes les heures
+    if minutes == 47 {
+        // Supprimer definitivement les messages expires de la corbeille
+        if let Err(e) = purger_corbeille(gestionnaire, middleware).await {
+            error!("gestionnaire.traiter_cedule Erreur purger_corbeille: {:?}", e);
+        }
+    }
+
+    // Executer une fois par jour
+    if heure == 5 && minutes == 27 {
+        // Recalculer l'usage des boites (corrige les ecarts des increments)
+        if let Err(e) = entretien_usage_boites(middleware).await {
+            error!("gestionnaire.traiter_cedule Erreur entretien_usage_boites: {:?}", e);
+        }
+    }
+
     Ok(())
 }
 
@@ -557,3 +960,21 @@ pub async fn traiter_cedule<M>(gestionnaire: &GestionnaireMessagerie, middleware
 //
 // }
 
+
+#[cfg(test)]
+mod test_gestionnaire {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    #[test]
+    fn test_presence_postmaster_expiree() {
+        setup("test_presence_postmaster_expiree");
+        let now = Utc::now();
+        // Presence jamais observee : aucun hold
+        assert!(! presence_postmaster_expiree(None, &now));
+        assert!(! presence_postmaster_expiree(Some(now - chrono::Duration::seconds(10)), &now));
+        assert!(presence_postmaster_expiree(
+            Some(now - chrono::Duration::seconds(CONST_DELAI_PRESENCE_POSTMASTER + 1)), &now));
+    }
+}
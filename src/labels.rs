@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use log::debug;
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{AggregateOptions, FindOptions};
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+
+/// Label avec le nombre de messages associes.
+#[derive(Clone, Debug, Serialize)]
+pub struct LabelCompte {
+    #[serde(flatten)]
+    pub label: DocLabel,
+    /// Messages recus (non supprimes) avec le label.
+    pub total: i64,
+    pub non_lus: i64,
+    /// Messages envoyes (non supprimes) avec le label.
+    pub envoyes: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct CompteLabel {
+    #[serde(rename="_id")]
+    label_id: String,
+    total: i64,
+    non_lus: i64,
+}
+
+/// Retourne true si le label existe pour l'usager.
+pub async fn verifier_label<M>(middleware: &M, user_id: &str, label_id: &str) -> Result<bool, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_LABEL_ID: label_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_LABELS)?;
+    Ok(collection.count_documents(filtre, None).await? > 0)
+}
+
+/// Charge les labels de l'usager (selon l'ordre) avec les compteurs de messages.
+pub async fn charger_labels<M>(middleware: &M, user_id: &str) -> Result<Vec<LabelCompte>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let options = FindOptions::builder()
+        .sort(doc! {"ordre": 1, CHAMP_NOM: 1})
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_LABELS)?;
+    let mut labels = Vec::new();
+    let mut curseur = collection.find(doc! {CHAMP_USER_ID: user_id}, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let label: DocLabel = convertir_bson_deserializable(r?)?;
+        labels.push(label);
+    }
+
+    let comptes_incoming = compter_labels(middleware, NOM_COLLECTION_INCOMING, user_id).await?;
+    let comptes_outgoing = compter_labels(middleware, NOM_COLLECTION_OUTGOING, user_id).await?;
+
+    let labels = labels.into_iter()
+        .map(|label| {
+            let (total, non_lus) = match comptes_incoming.get(&label.label_id) {
+                Some(c) => (c.total, c.non_lus),
+                None => (0, 0)
+            };
+            let envoyes = match comptes_outgoing.get(&label.label_id) {
+                Some(c) => c.total,
+                None => 0
+            };
+            LabelCompte { label, total, non_lus, envoyes }
+        })
+        .collect();
+
+    Ok(labels)
+}
+
+async fn compter_labels<M>(middleware: &M, nom_collection: &str, user_id: &str)
+    -> Result<HashMap<String, CompteLabel>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let pipeline: Vec<Document> = vec![
+        doc! {"$match": {
+            CHAMP_USER_ID: user_id,
+            CHAMP_SUPPRIME: false,
+            format!("{}.0", CHAMP_LABELS): {"$exists": true},
+        }},
+        doc! {"$unwind": {"path": format!("${}", CHAMP_LABELS)}},
+        doc! {"$group": {
+            "_id": format!("${}", CHAMP_LABELS),
+            "total": {"$sum": 1},
+            "non_lus": {"$sum": {"$cond": [{"$eq": [format!("${}", CHAMP_FLAG_LU), false]}, 1, 0]}},
+        }},
+    ];
+    let options = AggregateOptions::builder().build();
+    let collection = middleware.get_collection(nom_collection)?;
+
+    let mut comptes = HashMap::new();
+    let mut curseur = collection.aggregate(pipeline, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let compte: CompteLabel = convertir_bson_deserializable(r?)?;
+        comptes.insert(compte.label_id.clone(), compte);
+    }
+    debug!("compter_labels {} usager {} : {} labels", nom_collection, user_id, comptes.len());
+
+    Ok(comptes)
+}
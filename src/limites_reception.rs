@@ -0,0 +1,264 @@
+use std::error::Error;
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::bson::serde_helpers::deserialize_chrono_datetime_from_bson_datetime;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+
+pub const TYPE_SOURCE_IDMG: &str = "idmg";
+pub const TYPE_SOURCE_CERTIFICAT: &str = "certificat";
+
+/// Limites par defaut lorsque la configuration est absente de Messagerie/configuration.
+const LIMITE_IDMG_CAPACITE: f64 = 600.0;
+const LIMITE_IDMG_TAUX_MINUTE: f64 = 60.0;
+const LIMITE_CERTIFICAT_CAPACITE: f64 = 120.0;
+const LIMITE_CERTIFICAT_TAUX_MINUTE: f64 = 20.0;
+
+/// Delai retry-after (secondes) lorsque le taux de remplissage est nul.
+const DELAI_RETRY_AFTER_MAX: i64 = 3600;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DocSourceReception {
+    type_source: String,
+    source: String,
+    jetons: f64,
+    #[serde(deserialize_with="deserialize_chrono_datetime_from_bson_datetime")]
+    derniere_maj: DateTime<Utc>,
+}
+
+/// Charge les limites de reception configurees. Les valeurs par defaut sont utilisees pour
+/// les limites absentes.
+pub async fn charger_configuration_limites<M>(middleware: &M)
+    -> Result<TransactionConserverConfigurationLimitesReception, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_CONFIG_KEY: CONFIG_KEY_LIMITES_RECEPTION };
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    let mut configuration: TransactionConserverConfigurationLimitesReception = match collection.find_one(filtre, None).await? {
+        Some(d) => convertir_bson_deserializable(d)?,
+        None => TransactionConserverConfigurationLimitesReception { idmg: None, certificat: None }
+    };
+
+    if configuration.idmg.is_none() {
+        configuration.idmg = Some(LimiteTokenBucket { capacite: LIMITE_IDMG_CAPACITE, taux_minute: LIMITE_IDMG_TAUX_MINUTE });
+    }
+    if configuration.certificat.is_none() {
+        configuration.certificat = Some(LimiteTokenBucket { capacite: LIMITE_CERTIFICAT_CAPACITE, taux_minute: LIMITE_CERTIFICAT_TAUX_MINUTE });
+    }
+
+    Ok(configuration)
+}
+
+/// Verifie les limites de reception pour l'idmg source et le certificat de l'expediteur.
+/// Les deux buckets sont verifies avant de consommer un jeton : un message refuse pour le
+/// certificat ne consomme pas de jeton de l'idmg. Retourne Some(retry_after secondes) si
+/// une limite est atteinte.
+pub async fn verifier_limites_reception<M>(middleware: &M, idmg: &str, fingerprint_certificat: Option<&str>)
+    -> Result<Option<i64>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let configuration = charger_configuration_limites(middleware).await?;
+
+    let mut buckets = Vec::new();
+    if let Some(limite) = configuration.idmg.as_ref() {
+        buckets.push((TYPE_SOURCE_IDMG, idmg, limite));
+    }
+    if let (Some(fingerprint), Some(limite)) = (fingerprint_certificat, configuration.certificat.as_ref()) {
+        buckets.push((TYPE_SOURCE_CERTIFICAT, fingerprint, limite));
+    }
+
+    // Verification prealable sans consommer
+    for (type_source, source, limite) in &buckets {
+        if let Some(retry_after) = verifier_jetons(middleware, type_source, source, limite).await? {
+            warn!("verifier_limites_reception Limite atteinte pour {} {} (idmg {}), retry_after {}s", type_source, source, idmg, retry_after);
+            return Ok(Some(retry_after))
+        }
+    }
+
+    // Consommer atomiquement. Si un bucket refuse (concurrence), rembourser les jetons deja consommes.
+    let mut consommes = Vec::new();
+    for (type_source, source, limite) in &buckets {
+        if let Some(retry_after) = consommer_jeton(middleware, type_source, source, limite).await? {
+            for (type_source, source) in consommes {
+                rembourser_jeton(middleware, type_source, source).await?;
+            }
+            warn!("verifier_limites_reception Limite atteinte pour {} {} (idmg {}), retry_after {}s", type_source, source, idmg, retry_after);
+            return Ok(Some(retry_after))
+        }
+        consommes.push((*type_source, *source));
+    }
+
+    Ok(None)
+}
+
+/// Jetons disponibles apres remplissage pour le temps ecoule (secondes).
+fn remplir_jetons(jetons: f64, ecoule: f64, limite: &LimiteTokenBucket) -> f64 {
+    let taux_seconde = limite.taux_minute / 60.0;
+    f64::min(limite.capacite, jetons + f64::max(ecoule, 0.0) * taux_seconde)
+}
+
+/// Delai (secondes) avant qu'un jeton soit disponible.
+fn calculer_retry_after(jetons: f64, limite: &LimiteTokenBucket) -> i64 {
+    let taux_seconde = limite.taux_minute / 60.0;
+    match taux_seconde > 0.0 {
+        true => i64::max(((1.0 - jetons) / taux_seconde).ceil() as i64, 1),
+        false => DELAI_RETRY_AFTER_MAX
+    }
+}
+
+/// Verifie qu'un jeton est disponible sans le consommer. Un refus est comptabilise.
+async fn verifier_jetons<M>(middleware: &M, type_source: &str, source: &str, limite: &LimiteTokenBucket)
+    -> Result<Option<i64>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_TYPE_SOURCE: type_source, CHAMP_SOURCE: source };
+    let collection = middleware.get_collection(NOM_COLLECTION_SOURCES_RECEPTION)?;
+    let jetons = match collection.find_one(filtre.clone(), None).await? {
+        Some(d) => {
+            let doc_source: DocSourceReception = convertir_bson_deserializable(d)?;
+            let ecoule = (Utc::now() - doc_source.derniere_maj).num_milliseconds() as f64 / 1000.0;
+            remplir_jetons(doc_source.jetons, ecoule, limite)
+        },
+        None => limite.capacite
+    };
+
+    if jetons >= 1.0 {
+        return Ok(None)
+    }
+
+    let ops = doc! {
+        "$inc": {"refuses": 1, "total": 1},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    collection.update_one(filtre, ops, None).await?;
+
+    Ok(Some(calculer_retry_after(jetons, limite)))
+}
+
+/// Token bucket. Le remplissage (selon le temps ecoule depuis la derniere maj) et la consommation
+/// sont faits par un seul update conditionnel (pipeline) pour eviter les courses entre receptions.
+async fn consommer_jeton<M>(middleware: &M, type_source: &str, source: &str, limite: &LimiteTokenBucket)
+    -> Result<Option<i64>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let now = Utc::now();
+    let filtre = doc! { CHAMP_TYPE_SOURCE: type_source, CHAMP_SOURCE: source };
+    let collection = middleware.get_collection(NOM_COLLECTION_SOURCES_RECEPTION)?;
+
+    let taux_seconde = limite.taux_minute / 60.0;
+    let ecoule = doc! {"$max": [0, {"$divide": [{"$subtract": [now, {"$ifNull": ["$derniere_maj", now]}]}, 1000]}]};
+    let remplis = doc! {"$min": [
+        limite.capacite,
+        {"$add": [{"$ifNull": ["$jetons", limite.capacite]}, {"$multiply": [ecoule, taux_seconde]}]}
+    ]};
+    let pipeline = vec![
+        doc! {"$set": {
+            "jetons": remplis,
+            "derniere_maj": now,
+            CHAMP_CREATION: {"$ifNull": [format!("${}", CHAMP_CREATION), now]},
+            CHAMP_MODIFICATION: now,
+        }},
+        doc! {"$set": {"accepte": {"$gte": ["$jetons", 1]}}},
+        doc! {"$set": {
+            "jetons": {"$cond": ["$accepte", {"$subtract": ["$jetons", 1]}, "$jetons"]},
+            "acceptes": {"$add": [{"$ifNull": ["$acceptes", 0]}, {"$cond": ["$accepte", 1, 0]}]},
+            "refuses": {"$add": [{"$ifNull": ["$refuses", 0]}, {"$cond": ["$accepte", 0, 1]}]},
+            "total": {"$add": [{"$ifNull": ["$total", 0]}, 1]},
+        }},
+    ];
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let resultat = match collection.find_one_and_update(filtre, pipeline, Some(options)).await? {
+        Some(d) => d,
+        None => Err(format!("limites_reception.consommer_jeton Source {} {} non sauvegardee", type_source, source))?
+    };
+
+    let jetons = resultat.get_f64("jetons").unwrap_or(0.0);
+    let retry_after = match resultat.get_bool("accepte").unwrap_or(false) {
+        true => None,
+        false => Some(calculer_retry_after(jetons, limite))
+    };
+    debug!("consommer_jeton Source {} {} : jetons {}, retry_after {:?}", type_source, source, jetons, retry_after);
+
+    Ok(retry_after)
+}
+
+/// Remet un jeton consomme pour un message finalement refuse par un autre bucket.
+async fn rembourser_jeton<M>(middleware: &M, type_source: &str, source: &str) -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_TYPE_SOURCE: type_source, CHAMP_SOURCE: source };
+    let ops = doc! {
+        "$inc": {"jetons": 1.0, "acceptes": -1, "refuses": 1},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_SOURCES_RECEPTION)?;
+    collection.update_one(filtre, ops, None).await?;
+    Ok(())
+}
+
+/// Retourne les principales sources de reception (plus grand nombre de messages recus).
+pub async fn charger_sources_reception<M>(middleware: &M, type_source: Option<&str>, limit: i64)
+    -> Result<Vec<Document>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = match type_source {
+        Some(t) => doc! { CHAMP_TYPE_SOURCE: t },
+        None => doc! {}
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {"_id": 0, CHAMP_TYPE_SOURCE: 1, CHAMP_SOURCE: 1, "acceptes": 1, "refuses": 1, "total": 1, CHAMP_MODIFICATION: 1})
+        .sort(doc! {"total": -1})
+        .limit(limit)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_SOURCES_RECEPTION)?;
+
+    let mut sources = Vec::new();
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        sources.push(r?);
+    }
+    info!("charger_sources_reception {} sources chargees", sources.len());
+
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod test_limites_reception {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    fn limite(capacite: f64, taux_minute: f64) -> LimiteTokenBucket {
+        LimiteTokenBucket { capacite, taux_minute }
+    }
+
+    #[test]
+    fn test_remplir_jetons() {
+        setup("test_remplir_jetons");
+        let limite = limite(10.0, 60.0);
+        assert_eq!(5.0, remplir_jetons(0.0, 5.0, &limite));
+        assert_eq!(10.0, remplir_jetons(8.0, 100.0, &limite));
+        // Horloge qui recule, aucun remplissage
+        assert_eq!(5.0, remplir_jetons(5.0, -10.0, &limite));
+    }
+
+    #[test]
+    fn test_calculer_retry_after() {
+        setup("test_calculer_retry_after");
+        let limite_30 = limite(10.0, 30.0);
+        assert_eq!(2, calculer_retry_after(0.0, &limite_30));
+        assert_eq!(1, calculer_retry_after(0.9, &limite_30));
+        assert_eq!(DELAI_RETRY_AFTER_MAX, calculer_retry_after(0.0, &limite(10.0, 0.0)));
+    }
+}
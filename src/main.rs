@@ -9,6 +9,22 @@ mod evenements;
 mod message_structs;
 mod attachments;
 mod communs;
+mod cles_outbox;
+mod limites_reception;
+mod usage_boites;
+mod reponses_automatiques;
+mod regles_transfert;
+mod regles_messages;
+mod quarantaine;
+mod politique_federation;
+mod reputation;
+mod signalements;
+mod anti_rejeu;
+mod certificats_messages;
+mod cles_expediteurs;
+mod labels;
+mod corbeille;
+mod conversations;
 
 use crate::domaines_messagerie::run;
 
@@ -36,6 +36,11 @@ pub struct DocMappingIdmg {
     pub attachments_restants: Option<Vec<String>>,
     pub attachments_completes: Option<Vec<String>>,
     pub attachments_en_cours: Option<HashMap<String, AttachmentEnCours>>,
+    /// Urls de l'application messagerie du idmg, en ordre de failover lors du dernier push.
+    pub urls: Option<Vec<String>>,
+    /// Url utilisee pour la derniere confirmation de transmission.
+    pub url: Option<String>,
+    pub push_time_ms: Option<i64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -63,6 +68,7 @@ pub struct DocDestinataire {
     pub destinataire: String,
     pub user: Option<String>,
     pub dns: Option<String>,
+    pub bcc: Option<bool>,
     pub processed: Option<bool>,
     pub result: Option<i32>,
 }
@@ -105,14 +111,46 @@ impl AdresseMessagerie {
 pub struct CommandePoster {
     pub message: MessageMilleGrille,
     pub destinataires: Vec<String>,
+    /// Destinataires caches. Chaque destinataire bcc recoit une enveloppe de transfert distincte.
+    pub bcc: Option<Vec<String>>,
     pub fuuids: Option<Vec<String>>,
+    /// Adresse de l'expediteur. Doit correspondre au user_id du certificat de l'usager, elle est
+    /// ensuite utilisee par les filtres, regles, quarantaine et cles epinglees des destinataires.
+    pub from: Option<String>,
+    /// Type d'envoi automatise (e.g. reponseAutomatique, liste, notification). None pour un message usager.
+    pub type_envoi: Option<String>,
+    /// Provenance d'un message transfere par une regle de transfert.
+    pub transfert: Option<ProvenanceTransfert>,
+    /// Jeton opaque de conversation, derive par le client (le contenu chiffre n'est pas lisible).
+    pub thread: Option<String>,
+    /// Usager proprietaire d'un message poste par le domaine (e.g. reponse automatique). Ignore
+    /// lorsque le certificat de la transaction porte un user_id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
 }
 
 impl CommandePoster {
 
     /// Retourne la liste combinee de to et bcc.
     pub fn get_destinataires(&self) -> Vec<String> {
-        self.destinataires.clone()
+        let mut destinataires = self.destinataires.clone();
+        if let Some(bcc) = self.bcc.as_ref() {
+            for b in bcc {
+                if ! destinataires.contains(b) {
+                    destinataires.push(b.to_owned());
+                }
+            }
+        }
+        destinataires
+    }
+
+    /// Retourne true si l'adresse est un destinataire cache (bcc).
+    pub fn est_bcc<S>(&self, adresse: S) -> bool where S: AsRef<str> {
+        let adresse = adresse.as_ref();
+        match self.bcc.as_ref() {
+            Some(bcc) => bcc.iter().any(|b| b.as_str() == adresse) && ! self.destinataires.iter().any(|d| d.as_str() == adresse),
+            None => false
+        }
     }
 
 }
@@ -121,10 +159,19 @@ impl CommandePoster {
 pub struct DocumentOutgoing {
     pub message: MessageMilleGrille,
     pub destinataires: HashMap<String, Option<i64>>,
+    pub bcc: Option<Vec<String>>,
     pub fuuids: Option<Vec<String>>,
+    pub from: Option<String>,
+    pub type_envoi: Option<String>,
+    pub transfert: Option<ProvenanceTransfert>,
     pub user_id: String,
     pub supprime: bool,
     pub transfert_complete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etoile: Option<bool>,
+    pub thread: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -163,6 +210,15 @@ pub struct TransactionRecevoir {
 pub struct DestinataireInfo {
     pub adresse: Option<String>,
     pub user_id: Option<String>,
+    /// Code de livraison lorsque le destinataire est rejete (e.g. CODE_DESTINATAIRE_REFUSE).
+    pub code: Option<i32>,
+}
+
+/// Reponse de la transaction recevoir, code de livraison par adresse de destinataire.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReponseTransactionRecevoir {
+    pub ok: Option<bool>,
+    pub usagers: Option<HashMap<String, i32>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -170,6 +226,11 @@ pub struct CommandeRecevoirPost {
     pub message: MessageMilleGrille,
     pub destinataires: Vec<String>,
     pub fuuids: Option<Vec<String>>,
+    /// Adresse de l'expediteur, verifiee a la reception (certificat usager ou idmg tiers).
+    pub from: Option<String>,
+    pub type_envoi: Option<String>,
+    pub transfert: Option<ProvenanceTransfert>,
+    pub thread: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -180,6 +241,13 @@ pub struct DocumentRecevoirPost {
 
     // Section inter-millegrille - fichiers deja presents localement
     pub fichiers: Option<HashMap<String, bool>>,
+
+    pub from: Option<String>,
+    pub type_envoi: Option<String>,
+    pub transfert: Option<ProvenanceTransfert>,
+    /// Quarantaine imposee a la reception (e.g. reputation de la millegrille source).
+    pub quarantaine: Option<bool>,
+    pub thread: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -210,6 +278,22 @@ pub struct RequeteGetMessages {
     pub message_ids: Option<Vec<String>>,
     // pub uuid_transactions: Option<Vec<String>>,
     pub messages_envoyes: Option<bool>,
+    /// true : messages en quarantaine (demandes) seulement.
+    pub quarantaine: Option<bool>,
+    /// Messages avec ce label (label_id) seulement.
+    pub label: Option<String>,
+    /// Messages recus : reception (defaut), archive ou tous.
+    pub boite: Option<String>,
+    /// true : messages avec etoile seulement, false : messages sans etoile.
+    pub etoile: Option<bool>,
+    /// Messages de la conversation (thread) seulement.
+    pub thread: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequeteGetConversations {
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -329,6 +413,33 @@ pub struct DocumentIncoming {
     pub fichiers: Option<HashMap<String, bool>>,
     pub fichiers_completes: bool,
     pub niveau: Option<String>,
+    /// Taille du message (octets), utilisee pour l'usage de la boite.
+    pub taille: Option<i64>,
+    pub transfert: Option<ProvenanceTransfert>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_archive: Option<DateEpochSeconds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etoile: Option<bool>,
+    /// Message d'un expediteur inconnu (hors contacts), en attente d'acceptation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantaine: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_expediteur: Option<String>,
+    /// Message signale (abus) par l'usager.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signale: Option<bool>,
+    /// Message signe par une cle differente de la cle epinglee pour l'expediteur.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cle_changee: Option<bool>,
+    /// Index de l'expediteur dans les cles epinglees (present avec cle_changee).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_cle_expediteur: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -341,10 +452,22 @@ pub struct MessageIncomingClient {
     pub date_ouverture: Option<DateEpochSeconds>,
     pub fichiers: Option<HashMap<String, bool>>,
     pub fichiers_completes: bool,
+    pub transfert: Option<ProvenanceTransfert>,
+    pub labels: Option<Vec<String>>,
+    pub archive: Option<bool>,
+    pub etoile: Option<bool>,
+    pub quarantaine: Option<bool>,
+    pub index_expediteur: Option<String>,
+    pub signale: Option<bool>,
+    pub cle_changee: Option<bool>,
+    pub thread: Option<String>,
     #[serde(rename="certificat_message")]
     pub certificat: Option<Vec<String>>,
     #[serde(rename="millegrille_message")]
     pub millegrille: Option<String>,
+    /// Certificat de l'expediteur introuvable (ni en cache, ni conserve).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificat_indisponible: Option<bool>,
 }
 
 impl From<DocumentIncoming> for MessageIncomingClient {
@@ -358,8 +481,18 @@ impl From<DocumentIncoming> for MessageIncomingClient {
             date_ouverture: value.date_ouverture,
             fichiers: value.fichiers,
             fichiers_completes: value.fichiers_completes,
+            transfert: value.transfert,
+            labels: value.labels,
+            archive: value.archive,
+            etoile: value.etoile,
+            quarantaine: value.quarantaine,
+            index_expediteur: value.index_expediteur,
+            signale: value.signale,
+            cle_changee: value.cle_changee,
+            thread: value.thread,
             certificat: None,
             millegrille: None,
+            certificat_indisponible: None,
         }
     }
 }
@@ -458,6 +591,11 @@ pub struct ProfilReponse {
     pub notifications_actives: Option<bool>,
     pub webpush_subscriptions: Option<HashMap<String, TransactionSauvegarderSubscriptionWebpush>>,
     pub email_inclure_detail: Option<bool>,  // Ajouter detail comme pour webpush dans email (insecure)
+    pub filtre_expediteurs: Option<FiltreExpediteurs>,
+    pub reponse_automatique: Option<ConfigurationReponseAutomatique>,
+    pub regles_transfert: Option<Vec<RegleTransfert>>,
+    pub regles_messages: Option<Vec<RegleMessage>>,
+    pub quarantaine: Option<ConfigurationQuarantaine>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -481,6 +619,9 @@ pub struct Contact {
     pub tag: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub header: Option<String>,
+    /// Index aveugles des adresses du contact (quarantaine des expediteurs inconnus).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_adresses: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -515,6 +656,39 @@ pub struct TransactionTransfertComplete {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TransactionSupprimerMessage {
     pub message_ids: Vec<String>,
+    /// Supprimer des messages envoyes (outgoing) plutot que des messages recus.
+    pub messages_envoyes: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionEtoile {
+    pub message_ids: Vec<String>,
+    pub etoile: bool,
+    pub messages_envoyes: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionRestaurerMessages {
+    pub message_ids: Vec<String>,
+    pub messages_envoyes: Option<bool>,
+}
+
+/// Messages de la corbeille supprimes definitivement (retention expiree).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionPurgerMessages {
+    pub messages: Vec<MessagePurge>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessagePurge {
+    pub user_id: String,
+    pub message_id: String,
+    pub messages_envoyes: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionArchiverMessages {
+    pub message_ids: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -560,6 +734,33 @@ pub struct ReponseFichesApplications {
 //     pub destinataires: Option<Vec<ConfirmerDestinataire>>,
 // }
 
+/// Champs optionnels de la confirmation de transmission du postmaster (url utilisee).
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConfirmationTransmissionUrl {
+    pub url: Option<String>,
+}
+
+/// Etat de sante d'un url d'application messagerie tierce.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocSanteUrl {
+    pub url: String,
+    pub idmg: Option<String>,
+    pub succes: Option<i64>,
+    pub echecs: Option<i64>,
+    pub echecs_consecutifs: Option<i64>,
+    pub latence_ms: Option<i64>,
+    pub latence_totale_ms: Option<i64>,
+}
+
+impl DocSanteUrl {
+    pub fn latence_moyenne_ms(&self) -> Option<i64> {
+        match self.succes {
+            Some(succes) if succes > 0 => Some(self.latence_totale_ms.unwrap_or(0) / succes),
+            _ => None
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConfirmerDestinataire {
     pub code: i32,
@@ -627,6 +828,7 @@ pub struct ReponseVerifierExistanceFuuidsMessage {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EvenementFichiersConsigne {
     pub hachage_bytes: String,
+    pub taille: Option<i64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -756,6 +958,8 @@ pub struct UsagerNotificationsOutgoing {
     #[serde(deserialize_with="deserialize_chrono_datetime_from_bson_datetime")]
     pub expiration_lock_notifications: DateTime<Utc>,
     pub message_id_notifications: Option<Vec<String>>,
+    /// Quota souple de la boite depasse depuis la derniere notification.
+    pub avertissement_quota: Option<bool>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -872,6 +1076,10 @@ pub struct CommandeTransfertPoster {
     pub to: Vec<String>,
     pub files: Option<Vec<String>>,
     pub message_key: String,
+    pub from: Option<String>,
+    pub type_envoi: Option<String>,
+    pub transfert: Option<ProvenanceTransfert>,
+    pub thread: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -884,3 +1092,660 @@ pub struct TransactionFichiersCompletes {
 pub struct ReponsePresenceFichiers {
     pub fuuids: HashMap<String, bool>,
 }
+
+/// Filtre des expediteurs d'un usager, conserve dans le profil. Le filtre est applique a la
+/// reception sans dechiffrer le message (adresse declaree et idmg de la millegrille d'origine).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FiltreExpediteurs {
+    pub adresses_bloquees: Option<Vec<String>>,
+    pub idmgs_bloques: Option<Vec<String>>,
+    /// Si true, seuls les expediteurs autorises sont acceptes.
+    pub autoriser_seulement: Option<bool>,
+    pub adresses_autorisees: Option<Vec<String>>,
+    pub idmgs_autorises: Option<Vec<String>>,
+    /// Fingerprints de certificats expediteurs.
+    pub certificats_bloques: Option<Vec<String>>,
+    pub certificats_autorises: Option<Vec<String>>,
+}
+
+impl FiltreExpediteurs {
+
+    /// Retourne true si l'expediteur est accepte par le filtre. Le certificat (fingerprint) et
+    /// l'idmg proviennent du message signe, l'adresse doit etre verifiee par l'appelant.
+    pub fn accepter(&self, adresse: Option<&str>, fingerprint: &str, idmg: &str) -> bool {
+        let contient = |liste: &Option<Vec<String>>, valeur: &str| {
+            match liste.as_ref() {
+                Some(l) => l.iter().any(|v| v.as_str() == valeur),
+                None => false
+            }
+        };
+
+        if contient(&self.idmgs_bloques, idmg) || contient(&self.certificats_bloques, fingerprint) {
+            return false
+        }
+        if let Some(a) = adresse {
+            if contient(&self.adresses_bloquees, a) {
+                return false
+            }
+        }
+
+        match self.autoriser_seulement {
+            Some(true) => {
+                if contient(&self.idmgs_autorises, idmg) || contient(&self.certificats_autorises, fingerprint) {
+                    return true
+                }
+                match adresse {
+                    Some(a) => contient(&self.adresses_autorisees, a),
+                    None => false
+                }
+            },
+            _ => true
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMajFiltreExpediteurs {
+    pub filtre: FiltreExpediteurs,
+}
+
+/// Limite token bucket : capacite maximale (jetons) et taux de remplissage par minute.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LimiteTokenBucket {
+    pub capacite: f64,
+    pub taux_minute: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionConserverConfigurationLimitesReception {
+    /// Limite par millegrille source (idmg).
+    pub idmg: Option<LimiteTokenBucket>,
+    /// Limite par certificat expediteur (fingerprint).
+    pub certificat: Option<LimiteTokenBucket>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequeteGetSourcesReception {
+    pub limit: Option<i64>,
+    pub type_source: Option<String>,
+}
+
+/// Politique de federation de l'instance. En mode liste autorisee, seules les millegrilles de
+/// idmgs_autorises sont acceptees. Les idmgs_refuses sont toujours refuses.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionConserverPolitiqueFederation {
+    pub liste_autorisee: bool,
+    pub idmgs_autorises: Option<Vec<String>>,
+    pub idmgs_refuses: Option<Vec<String>>,
+    /// Notes de l'operateur par idmg.
+    pub notes: Option<HashMap<String, String>>,
+    /// Les messages des millegrilles dont le score de reputation est sous ce seuil sont places
+    /// en quarantaine.
+    pub seuil_quarantaine_reputation: Option<f64>,
+}
+
+impl TransactionConserverPolitiqueFederation {
+    pub fn autorise(&self, idmg: &str) -> bool {
+        if let Some(refuses) = self.idmgs_refuses.as_ref() {
+            if refuses.iter().any(|i| i.as_str() == idmg) { return false }
+        }
+        if self.liste_autorisee {
+            return match self.idmgs_autorises.as_ref() {
+                Some(autorises) => autorises.iter().any(|i| i.as_str() == idmg),
+                None => false
+            }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionConserverConfigurationQuotas {
+    pub quota_souple_octets: Option<i64>,
+    pub quota_dur_octets: Option<i64>,
+    pub quota_souple_messages: Option<i64>,
+    pub quota_dur_messages: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionConserverConfigurationCorbeille {
+    /// Nombre de jours avant la suppression definitive d'un message de la corbeille.
+    pub retention_jours: Option<i64>,
+}
+
+impl TransactionConserverConfigurationQuotas {
+
+    /// Retourne true si l'usage depasse le quota souple (avertissement).
+    pub fn depasse_souple(&self, usage: &DocUsageBoite) -> bool {
+        Self::depasse(usage, self.quota_souple_octets, self.quota_souple_messages)
+    }
+
+    /// Retourne true si l'usage atteint le quota dur (reception refusee).
+    pub fn depasse_dur(&self, usage: &DocUsageBoite) -> bool {
+        Self::depasse(usage, self.quota_dur_octets, self.quota_dur_messages)
+    }
+
+    fn depasse(usage: &DocUsageBoite, octets: Option<i64>, messages: Option<i64>) -> bool {
+        if let Some(o) = octets {
+            if usage.taille_totale() >= o { return true }
+        }
+        if let Some(m) = messages {
+            if usage.messages >= m { return true }
+        }
+        false
+    }
+}
+
+/// Compteurs de reputation d'une millegrille tierce. Les valeurs decroissent avec le temps, elles
+/// sont conservees ponderees (voir reputation.rs) et converties a la lecture.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CompteursReputation {
+    #[serde(default)]
+    pub recus: f64,
+    #[serde(default)]
+    pub envoyes: f64,
+    #[serde(default)]
+    pub abus: f64,
+    #[serde(default)]
+    pub bloques: f64,
+    #[serde(default)]
+    pub echecs_entrants: f64,
+    #[serde(default)]
+    pub echecs_sortants: f64,
+    #[serde(default)]
+    pub anomalies_certificat: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocReputationMillegrille {
+    pub idmg: String,
+    pub compteurs: CompteursReputation,
+    #[serde(deserialize_with="deserialize_chrono_datetime_from_bson_datetime")]
+    pub derniere_maj: DateTime<Utc>,
+    #[serde(default)]
+    pub score: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequeteGetReputations {
+    pub idmg: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Usage de la boite d'un usager (messages recus non supprimes et fichiers attaches).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocUsageBoite {
+    pub user_id: String,
+    pub messages: i64,
+    pub taille_messages: i64,
+    pub fuuids: i64,
+    pub taille_fuuids: i64,
+    pub avertissement_quota: Option<bool>,
+}
+
+impl DocUsageBoite {
+    pub fn taille_totale(&self) -> i64 {
+        self.taille_messages + self.taille_fuuids
+    }
+}
+
+/// Configuration du repondeur automatique d'un usager (conservee dans le profil).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigurationReponseAutomatique {
+    pub actif: bool,
+    /// Debut et fin de la periode active (epoch secondes).
+    pub debut: Option<i64>,
+    pub fin: Option<i64>,
+    /// Message modele, chiffre avec la cle de reponse. Re-signe par le domaine a chaque reponse.
+    pub message: Option<MessageMilleGrille>,
+    /// Nombre de jours minimum entre deux reponses au meme expediteur.
+    pub intervalle_jours: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMajReponseAutomatique {
+    pub reponse_automatique: ConfigurationReponseAutomatique,
+}
+
+/// Regle de transfert d'un usager. Sans critere (expediteurs, idmgs), tous les messages sont transferes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegleTransfert {
+    pub adresse_cible: String,
+    pub expediteurs: Option<Vec<String>>,
+    pub idmgs: Option<Vec<String>>,
+}
+
+impl RegleTransfert {
+    pub fn correspond(&self, from: Option<&str>, idmg: &str) -> bool {
+        let expediteurs = self.expediteurs.as_ref().filter(|e| ! e.is_empty());
+        let idmgs = self.idmgs.as_ref().filter(|i| ! i.is_empty());
+        if expediteurs.is_none() && idmgs.is_none() {
+            return true
+        }
+        if let (Some(e), Some(f)) = (expediteurs, from) {
+            if e.iter().any(|e| e.as_str() == f) { return true }
+        }
+        if let Some(i) = idmgs {
+            if i.iter().any(|i| i.as_str() == idmg) { return true }
+        }
+        false
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMajReglesTransfert {
+    pub regles: Vec<RegleTransfert>,
+}
+
+/// Provenance d'un message transfere. Conserve l'identite et la signature du message original
+/// ainsi que la chaine des adresses de transfert (detection de boucles).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProvenanceTransfert {
+    pub message_id: String,
+    pub pubkey: String,
+    pub signature: String,
+    pub origine: Option<String>,
+    pub from: Option<String>,
+    pub chaine: Vec<String>,
+}
+
+/// Metadonnees visibles par le serveur lors de la reception d'un message.
+#[derive(Clone, Debug)]
+pub struct MetadonneesReception<'a> {
+    pub from: Option<&'a str>,
+    pub idmg: &'a str,
+    pub certificat: &'a str,
+    pub attachments: bool,
+    pub date_reception: &'a DateTime<Utc>,
+    pub adresse_destination: Option<&'a str>,
+}
+
+/// Conditions d'une regle de message. Toutes les conditions presentes doivent correspondre,
+/// une liste correspond si elle contient la valeur. Sans condition, la regle s'applique a tous
+/// les messages.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConditionsRegleMessage {
+    pub expediteurs: Option<Vec<String>>,
+    pub idmgs: Option<Vec<String>>,
+    pub certificats: Option<Vec<String>>,
+    pub attachments: Option<bool>,
+    /// Heure de reception UTC (0-23). La plage peut traverser minuit (e.g. 22 a 6).
+    pub heure_debut: Option<u32>,
+    pub heure_fin: Option<u32>,
+    pub adresses_destination: Option<Vec<String>>,
+}
+
+fn liste_correspond(liste: &Option<Vec<String>>, valeur: Option<&str>) -> bool {
+    match liste.as_ref().filter(|l| ! l.is_empty()) {
+        Some(l) => match valeur {
+            Some(v) => l.iter().any(|e| e.as_str() == v),
+            None => false
+        },
+        None => true
+    }
+}
+
+impl ConditionsRegleMessage {
+    pub fn correspond(&self, metadonnees: &MetadonneesReception) -> bool {
+        if ! liste_correspond(&self.expediteurs, metadonnees.from) { return false }
+        if ! liste_correspond(&self.idmgs, Some(metadonnees.idmg)) { return false }
+        if ! liste_correspond(&self.certificats, Some(metadonnees.certificat)) { return false }
+        if ! liste_correspond(&self.adresses_destination, metadonnees.adresse_destination) { return false }
+        if let Some(a) = self.attachments {
+            if a != metadonnees.attachments { return false }
+        }
+        if self.heure_debut.is_some() || self.heure_fin.is_some() {
+            let heure = chrono::Timelike::hour(metadonnees.date_reception);
+            let debut = self.heure_debut.unwrap_or(0);
+            let fin = self.heure_fin.unwrap_or(23);
+            let dans_plage = match debut <= fin {
+                true => heure >= debut && heure <= fin,
+                false => heure >= debut || heure <= fin
+            };
+            if ! dans_plage { return false }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ActionsRegleMessage {
+    pub labels: Option<Vec<String>>,
+    pub lu: Option<bool>,
+    pub archiver: Option<bool>,
+    pub etoile: Option<bool>,
+    pub supprimer: Option<bool>,
+    pub sans_notification: Option<bool>,
+}
+
+/// Regle appliquee a la reception. Les regles sont evaluees dans l'ordre, arreter interrompt
+/// l'evaluation des regles suivantes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegleMessage {
+    pub nom: Option<String>,
+    pub actif: Option<bool>,
+    pub conditions: ConditionsRegleMessage,
+    pub actions: ActionsRegleMessage,
+    pub arreter: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMajReglesMessages {
+    pub regles: Vec<RegleMessage>,
+}
+
+/// Quarantaine des expediteurs inconnus. Le sel est fourni par le client lors de l'activation,
+/// il sert a calculer l'index aveugle des adresses (contacts et expediteurs).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigurationQuarantaine {
+    pub actif: bool,
+    pub sel_index: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMajQuarantaine {
+    pub quarantaine: ConfigurationQuarantaine,
+}
+
+/// Accepte ou refuse un expediteur en quarantaine (par index aveugle). Lors de l'acceptation,
+/// le contact (chiffre) est ajoute aux contacts de l'usager.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionTraiterQuarantaine {
+    pub index_expediteur: String,
+    pub accepter: bool,
+    pub contact: Option<Contact>,
+}
+
+/// Accepte ou refuse des messages en quarantaine sans index d'expediteur (e.g. quarantaine par
+/// reputation de la millegrille source).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionTraiterQuarantaineMessages {
+    pub message_ids: Vec<String>,
+    pub accepter: bool,
+}
+
+/// Signalement d'un message recu (spam, abus). Avec transmettre, un signalement signe est
+/// transmis a la millegrille de l'expediteur.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSignalerMessage {
+    pub message_id: String,
+    pub raison: Option<String>,
+    pub transmettre: Option<bool>,
+}
+
+/// Signalement transmis a la millegrille d'origine d'un message. Le message original (signe)
+/// sert de preuve.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignalementAbus {
+    pub message_id: String,
+    pub raison: Option<String>,
+    pub message: MessageMilleGrille,
+}
+
+/// Label (dossier) de l'usager. Le label_id est la valeur conservee dans les labels des messages,
+/// incluant les labels appliques par les regles de messages.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocLabel {
+    pub label_id: String,
+    pub nom: String,
+    pub couleur: Option<String>,
+    pub ordre: Option<i32>,
+}
+
+/// Cree ou met a jour un label. Sans label_id, le id de la transaction est utilise.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMajLabel {
+    pub label_id: Option<String>,
+    pub nom: String,
+    pub couleur: Option<String>,
+    pub ordre: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSupprimerLabel {
+    pub label_id: String,
+}
+
+/// Ajoute ou retire un label sur des messages recus (ou envoyes avec messages_envoyes).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionLabelMessages {
+    pub label_id: String,
+    pub message_ids: Vec<String>,
+    pub messages_envoyes: Option<bool>,
+}
+
+/// Accepte la nouvelle cle de l'expediteur d'un message (cle_changee).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionAccepterCleExpediteur {
+    pub message_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandeRecevoirSignalementExterne {
+    pub signalement: MessageMilleGrille,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocSignalement {
+    pub signalement_id: String,
+    pub type_signalement: String,
+    pub user_id: Option<String>,
+    pub idmg: Option<String>,
+    pub message_id: String,
+    pub raison: Option<String>,
+    pub message: MessageMilleGrille,
+    pub date_signalement: DateEpochSeconds,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequeteGetSignalements {
+    pub idmg: Option<String>,
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+}
+
+#[cfg(test)]
+mod test_message_structs {
+    use millegrilles_common_rust::serde_json;
+
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    const IDMG_1: &str = "zIdmg1";
+    const IDMG_2: &str = "zIdmg2";
+    const FINGERPRINT_1: &str = "zFingerprint1";
+    const ADRESSE_1: &str = "@usager1/millegrille1.com";
+
+    fn liste(valeurs: &[&str]) -> Option<Vec<String>> {
+        Some(valeurs.iter().map(|v| v.to_string()).collect())
+    }
+
+    #[test]
+    fn test_filtre_expediteurs_vide() {
+        setup("test_filtre_expediteurs_vide");
+        let filtre = FiltreExpediteurs::default();
+        assert!(filtre.accepter(Some(ADRESSE_1), FINGERPRINT_1, IDMG_1));
+        assert!(filtre.accepter(None, FINGERPRINT_1, IDMG_1));
+    }
+
+    #[test]
+    fn test_filtre_expediteurs_bloques() {
+        setup("test_filtre_expediteurs_bloques");
+        let filtre = FiltreExpediteurs {
+            idmgs_bloques: liste(&[IDMG_2]),
+            certificats_bloques: liste(&["zFingerprintBloque"]),
+            adresses_bloquees: liste(&[ADRESSE_1]),
+            ..Default::default()
+        };
+        assert!(! filtre.accepter(None, FINGERPRINT_1, IDMG_2));
+        assert!(! filtre.accepter(None, "zFingerprintBloque", IDMG_1));
+        assert!(! filtre.accepter(Some(ADRESSE_1), FINGERPRINT_1, IDMG_1));
+        assert!(filtre.accepter(Some("@autre/millegrille1.com"), FINGERPRINT_1, IDMG_1));
+    }
+
+    #[test]
+    fn test_filtre_expediteurs_autoriser_seulement() {
+        setup("test_filtre_expediteurs_autoriser_seulement");
+        let filtre = FiltreExpediteurs {
+            autoriser_seulement: Some(true),
+            idmgs_autorises: liste(&[IDMG_1]),
+            certificats_autorises: liste(&[FINGERPRINT_1]),
+            adresses_autorisees: liste(&[ADRESSE_1]),
+            ..Default::default()
+        };
+        assert!(filtre.accepter(None, "zAutre", IDMG_1));
+        assert!(filtre.accepter(None, FINGERPRINT_1, IDMG_2));
+        assert!(filtre.accepter(Some(ADRESSE_1), "zAutre", IDMG_2));
+        assert!(! filtre.accepter(Some("@autre/millegrille2.com"), "zAutre", IDMG_2));
+        assert!(! filtre.accepter(None, "zAutre", IDMG_2));
+    }
+
+    #[test]
+    fn test_filtre_expediteurs_bloque_prioritaire() {
+        setup("test_filtre_expediteurs_bloque_prioritaire");
+        let filtre = FiltreExpediteurs {
+            autoriser_seulement: Some(true),
+            idmgs_autorises: liste(&[IDMG_1]),
+            certificats_bloques: liste(&[FINGERPRINT_1]),
+            ..Default::default()
+        };
+        assert!(! filtre.accepter(None, FINGERPRINT_1, IDMG_1));
+    }
+
+    fn metadonnees<'a>(date_reception: &'a DateTime<Utc>) -> MetadonneesReception<'a> {
+        MetadonneesReception {
+            from: Some(ADRESSE_1),
+            idmg: IDMG_1,
+            certificat: FINGERPRINT_1,
+            attachments: false,
+            date_reception,
+            adresse_destination: None,
+        }
+    }
+
+    /// Date de reception le 2023-01-01 a l'heure UTC indiquee.
+    fn date_heure(heure: i64) -> DateTime<Utc> {
+        chrono::TimeZone::timestamp(&Utc, 1672531200 + heure * 3600, 0)
+    }
+
+    #[test]
+    fn test_conditions_regle_sans_condition() {
+        setup("test_conditions_regle_sans_condition");
+        let date = date_heure(12);
+        assert!(ConditionsRegleMessage::default().correspond(&metadonnees(&date)));
+    }
+
+    #[test]
+    fn test_conditions_regle_listes() {
+        setup("test_conditions_regle_listes");
+        let date = date_heure(12);
+        let conditions = ConditionsRegleMessage {
+            expediteurs: liste(&[ADRESSE_1]),
+            idmgs: liste(&[IDMG_1]),
+            ..Default::default()
+        };
+        assert!(conditions.correspond(&metadonnees(&date)));
+
+        let conditions = ConditionsRegleMessage { idmgs: liste(&[IDMG_2]), ..Default::default() };
+        assert!(! conditions.correspond(&metadonnees(&date)));
+
+        // Une condition sur l'adresse ne correspond pas a un message sans from
+        let conditions = ConditionsRegleMessage { expediteurs: liste(&[ADRESSE_1]), ..Default::default() };
+        let mut meta = metadonnees(&date);
+        meta.from = None;
+        assert!(! conditions.correspond(&meta));
+
+        let conditions = ConditionsRegleMessage { attachments: Some(true), ..Default::default() };
+        assert!(! conditions.correspond(&metadonnees(&date)));
+    }
+
+    #[test]
+    fn test_conditions_regle_plage_heures() {
+        setup("test_conditions_regle_plage_heures");
+        let conditions = ConditionsRegleMessage { heure_debut: Some(9), heure_fin: Some(17), ..Default::default() };
+        assert!(conditions.correspond(&metadonnees(&date_heure(9))));
+        assert!(conditions.correspond(&metadonnees(&date_heure(17))));
+        assert!(! conditions.correspond(&metadonnees(&date_heure(18))));
+        assert!(! conditions.correspond(&metadonnees(&date_heure(3))));
+    }
+
+    #[test]
+    fn test_conditions_regle_plage_heures_minuit() {
+        setup("test_conditions_regle_plage_heures_minuit");
+        let conditions = ConditionsRegleMessage { heure_debut: Some(22), heure_fin: Some(6), ..Default::default() };
+        assert!(conditions.correspond(&metadonnees(&date_heure(23))));
+        assert!(conditions.correspond(&metadonnees(&date_heure(0))));
+        assert!(conditions.correspond(&metadonnees(&date_heure(6))));
+        assert!(! conditions.correspond(&metadonnees(&date_heure(7))));
+        assert!(! conditions.correspond(&metadonnees(&date_heure(21))));
+
+        // Heure de fin seulement : depuis minuit
+        let conditions = ConditionsRegleMessage { heure_fin: Some(6), ..Default::default() };
+        assert!(conditions.correspond(&metadonnees(&date_heure(2))));
+        assert!(! conditions.correspond(&metadonnees(&date_heure(8))));
+    }
+
+    fn politique(liste_autorisee: bool, autorises: Option<Vec<String>>, refuses: Option<Vec<String>>)
+        -> TransactionConserverPolitiqueFederation
+    {
+        TransactionConserverPolitiqueFederation {
+            liste_autorisee,
+            idmgs_autorises: autorises,
+            idmgs_refuses: refuses,
+            notes: None,
+            seuil_quarantaine_reputation: None,
+        }
+    }
+
+    #[test]
+    fn test_politique_federation_ouverte() {
+        setup("test_politique_federation_ouverte");
+        let politique = politique(false, None, liste(&[IDMG_2]));
+        assert!(politique.autorise(IDMG_1));
+        assert!(! politique.autorise(IDMG_2));
+    }
+
+    #[test]
+    fn test_politique_federation_liste_autorisee() {
+        setup("test_politique_federation_liste_autorisee");
+        assert!(! politique(true, None, None).autorise(IDMG_1));
+
+        let politique_liste = politique(true, liste(&[IDMG_1]), None);
+        assert!(politique_liste.autorise(IDMG_1));
+        assert!(! politique_liste.autorise(IDMG_2));
+
+        // Un idmg refuse est refuse meme s'il est dans la liste autorisee
+        let politique_refus = politique(true, liste(&[IDMG_1]), liste(&[IDMG_1]));
+        assert!(! politique_refus.autorise(IDMG_1));
+    }
+
+    #[test]
+    fn test_traiter_quarantaine_index_requis() {
+        setup("test_traiter_quarantaine_index_requis");
+        let valeur = serde_json::json!({"index_expediteur": "zIndex1", "accepter": false});
+        let transaction: TransactionTraiterQuarantaine = serde_json::from_value(valeur).expect("transaction");
+        assert_eq!("zIndex1", transaction.index_expediteur.as_str());
+
+        let valeur = serde_json::json!({"message_ids": ["zMessage1"], "accepter": false});
+        assert!(serde_json::from_value::<TransactionTraiterQuarantaine>(valeur).is_err());
+    }
+
+    #[test]
+    fn test_traiter_quarantaine_messages() {
+        setup("test_traiter_quarantaine_messages");
+        let valeur = serde_json::json!({"message_ids": ["zMessage1", "zMessage2"], "accepter": true});
+        let transaction: TransactionTraiterQuarantaineMessages = serde_json::from_value(valeur).expect("transaction");
+        assert_eq!(liste(&["zMessage1", "zMessage2"]), Some(transaction.message_ids));
+    }
+
+    #[test]
+    fn test_conditions_regle_niveaux_ignore() {
+        setup("test_conditions_regle_niveaux_ignore");
+        // Une regle existante avec niveaux est chargee, le champ n'est plus une condition
+        let valeur = serde_json::json!({"idmgs": [IDMG_1], "niveaux": ["info"]});
+        let conditions: ConditionsRegleMessage = serde_json::from_value(valeur).expect("conditions");
+        let date_reception = date_heure(12);
+        assert!(conditions.correspond(&metadonnees(&date_reception)));
+    }
+}
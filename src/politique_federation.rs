@@ -0,0 +1,52 @@
+use std::error::Error;
+
+use log::info;
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::constantes::Securite::L3Protege;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::serde_json::json;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+
+pub const DIRECTION_ENTRANT: &str = "entrant";
+pub const DIRECTION_SORTANT: &str = "sortant";
+
+/// Charge la politique de federation. Sans configuration, toutes les millegrilles sont autorisees.
+pub async fn charger_politique_federation<M>(middleware: &M)
+    -> Result<TransactionConserverPolitiqueFederation, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_CONFIG_KEY: CONFIG_KEY_POLITIQUE_FEDERATION };
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    match collection.find_one(filtre, None).await? {
+        Some(d) => Ok(convertir_bson_deserializable(d)?),
+        None => Ok(TransactionConserverPolitiqueFederation {
+            liste_autorisee: false,
+            idmgs_autorises: None,
+            idmgs_refuses: None,
+            notes: None,
+        })
+    }
+}
+
+/// Evenement d'audit (3.protege) pour une transmission refusee par la politique de federation.
+pub async fn emettre_evenement_federation_refusee<M>(middleware: &M, idmg: &str, direction: &str, message_id: &str)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    info!("emettre_evenement_federation_refusee Message {} {} refuse pour idmg {}", message_id, direction, idmg);
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_FEDERATION_REFUSEE)
+        .exchanges(vec![L3Protege])
+        .build();
+    let evenement = json!({
+        "idmg": idmg,
+        "direction": direction,
+        "message_id": message_id,
+        "code": CODE_FEDERATION_REFUSEE,
+    });
+    middleware.emettre_evenement(routage, &evenement).await?;
+    Ok(())
+}
@@ -9,12 +9,12 @@ use millegrilles_common_rust::mongodb::options::{AggregateOptions, CountOptions,
 use log::{debug, error, info, warn};
 use millegrilles_common_rust::async_trait::async_trait;
 use millegrilles_common_rust::bson::{doc, Document};
-use millegrilles_common_rust::certificats::{EnveloppeCertificat, ValidateurX509};
+use millegrilles_common_rust::certificats::{EnveloppeCertificat, ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::chiffrage::{CleSecrete, rechiffrer_asymetrique_multibase};
 use millegrilles_common_rust::chiffrage_cle::requete_charger_cles;
 use millegrilles_common_rust::chiffrage_ed25519::{chiffrer_asymmetrique_ed25519, dechiffrer_asymmetrique_ed25519};
 use millegrilles_common_rust::chrono::{Duration, Utc};
-use millegrilles_common_rust::constantes::{CHAMP_MODIFICATION, MessageKind, Securite, SECURITE_2_PRIVE};
+use millegrilles_common_rust::constantes::{CHAMP_MODIFICATION, MessageKind, RolesCertificats, Securite, SECURITE_2_PRIVE};
 use millegrilles_common_rust::constantes::Securite::{L1Public, L2Prive};
 use millegrilles_common_rust::formatteur_messages::{FormatteurMessage, MessageInterMillegrille, MessageMilleGrille};
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
@@ -28,7 +28,13 @@ use millegrilles_common_rust::multibase::Base;
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::serde_json::{json, Map, Value};
 use millegrilles_common_rust::tokio_stream::StreamExt;
+use millegrilles_common_rust::verificateur::VerificateurMessage;
 
+use crate::cles_outbox::{cle_message_en_attente, cles_attachments_en_attente};
+use crate::commandes::recevoir_message;
+use crate::regles_transfert::{liberer_transfert_echec, livraison_echouee};
+use crate::politique_federation::{charger_politique_federation, DIRECTION_SORTANT, emettre_evenement_federation_refusee};
+use crate::communs::url_to_mongokey;
 use crate::constantes::*;
 use crate::gestionnaire::GestionnaireMessagerie;
 use crate::message_structs::*;
@@ -76,6 +82,56 @@ pub async fn evenement_pompe_poste<M>(gestionnaire: &GestionnaireMessagerie, mid
     Ok(None)
 }
 
+/// Reception d'un evenement de presence de postmaster. Relance la pompe si elle etait en hold.
+pub async fn evenement_presence_postmaster<M>(gestionnaire: &GestionnaireMessagerie, middleware: &M, m: &MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("pompe_messages.evenement_presence_postmaster Evenement recu {:?}", m);
+    // L'evenement est emis sur L1Public, seul le role du certificat est verifie
+    if ! m.verifier_roles(vec![RolesCertificats::Postmaster]) {
+        warn!("evenement_presence_postmaster Evenement refuse, certificat n'est pas un postmaster : {:?}", m.correlation_id);
+        return Ok(None)
+    }
+    let hold = ! gestionnaire.postmaster_present();
+    gestionnaire.set_presence_postmaster(Some(Utc::now()));
+    if hold {
+        info!("evenement_presence_postmaster Postmaster present, fin du hold de la pompe");
+        emettre_evenement_pompe(middleware, None).await?;
+    }
+    Ok(None)
+}
+
+/// Verifie la presence de postmaster. Si aucune presence recente, fait un ping. Retourne false
+/// (hold) uniquement si la presence deja observee est expiree et que le ping echoue.
+async fn verifier_presence_postmaster<M>(middleware: &M, gestionnaire: &GestionnaireMessagerie) -> bool
+    where M: GenerateurMessages
+{
+    if gestionnaire.postmaster_present() {
+        return true
+    }
+
+    let routage = RoutageMessageAction::builder(DOMAINE_POSTMASTER, REQUETE_POSTMASTER_PING)
+        .exchanges(vec![L1Public])
+        .timeout_blocking(3000)
+        .build();
+    match middleware.transmettre_requete(routage, &json!({})).await {
+        Ok(TypeMessage::Valide(_)) => {
+            debug!("verifier_presence_postmaster Reponse ping postmaster recue");
+            gestionnaire.set_presence_postmaster(Some(Utc::now()));
+            true
+        },
+        Ok(r) => {
+            warn!("verifier_presence_postmaster Reponse ping postmaster invalide : {:?}", r);
+            ! gestionnaire.postmaster_hold()
+        },
+        Err(e) => {
+            debug!("verifier_presence_postmaster Postmaster absent : {:?}", e);
+            ! gestionnaire.postmaster_hold()
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MessagePompe {
     idmgs: Option<Vec<String>>,
@@ -98,14 +154,14 @@ impl PompeMessages {
     }
 
     /// Thread d'execution de la pompe.
-    pub async fn run<M>(mut self, middleware: Arc<M>)
-        where M: ValidateurX509 + GenerateurMessages + MongoDao + ChiffrageFactoryTrait
+    pub async fn run<M>(mut self, middleware: Arc<M>, gestionnaire: &'static GestionnaireMessagerie)
+        where M: ValidateurX509 + GenerateurMessages + MongoDao + ChiffrageFactoryTrait + VerificateurMessage
     {
         debug!("pompe_messages.PompeMessages Running thread pompe");
 
         while let Some(message) = self.rx.recv().await {
             debug!("pompe_messages.run Trigger recu : {:?}", message);
-            match self.cycle_pompe_messages(middleware.as_ref(), message).await {
+            match self.cycle_pompe_messages(middleware.as_ref(), gestionnaire, message).await {
                 Ok(_) => (),
                 Err(e) => error!("pompe_messages.run Erreur runtime : {:?}", e)
             }
@@ -114,16 +170,24 @@ impl PompeMessages {
         debug!("pompe_messages.PompeMessages Fin thread pompe");
     }
 
-    async fn cycle_pompe_messages<M>(&mut self, middleware: &M, trigger: MessagePompe)
+    async fn cycle_pompe_messages<M>(&mut self, middleware: &M, gestionnaire: &GestionnaireMessagerie, trigger: MessagePompe)
         -> Result<(), Box<dyn Error>>
-        where M: ValidateurX509 + GenerateurMessages + MongoDao + ChiffrageFactoryTrait
+        where M: ValidateurX509 + GenerateurMessages + MongoDao + ChiffrageFactoryTrait + VerificateurMessage
     {
         traiter_dns_unresolved(middleware, &trigger).await;
-        traiter_messages_locaux(middleware, &trigger).await;
-        traiter_notifications(middleware, &trigger).await;
-        traiter_attachments_tiers(middleware, &trigger).await;
-        traiter_messages_tiers(middleware, &trigger).await;
-        expirer_messages(middleware, &trigger).await;
+        traiter_dns_failure(middleware, &trigger).await;
+        traiter_messages_locaux(middleware, gestionnaire, &trigger).await;
+
+        // Hold : sans postmaster, on ne pousse rien vers l'exterieur (evite de consommer les retry)
+        // et les messages ne sont pas expires (les tentatives n'ont pas pu etre faites).
+        if verifier_presence_postmaster(middleware, gestionnaire).await {
+            traiter_notifications(middleware, &trigger).await;
+            traiter_attachments_tiers(middleware, &trigger).await;
+            traiter_messages_tiers(middleware, &trigger).await;
+            expirer_messages(middleware, &trigger).await;
+        } else {
+            info!("pompe_messages.cycle_pompe_messages Postmaster absent, messages tiers et notifications en hold");
+        }
         Ok(())
     }
 }
@@ -191,8 +255,143 @@ async fn traiter_dns_unresolved<M>(middleware: &M, trigger: &MessagePompe)
     }
 }
 
-async fn traiter_messages_locaux<M>(middleware: &M, trigger: &MessagePompe)
+/// Delais (heures) entre les tentatives de resolve des dns_failure. La derniere valeur est repetee
+/// jusqu'a l'age maximal du message.
+const DNS_FAILURE_RETRY_HEURES: [i64; 3] = [1, 6, 24];
+/// Age maximal (jours) d'un message pour lequel on tente encore de resoudre les dns_failure.
+const DNS_FAILURE_AGE_MAX_JOURS: i64 = 7;
+
+async fn traiter_dns_failure<M>(middleware: &M, trigger: &MessagePompe)
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("traiter_dns_failure");
+    if let Err(e) = retry_dns_failure(middleware).await {
+        error!("traiter_dns_failure Erreur traitement : {:?}", e);
+    }
+    if let Err(e) = expirer_dns_failure(middleware).await {
+        error!("traiter_dns_failure Erreur expiration : {:?}", e);
+    }
+}
+
+/// Les destinataires dont le DNS est toujours en echec apres l'age maximal sont marques en echec
+/// (CODE_DNS_ECHEC). L'expediteur recoit le resultat lorsque le message est complete.
+async fn expirer_dns_failure<M>(middleware: &M) -> Result<(), Box<dyn Error>>
     where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let filtre = doc! {
+        format!("{}.0", CHAMP_DNS_FAILURE): {"$exists": true},
+        "created": {"$lt": Utc::now() - Duration::days(DNS_FAILURE_AGE_MAX_JOURS)},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    let mut messages_expires = Vec::new();
+    let mut curseur = collection.find(filtre, None).await?;
+    while let Some(r) = curseur.next().await {
+        let message_outgoing: DocOutgointProcessing = convertir_bson_deserializable(r?)?;
+        messages_expires.push(message_outgoing);
+    }
+
+    for message_outgoing in messages_expires {
+        let dns_failure = match message_outgoing.dns_failure.as_ref() {
+            Some(d) => d,
+            None => continue
+        };
+        info!("expirer_dns_failure Message {} expire pour DNS {:?}", message_outgoing.message_id, dns_failure);
+
+        let array_filters = vec![
+            doc! {"dest.dns": {"$in": dns_failure}, "dest.processed": {"$ne": true}}
+        ];
+        let options = FindOneAndUpdateOptions::builder()
+            .array_filters(array_filters)
+            .return_document(ReturnDocument::After)
+            .build();
+        let filtre = doc! { CHAMP_MESSAGE_ID: &message_outgoing.message_id };
+        let ops = doc! {
+            "$set": {
+                "destinataires.$[dest].processed": true,
+                "destinataires.$[dest].result": CODE_DNS_ECHEC,
+            },
+            "$unset": {CHAMP_DNS_FAILURE: true, CHAMP_DNS_FAILURE_NEXT_RETRY: true},
+            "$currentDate": {CHAMP_LAST_PROCESSED: true},
+        };
+        let doc_mappe: DocOutgointProcessing = match collection.find_one_and_update(filtre, ops, Some(options)).await? {
+            Some(d) => convertir_bson_deserializable(d)?,
+            None => continue
+        };
+
+        if verifier_message_complete(middleware, &doc_mappe) {
+            let transaction = TransactionTransfertComplete {
+                message_id: doc_mappe.message_id.clone(),
+                message_complete: Some(true),
+                attachments_completes: Some(true),
+                destinataires: map_destinataires_outgoing(&doc_mappe),
+            };
+            soumettre_transfert_complete(middleware, &transaction).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Nouvelle tentative de resolve pour les DNS en echec, selon un backoff long (1h, 6h, 24h, ...).
+async fn retry_dns_failure<M>(middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    let now = Utc::now();
+    let filtre = doc! {
+        format!("{}.0", CHAMP_DNS_FAILURE): {"$exists": true},
+        "created": {"$gte": now - Duration::days(DNS_FAILURE_AGE_MAX_JOURS)},
+        CHAMP_DNS_FAILURE_NEXT_RETRY: {"$lte": now},
+    };
+    let options = FindOptions::builder()
+        .sort(doc!{CHAMP_DNS_FAILURE_NEXT_RETRY: 1})
+        .limit(1000)
+        .build();
+
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let doc_outgoing = r?;
+        let retry_count = match doc_outgoing.get_i32(CHAMP_DNS_FAILURE_RETRY_COUNT) {
+            Ok(c) => c as usize,
+            Err(_) => 0
+        };
+        let message_outgoing: DocOutgointProcessing = convertir_bson_deserializable(doc_outgoing)?;
+        let dns_failure = match message_outgoing.dns_failure {
+            Some(d) => d,
+            None => continue
+        };
+
+        // Conserver l'historique des tentatives par host, cedule la prochaine tentative
+        let idx_delai = std::cmp::min(retry_count + 1, DNS_FAILURE_RETRY_HEURES.len() - 1);
+        let next_retry = now + Duration::hours(DNS_FAILURE_RETRY_HEURES[idx_delai]);
+        let mut set_ops = doc! { CHAMP_DNS_FAILURE_NEXT_RETRY: next_retry };
+        let mut inc_ops = doc! { CHAMP_DNS_FAILURE_RETRY_COUNT: 1 };
+        for dns in &dns_failure {
+            let cle_dns = url_to_mongokey(dns)?;
+            set_ops.insert(format!("{}.{}.dns", CHAMP_DNS_FAILURE_RETRY, cle_dns), dns);
+            set_ops.insert(format!("{}.{}.derniere_tentative", CHAMP_DNS_FAILURE_RETRY, cle_dns), now);
+            inc_ops.insert(format!("{}.{}.tentatives", CHAMP_DNS_FAILURE_RETRY, cle_dns), 1);
+        }
+        let filtre = doc! { CHAMP_MESSAGE_ID: &message_outgoing.message_id };
+        let ops = doc! {
+            "$set": set_ops,
+            "$inc": inc_ops,
+            "$currentDate": {CHAMP_LAST_PROCESSED: true},
+        };
+        collection.update_one(filtre, ops, None).await?;
+
+        debug!("retry_dns_failure Nouvelle tentative ({}) de resolve pour message {}, DNS : {:?}",
+            retry_count + 1, message_outgoing.message_id, dns_failure);
+        if let Err(e) = emettre_requete_resolve(middleware, message_outgoing.transaction_id.as_str(), &dns_failure).await {
+            warn!("retry_dns_failure Erreur resolve message {}, DNS {:?} : {:?}", message_outgoing.message_id, dns_failure, e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn traiter_messages_locaux<M>(middleware: &M, gestionnaire: &GestionnaireMessagerie, trigger: &MessagePompe)
+    where M: ValidateurX509 + GenerateurMessages + MongoDao + VerificateurMessage + ChiffrageFactoryTrait
 {
     let batch = match get_batch_messages(middleware, true, 1000).await {
         Ok(b) => b,
@@ -204,7 +403,7 @@ async fn traiter_messages_locaux<M>(middleware: &M, trigger: &MessagePompe)
 
     debug!("Traiter batch messages locaux : {:?}", batch);
     for message in &batch {
-        if let Err(e) = pousser_message_local(middleware, message).await {
+        if let Err(e) = pousser_message_local(middleware, gestionnaire, message).await {
             error!("traiter_messages_locaux Erreur traitement pousser_message_local, message {} : {:?}", message.transaction_id, e);
         }
     }
@@ -391,7 +590,11 @@ async fn expirer_message_resolve<M>(middleware: &M, trigger: &MessagePompe) -> R
         "dns_unresolved.0": {"$exists": true}
     };
     let ops = doc! {
-        "$rename": {"dns_unresolved": "dns_failure"},
+        "$rename": {"dns_unresolved": CHAMP_DNS_FAILURE},
+        "$set": {
+            CHAMP_DNS_FAILURE_RETRY_COUNT: 0,
+            CHAMP_DNS_FAILURE_NEXT_RETRY: Utc::now() + Duration::hours(DNS_FAILURE_RETRY_HEURES[0]),
+        },
         "$currentDate": {CHAMP_LAST_PROCESSED: true},
     };
 
@@ -442,12 +645,22 @@ async fn get_batch_messages<M>(middleware: &M, local: bool, limit: i64)
 }
 
 /// Pousse des messages locaux. Transfere le contenu dans la reception de chaque destinataire.
-async fn pousser_message_local<M>(middleware: &M, message: &DocOutgointProcessing) -> Result<(), Box<dyn Error>>
-    where M: GenerateurMessages + MongoDao + ValidateurX509
+async fn pousser_message_local<M>(middleware: &M, gestionnaire: &GestionnaireMessagerie, message: &DocOutgointProcessing)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
 {
     debug!("pousser_message_local Pousser message : {:?}", message);
     let message_id = message.message_id.as_str();
 
+    if cle_message_en_attente(middleware, message_id).await? {
+        debug!("pousser_message_local Cle du message {} en attente (outbox), livraison retardee", message_id);
+        return Ok(())
+    }
+    if cles_attachments_en_attente(middleware, message.fuuids.as_ref()).await? {
+        debug!("pousser_message_local Cle d'attachment du message {} en attente (outbox), livraison retardee", message_id);
+        return Ok(())
+    }
+
     // Mapping idmg local
     let idmg_local = middleware.get_enveloppe_signature().idmg()?;
 
@@ -477,22 +690,25 @@ async fn pousser_message_local<M>(middleware: &M, message: &DocOutgointProcessin
         message: commande_poster.message,
         destinataires: destinataires.clone(),
         fuuids: commande_poster.fuuids,
+        from: commande_poster.from,
+        type_envoi: commande_poster.type_envoi,
+        transfert: commande_poster.transfert,
+        thread: commande_poster.thread,
     };
 
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_RECEVOIR)
-        .exchanges(vec![Securite::L2Prive])
-        .build();
-
-    let reponse: ReponseRecevoirMessages = match middleware.transmettre_commande(routage, &commande, true).await? {
-        Some(r) => match r {
-            TypeMessage::Valide(m) => {
-                m.message.parsed.map_contenu()?
-            },
-            _ => Err(format!("pompe_messages.pousser_message_local Erreur traitement recevoir message_id {} pour idmg local {} - mauvais type reponse traitemnet de transaction", message_id, idmg_local))?
+    // Livraison directe (in-process) via le gestionnaire. Le domaine traite le idmg local, aucun
+    // aller-retour MQ. En cas d'erreur, le retry du message s'applique (next_push_time).
+    let reponse = recevoir_message(middleware, commande, gestionnaire).await?;
+    match reponse {
+        Some(r) => {
+            let reponse: ReponseRecevoirMessages = r.map_contenu()?;
+            debug!("pousser_message_local Reponse livraison locale : {:?}", reponse);
+            if reponse.ok == Some(false) {
+                warn!("pousser_message_local Livraison locale message_id {} pour idmg {} refusee : {:?}", message_id, idmg_local, reponse);
+            }
         },
-        None => Err(format!("pompe_messages.pousser_message_local Erreur traitement recevoir message_id {} pour idmg local {} - aucune reponse de traitement de transaction", message_id, idmg_local))?
-    };
-    debug!("pousser_message_local Reponse commande message local : {:?}", reponse);
+        None => warn!("pousser_message_local Aucune reponse de livraison locale pour message_id {}", message_id)
+    }
 
     Ok(())
 }
@@ -629,7 +845,7 @@ pub async fn marquer_destinataires_process<M>(
         let options = UpdateOptions::builder().array_filters(array_filters.clone()).build();
 
         let mut processed = match result_code {
-            200 | 404 => true,
+            200 | 403 | 404 | 409 | 451 | 507 => true,
             _ => false
         };
 
@@ -644,15 +860,13 @@ pub async fn marquer_destinataires_process<M>(
             set_ops.insert(format!("idmgs_mapping.{}.next_push_time", idmg), next_push);
         }
 
-        let mut ops = doc! {
+        let ops = doc! {
             "$set": set_ops,
             "$currentDate": {"last_processed": true}
         };
 
-        if processed {
-            ops.insert("$pull", doc! {"idmgs_unprocessed": &idmg});
-            ops.insert("$unset", doc! { format!("idmgs_mapping.{}.next_push_time", idmg): true });
-        }
+        // Le retrait de idmgs_unprocessed est fait par marquer_idmg_process_code lorsque toutes
+        // les enveloppes du idmg sont traitees.
 
         debug!("marquer_outgoing_resultat Filtre maj outgoing : {:?}, ops: {:?}, array_filters : {:?}", filtre_outgoing, ops, array_filters);
         match collection_outgoing_processing.update_one(filtre_outgoing.clone(), ops, Some(options)).await {
@@ -679,17 +893,21 @@ pub async fn marquer_outgoing_resultat<M>(
     let collection_outgoing_processing = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
     let filtre_outgoing = doc! { CHAMP_UUID_MESSAGE: message_id };
 
+    // Marquer destinataires process status. L'etat de livraison est conserve par destinataire :
+    // chaque enveloppe bcc d'un idmg recoit sa propre confirmation.
+    if let Some(inner) = destinataires.as_ref() {
+        marquer_destinataires_process(middleware, message_id, idmg, inner).await?;
+    }
+
+    // Le idmg est traite uniquement lorsque toutes ses enveloppes sont traitees
+    let idmg_complete = processed && verifier_enveloppes_traitees(middleware, message_id, idmg).await?;
+
     // Marquer idmg prcess status
-    let doc_outgoing = match marquer_idmg_process_code(middleware, message_id, idmg, processed, result_code).await {
+    let doc_outgoing = match marquer_idmg_process_code(middleware, message_id, idmg, idmg_complete, result_code).await {
         Ok(inner) => inner,
         Err(e) => Err(format!("pompe_messages.marquer_outgoing_resultat Erreur marquer_idmg_process_code {:?}", e))?
     };
 
-    // Marquer destinataires process status
-    if let Some(inner) = destinataires.as_ref() {
-        marquer_destinataires_process(middleware, message_id, idmg, inner).await?;
-    }
-
     let doc_mappe = match doc_outgoing {
         Some(inner) => inner,
         None => return Ok(())  // Rien a faire
@@ -711,7 +929,15 @@ pub async fn marquer_outgoing_resultat<M>(
         }
     }
 
+    if ! idmg_complete {
+        return Ok(())  // Enveloppes en attente pour le idmg
+    }
+
     let millegrille_completee = match &doc_mappe.fuuids {
+        Some(_) if result_code == Some(CODE_FEDERATION_REFUSEE as u32) => {
+            // Millegrille refusee par la politique de federation, aucuns fichiers a transferer
+            true
+        },
         Some(fuuids) => {
             let idmg_local = middleware.idmg();
             if idmg != idmg_local {
@@ -759,16 +985,13 @@ pub async fn marquer_outgoing_resultat<M>(
             // Mapper destinataires
             let map_destinataires = map_destinataires_outgoing(&doc_mappe);
 
-            let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_TRANSFERT_COMPLETE)
-                .exchanges(vec![Securite::L4Secure])
-                .build();
             let t = TransactionTransfertComplete {
                 message_id: message_id.into(),
                 message_complete: Some(true),
                 attachments_completes: Some(true),
                 destinataires: map_destinataires,
             };
-            middleware.soumettre_transaction(routage.clone(), &t, false).await?;
+            soumettre_transfert_complete(middleware, &t).await?;
         }
     }
 
@@ -824,7 +1047,7 @@ where
     Ok(commande_poster)
 }
 
-async fn get_cle_message<M,S>(middleware: &M, cle_ref: S)
+pub async fn get_cle_message<M,S>(middleware: &M, cle_ref: S)
     -> Result<CleSecrete, Box<dyn Error>>
     where
         M: GenerateurMessages,
@@ -893,6 +1116,23 @@ async fn get_fiches_applications<M>(middleware: &M, message: &DocOutgointProcess
     Ok(fiches_applications.fiches)
 }
 
+/// Recupere la fiche de l'application messagerie d'une millegrille tierce.
+pub async fn get_fiche_application_idmg<M>(middleware: &M, idmg: &str)
+    -> Result<Option<FicheMillegrilleApplication>, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let routage_requete = RoutageMessageAction::builder("CoreTopologie", "applicationsTiers")
+        .exchanges(vec![L2Prive])
+        .build();
+    let requete = json!({"idmgs": [idmg], "application": "messagerie_web"});
+    let fiches_applications: ReponseFichesApplications = match middleware.transmettre_requete(routage_requete, &requete).await? {
+        TypeMessage::Valide(r) => r.message.parsed.map_contenu()?,
+        _ => Err(format!("pompe_messages.get_fiche_application_idmg Requete applicationsTiers, reponse de mauvais type"))?
+    };
+
+    Ok(fiches_applications.fiches.into_iter().find(|f| f.idmg.as_str() == idmg))
+}
+
 async fn rechiffrer_cle_pour_fiche<M>(middleware: &M, cle_secrete: &CleSecrete, fiche: &FicheMillegrilleApplication)
     -> Result<HashMap<String, String>, Box<dyn Error>>
     where M: ValidateurX509
@@ -939,34 +1179,98 @@ async fn rechiffrer_cle_pour_fiche<M>(middleware: &M, cle_secrete: &CleSecrete,
     Ok(cles_rechiffrees)
 }
 
-async fn generer_attachement_transfert<M>(
-    middleware: &M, message: &DocumentOutgoing,
-    processing: &DocOutgointProcessing,
-    fiche: &FicheMillegrilleApplication,
-    cle_secrete: &CleSecrete
-)
-    -> Result<MessageMilleGrille, Box<dyn Error>>
-    where M: ValidateurX509 + GenerateurMessages + MongoDao + ChiffrageFactoryTrait
+/// Groupe les destinataires d'un idmg par enveloppe de transfert : les destinataires visibles
+/// ensemble, puis chaque destinataire bcc seul.
+fn grouper_enveloppes_destinataires(processing: &DocOutgointProcessing, idmg: &str)
+    -> Result<Vec<Vec<String>>, Box<dyn Error>>
 {
-    let idmg_fiche = fiche.idmg.as_str();
-
     let mapping = match processing.idmgs_mapping.as_ref() {
-        Some(inner) => match inner.get(idmg_fiche) {
+        Some(inner) => match inner.get(idmg) {
             Some(inner) => inner,
-            None => Err(format!("pompe_messages.generer_attachement_transfert Mapping idmg {} absent", idmg_fiche))?
+            None => Err(format!("pompe_messages.grouper_enveloppes_destinataires Mapping idmg {} absent", idmg))?
         },
-        None => Err(format!("pompe_messages.generer_attachement_transfert Mapping idmgs absent"))?
+        None => Err(format!("pompe_messages.grouper_enveloppes_destinataires Mapping idmgs absent"))?
     };
 
-    let destinataires = {
-        let mut destinataires = mapper_destinataires(processing, mapping);
-        destinataires.into_iter().map(|d| d.destinataire).collect::<Vec<String>>()
+    let mut visibles = Vec::new();
+    let mut enveloppes = Vec::new();
+    for d in mapper_destinataires(processing, mapping) {
+        if d.bcc == Some(true) {
+            enveloppes.push(vec![d.destinataire]);
+        } else {
+            visibles.push(d.destinataire);
+        }
+    }
+    if ! visibles.is_empty() {
+        enveloppes.insert(0, visibles);
+    }
+
+    Ok(enveloppes)
+}
+
+/// Retourne true si toutes les enveloppes du idmg sont traitees.
+async fn verifier_enveloppes_traitees<M>(middleware: &M, message_id: &str, idmg: &str) -> Result<bool, String>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    let doc_processing: DocOutgointProcessing = match collection.find_one(doc! { CHAMP_UUID_MESSAGE: message_id }, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable(d) {
+            Ok(inner) => inner,
+            Err(e) => Err(format!("pompe_messages.verifier_enveloppes_traitees Erreur conversion DocOutgoingProcessing : {:?}", e))?
+        },
+        Ok(None) => return Ok(true),
+        Err(e) => Err(format!("pompe_messages.verifier_enveloppes_traitees Erreur chargement message {} : {:?}", message_id, e))?
+    };
+    match enveloppes_en_attente(&doc_processing, idmg) {
+        Ok(enveloppes) => {
+            if ! enveloppes.is_empty() {
+                debug!("verifier_enveloppes_traitees Message {} idmg {}, {} enveloppes en attente", message_id, idmg, enveloppes.len());
+            }
+            Ok(enveloppes.is_empty())
+        },
+        Err(e) => {
+            // Mapping absent (e.g. idmg retire), le resultat s'applique au idmg
+            warn!("verifier_enveloppes_traitees Message {} idmg {} : {:?}", message_id, idmg, e);
+            Ok(true)
+        }
+    }
+}
+
+/// Enveloppes du idmg dont au moins un destinataire n'est pas encore traite. Les enveloppes bcc
+/// deja livrees ne sont pas transmises de nouveau.
+fn enveloppes_en_attente(processing: &DocOutgointProcessing, idmg: &str)
+    -> Result<Vec<Vec<String>>, Box<dyn Error>>
+{
+    let traites: HashSet<&str> = match processing.destinataires.as_ref() {
+        Some(d) => d.iter()
+            .filter(|d| d.processed == Some(true))
+            .map(|d| d.destinataire.as_str())
+            .collect(),
+        None => HashSet::new()
     };
+    let enveloppes = grouper_enveloppes_destinataires(processing, idmg)?.into_iter()
+        .filter(|e| e.iter().any(|d| ! traites.contains(d.as_str())))
+        .collect();
+    Ok(enveloppes)
+}
 
+async fn generer_attachement_transfert<M>(
+    middleware: &M, message: &DocumentOutgoing,
+    destinataires: Vec<String>,
+    fiche: &FicheMillegrilleApplication,
+    cle_secrete: &CleSecrete
+)
+    -> Result<MessageMilleGrille, Box<dyn Error>>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao + ChiffrageFactoryTrait
+{
     let commande_transfert = CommandeTransfertPoster {
         to: destinataires,
         files: message.fuuids.clone(),
-        message_key: multibase::encode(Base::Base64, &cle_secrete.0[..])
+        message_key: multibase::encode(Base::Base64, &cle_secrete.0[..]),
+        from: message.from.clone(),
+        type_envoi: message.type_envoi.clone(),
+        transfert: message.transfert.clone(),
+        thread: message.thread.clone(),
     };
 
     debug!("pompe_messages.generer_attachement_transfert Commande transfert a chiffrer : {:?}", commande_transfert);
@@ -1018,6 +1322,15 @@ async fn pousser_message_vers_tiers<M>(middleware: &M, message: &DocOutgointProc
     let uuid_transaction = message.transaction_id.as_str();
     let uuid_message = message.message_id.as_str();
 
+    if cle_message_en_attente(middleware, uuid_message).await? {
+        debug!("pousser_message_vers_tiers Cle du message {} en attente (outbox), livraison retardee", uuid_message);
+        return Ok(())
+    }
+    if cles_attachments_en_attente(middleware, message.fuuids.as_ref()).await? {
+        debug!("pousser_message_vers_tiers Cle d'attachment du message {} en attente (outbox), livraison retardee", uuid_message);
+        return Ok(())
+    }
+
     // Charger transaction message mappee via serde
     let commande_poster = charger_preparer_message(middleware, uuid_message).await?;
 
@@ -1036,42 +1349,213 @@ async fn pousser_message_vers_tiers<M>(middleware: &M, message: &DocOutgointProc
 
     let fiches = get_fiches_applications(middleware, message).await?;
     let enveloppe_privee = middleware.get_enveloppe_signature();
+    let politique = charger_politique_federation(middleware).await?;
+
+    for mut fiche in fiches.into_iter() {
+        if ! politique.autorise(fiche.idmg.as_str()) {
+            refuser_federation_sortant(middleware, message, fiche.idmg.as_str()).await?;
+            continue
+        }
 
-    for fiche in fiches.into_iter() {
         // Incrementer compteur, mettre next push a 15 minutes (en cas d'echec)
         incrementer_push(middleware, fiche.idmg.as_str(), uuid_transaction).await?;
 
-        // Generer attachement transfert chiffre pour destinataires, cle, fuuids
-        let attachement_transfert = generer_attachement_transfert(
-            middleware, &commande_poster, &message, &fiche, &cle_secrete_message).await?;
+        // Ordonner les urls de la fiche selon leur etat (failover), conserver l'ordre pour le idmg
+        let urls = ordonner_urls_fiche(middleware, &mut fiche).await?;
+        marquer_urls_push(middleware, fiche.idmg.as_str(), uuid_message, &urls).await?;
 
-        // Formatter commande avec attachements pour fiche courante
-        let mut message = commande_poster.message.clone();
-        message.ajouter_attachement("transfert", serde_json::to_value(attachement_transfert)?);
+        // Une enveloppe pour les destinataires visibles et une enveloppe distincte par destinataire
+        // bcc. La millegrille tierce ne voit pas les adresses des destinataires caches.
+        for destinataires in enveloppes_en_attente(message, fiche.idmg.as_str())? {
+            // Generer attachement transfert chiffre pour destinataires, cle, fuuids
+            let attachement_transfert = generer_attachement_transfert(
+                middleware, &commande_poster, destinataires, &fiche, &cle_secrete_message).await?;
 
-        // Emettre message sous forme de commande inter-millegrille vers PostMaster
-        let routage = RoutageMessageAction::builder(DOMAINE_POSTMASTER, TRANSACTION_POSTER)
-            .exchanges(vec![Securite::L1Public])
-            .build();
+            // Formatter commande avec attachements pour fiche courante
+            let mut message = commande_poster.message.clone();
+            message.ajouter_attachement("transfert", serde_json::to_value(attachement_transfert)?);
 
-        let contenu_poster = CommandePostmasterPoster {
-            idmg: fiche.idmg.clone(),
-            message_id: uuid_message.to_owned(),
-            fiche,
-        };
+            // Emettre message sous forme de commande inter-millegrille vers PostMaster
+            let routage = RoutageMessageAction::builder(DOMAINE_POSTMASTER, TRANSACTION_POSTER)
+                .exchanges(vec![Securite::L1Public])
+                .build();
+
+            let contenu_poster = CommandePostmasterPoster {
+                idmg: fiche.idmg.clone(),
+                message_id: uuid_message.to_owned(),
+                fiche: fiche.clone(),
+            };
+
+            let mut commande_postmaster = MessageMilleGrille::new_signer(
+                &enveloppe_privee, MessageKind::Commande, &contenu_poster,
+                Some(DOMAINE_POSTMASTER), Some(TRANSACTION_POSTER), None::<&str>,
+                None::<i32>, true)?;
+
+            commande_postmaster.ajouter_attachement("message", serde_json::to_value(message)?);
+
+            debug!("Pousser message vers postmaster:\n{}", serde_json::to_string(&commande_postmaster)?);
+
+            middleware.emettre_message_millegrille(
+                routage, true, TypeMessageOut::Commande, commande_postmaster).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Marque les destinataires d'une millegrille refusee par la politique de federation (traites,
+/// code CODE_FEDERATION_REFUSEE) et emet l'evenement d'audit.
+async fn refuser_federation_sortant<M>(middleware: &M, message: &DocOutgointProcessing, idmg: &str)
+    -> Result<(), Box<dyn Error>>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let message_id = message.message_id.as_str();
+    warn!("refuser_federation_sortant Message {} vers idmg {} refuse par la politique de federation", message_id, idmg);
 
-        let mut commande_poster = MessageMilleGrille::new_signer(
-            &enveloppe_privee, MessageKind::Commande, &contenu_poster,
-            Some(DOMAINE_POSTMASTER), Some(TRANSACTION_POSTER), None::<&str>,
-            None::<i32>, true)?;
+    let destinataires: Vec<ConfirmerDestinataire> = grouper_enveloppes_destinataires(message, idmg)?.into_iter()
+        .flatten()
+        .map(|d| ConfirmerDestinataire { code: CODE_FEDERATION_REFUSEE, destinataire: d })
+        .collect();
+    marquer_outgoing_resultat(
+        middleware, message_id, idmg, Some(destinataires), true, Some(CODE_FEDERATION_REFUSEE as u32)).await?;
+
+    emettre_evenement_federation_refusee(middleware, idmg, DIRECTION_SORTANT, message_id).await?;
+
+    Ok(())
+}
+
+/// Trie les applications de la fiche pour essayer les urls en sante en premier. Les urls sans
+/// echecs consecutifs passent en premier, ensuite par latence moyenne.
+async fn ordonner_urls_fiche<M>(middleware: &M, fiche: &mut FicheMillegrilleApplication)
+    -> Result<Vec<String>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let urls: Vec<String> = fiche.applications.iter().map(|a| a.url.clone()).collect();
+    if urls.len() < 2 {
+        return Ok(urls)
+    }
 
-        commande_poster.ajouter_attachement("message", serde_json::to_value(message)?);
+    let mut sante_urls: HashMap<String, DocSanteUrl> = HashMap::new();
+    let filtre = doc! { CHAMP_URL: {"$in": &urls} };
+    let collection = middleware.get_collection(NOM_COLLECTION_SANTE_URLS)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    while let Some(r) = curseur.next().await {
+        let doc_sante: DocSanteUrl = convertir_bson_deserializable(r?)?;
+        sante_urls.insert(doc_sante.url.clone(), doc_sante);
+    }
+
+    // Tri stable, l'ordre de la fiche est conserve pour les urls equivalents
+    fiche.applications.sort_by_key(|a| cle_tri_sante_url(sante_urls.get(&a.url)));
+
+    let urls = fiche.applications.iter().map(|a| a.url.clone()).collect();
+    debug!("ordonner_urls_fiche Ordre urls pour idmg {} : {:?}", fiche.idmg, urls);
+
+    Ok(urls)
+}
+
+/// Cle de tri d'un url : echecs consecutifs, ensuite latence moyenne. Un url sans historique
+/// est considere en sante.
+fn cle_tri_sante_url(sante: Option<&DocSanteUrl>) -> (i64, i64) {
+    match sante {
+        Some(s) => (s.echecs_consecutifs.unwrap_or(0), s.latence_moyenne_ms().unwrap_or(0)),
+        None => (0, 0)
+    }
+}
+
+/// Conserve l'ordre des urls et l'url essaye en premier par le postmaster. Cet url est utilise
+/// pour la sante lorsque la confirmation de transmission ne fournit pas d'url.
+async fn marquer_urls_push<M>(middleware: &M, idmg: &str, message_id: &str, urls: &Vec<String>)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let ops = doc!{
+        "$set": {
+            format!("idmgs_mapping.{}.urls", idmg): urls,
+            format!("idmgs_mapping.{}.url", idmg): urls.first().cloned(),
+            format!("idmgs_mapping.{}.push_time_ms", idmg): Utc::now().timestamp_millis(),
+        },
+    };
+    let filtre = doc!{ CHAMP_MESSAGE_ID: message_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    collection.update_one(filtre, ops, None).await?;
 
-        debug!("Pousser message vers postmaster:\n{}", serde_json::to_string(&commande_poster)?);
+    Ok(())
+}
 
-        middleware.emettre_message_millegrille(
-            routage, true, TypeMessageOut::Commande, commande_poster).await?;
+/// Url de la transmission : celui de la confirmation, sinon celui conserve lors du push.
+fn url_transmission(url: Option<String>, mapping: &DocMappingIdmg) -> Option<String> {
+    match url {
+        Some(u) => Some(u),
+        None => mapping.url.clone()
     }
+}
+
+/// Les erreurs serveur (5xx sauf 507) et les codes hors HTTP sont des echecs de transport.
+fn transport_reussi(code: u32) -> bool {
+    (200..500).contains(&code) || code == 507
+}
+
+/// Conserve le resultat d'une transmission pour l'url utilise (compteurs succes/echecs, latence).
+/// La sante reflete le transport seulement : une reponse de l'application tierce (meme un refus
+/// comme 404 ou 507) est un succes. Sans url fourni par le postmaster, l'url conserve lors du
+/// push est utilise.
+pub async fn maj_sante_url<M>(middleware: &M, message_id: &str, idmg: &str, url: Option<String>, code: u32)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let succes = transport_reussi(code);
+
+    let collection_outgoing = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    let filtre_outgoing = doc!{ CHAMP_MESSAGE_ID: message_id };
+    let doc_outgoing: DocOutgointProcessing = match collection_outgoing.find_one(filtre_outgoing.clone(), None).await? {
+        Some(d) => convertir_bson_deserializable(d)?,
+        None => return Ok(())
+    };
+    let mapping = match doc_outgoing.idmgs_mapping.as_ref() {
+        Some(m) => match m.get(idmg) {
+            Some(m) => m,
+            None => return Ok(())
+        },
+        None => return Ok(())
+    };
+
+    let url = match url_transmission(url, mapping) {
+        Some(u) => u,
+        None => {
+            debug!("maj_sante_url Url inconnu pour message {} idmg {}, skip", message_id, idmg);
+            return Ok(())
+        }
+    };
+
+    let now = Utc::now();
+    let ops = match succes {
+        true => {
+            let mut set_ops = doc!{ "idmg": idmg, "echecs_consecutifs": 0, "dernier_succes": now };
+            let mut inc_ops = doc!{ "succes": 1 };
+            if let Some(push_time_ms) = mapping.push_time_ms {
+                let latence_ms = now.timestamp_millis() - push_time_ms;
+                set_ops.insert("latence_ms", latence_ms);
+                inc_ops.insert("latence_totale_ms", latence_ms);
+            }
+            doc!{ "$set": set_ops, "$inc": inc_ops, "$currentDate": {CHAMP_MODIFICATION: true} }
+        },
+        false => doc!{
+            "$set": { "idmg": idmg, "dernier_echec": now, "dernier_code": code },
+            "$inc": { "echecs": 1, "echecs_consecutifs": 1 },
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        }
+    };
+    let filtre = doc!{ CHAMP_URL: &url };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_SANTE_URLS)?;
+    collection.update_one(filtre, ops, Some(options)).await?;
+
+    // Conserver l'url dans l'etat de livraison du idmg
+    let ops = doc!{
+        "$set": { format!("idmgs_mapping.{}.url", idmg): &url },
+        "$currentDate": {CHAMP_LAST_PROCESSED: true},
+    };
+    collection_outgoing.update_one(filtre_outgoing, ops, None).await?;
 
     Ok(())
 }
@@ -1279,16 +1763,13 @@ pub async fn verifier_fin_transferts_attachments<M>(middleware: &M, doc_outgoing
         let doc_outgoing: DocOutgointProcessing = convertir_bson_deserializable(d)?;
         if verifier_message_complete(middleware, &doc_outgoing) {
             let map_destinataires = map_destinataires_outgoing(&doc_outgoing);
-            let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_TRANSFERT_COMPLETE)
-                .exchanges(vec![Securite::L4Secure])
-                .build();
             let t = TransactionTransfertComplete {
                 message_id: doc_outgoing.message_id,
                 message_complete: Some(true),
                 attachments_completes: Some(true),
                 destinataires: map_destinataires,
             };
-            middleware.soumettre_transaction(routage.clone(), &t, false).await?;
+            soumettre_transfert_complete(middleware, &t).await?;
         }
     }
 
@@ -1371,13 +1852,172 @@ async fn marquer_messages_completes<M>(middleware: &M) -> Result<(), Box<dyn Err
         }
     }
 
+    for transaction in messages_completes.values() {
+        soumettre_transfert_complete(middleware, transaction).await?;
+    }
+
+    Ok(())
+}
+
+/// Soumet la transaction transfert complete. Un message transfere par une regle de transfert qui
+/// n'a ete livre a aucun destinataire libere son transfert (un nouveau transfert est permis).
+async fn soumettre_transfert_complete<M>(middleware: &M, transaction: &TransactionTransfertComplete)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
     let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_TRANSFERT_COMPLETE)
         .exchanges(vec![Securite::L4Secure])
         .build();
+    middleware.soumettre_transaction(routage, transaction, false).await?;
 
-    for transaction in messages_completes.values() {
-        middleware.soumettre_transaction(routage.clone(), transaction, false).await?;
+    if let Some(destinataires) = transaction.destinataires.as_ref() {
+        if livraison_echouee(destinataires) {
+            if let Err(e) = liberer_transfert_echec(middleware, transaction.message_id.as_str()).await {
+                warn!("soumettre_transfert_complete Erreur liberation transfert message {} : {:?}", transaction.message_id, e);
+            }
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test_pompe_messages {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    const IDMG_2: &str = "zIdmg2";
+
+    fn processing_bcc(resultat_bcc_1: bool, resultat_bcc_2: bool) -> DocOutgointProcessing {
+        let code_bcc_1 = if resultat_bcc_1 { 200 } else { 500 };
+        let code_bcc_2 = if resultat_bcc_2 { 200 } else { 500 };
+        let doc_processing = doc! {
+            "transaction_id": "zTransaction1",
+            "message_id": "zMessage1",
+            "destinataires": [
+                {"destinataire": "usager1@millegrille2.com", "user": "usager1", "dns": "millegrille2.com", "processed": true, "result": 200},
+                {"destinataire": "usager2@millegrille2.com", "user": "usager2", "dns": "millegrille2.com", "bcc": true, "processed": resultat_bcc_1, "result": code_bcc_1},
+                {"destinataire": "usager3@millegrille2.com", "user": "usager3", "dns": "millegrille2.com", "bcc": true, "processed": resultat_bcc_2, "result": code_bcc_2},
+            ],
+            "idmgs_mapping": {IDMG_2: {"dns": ["millegrille2.com"]}},
+        };
+        convertir_bson_deserializable(doc_processing).expect("convertir")
+    }
+
+    #[test]
+    fn test_enveloppes_bcc_separees() {
+        setup("test_enveloppes_bcc_separees");
+        let processing = processing_bcc(false, false);
+        let enveloppes = grouper_enveloppes_destinataires(&processing, IDMG_2).expect("enveloppes");
+        assert_eq!(3, enveloppes.len());
+        assert_eq!(vec!["usager1@millegrille2.com".to_string()], enveloppes[0]);
+    }
+
+    #[test]
+    fn test_enveloppe_bcc_echec() {
+        setup("test_enveloppe_bcc_echec");
+        let processing = processing_bcc(true, false);
+        let enveloppes = enveloppes_en_attente(&processing, IDMG_2).expect("enveloppes");
+        // Seule l'enveloppe bcc en echec est transmise de nouveau
+        assert_eq!(vec![vec!["usager3@millegrille2.com".to_string()]], enveloppes);
+    }
+
+    #[test]
+    fn test_enveloppes_livrees() {
+        setup("test_enveloppes_livrees");
+        let processing = processing_bcc(true, true);
+        let enveloppes = enveloppes_en_attente(&processing, IDMG_2).expect("enveloppes");
+        assert!(enveloppes.is_empty());
+    }
+
+    #[test]
+    fn test_enveloppes_mapping_absent() {
+        setup("test_enveloppes_mapping_absent");
+        let processing = processing_bcc(false, false);
+        assert!(enveloppes_en_attente(&processing, "zIdmgInconnu").is_err());
+    }
+
+    fn sante(url: &str, echecs_consecutifs: i64, succes: i64, latence_totale_ms: i64) -> DocSanteUrl {
+        DocSanteUrl {
+            url: url.to_string(),
+            idmg: Some(IDMG_2.to_string()),
+            succes: Some(succes),
+            echecs: Some(echecs_consecutifs),
+            echecs_consecutifs: Some(echecs_consecutifs),
+            latence_ms: None,
+            latence_totale_ms: Some(latence_totale_ms),
+        }
+    }
+
+    #[test]
+    fn test_ordre_url_en_echec() {
+        setup("test_ordre_url_en_echec");
+        let mut sante_urls = HashMap::new();
+        sante_urls.insert("https://a.millegrille2.com".to_string(), sante("https://a.millegrille2.com", 2, 10, 1000));
+        sante_urls.insert("https://b.millegrille2.com".to_string(), sante("https://b.millegrille2.com", 0, 10, 5000));
+
+        let mut urls = vec![
+            "https://a.millegrille2.com".to_string(),
+            "https://b.millegrille2.com".to_string(),
+            "https://c.millegrille2.com".to_string(),
+        ];
+        urls.sort_by_key(|u| cle_tri_sante_url(sante_urls.get(u)));
+
+        // L'url en echec passe en dernier, l'url sans historique garde sa position relative
+        assert_eq!(vec![
+            "https://b.millegrille2.com".to_string(),
+            "https://c.millegrille2.com".to_string(),
+            "https://a.millegrille2.com".to_string(),
+        ], urls);
+    }
+
+    #[test]
+    fn test_ordre_url_latence() {
+        setup("test_ordre_url_latence");
+        let mut sante_urls = HashMap::new();
+        sante_urls.insert("https://a.millegrille2.com".to_string(), sante("https://a.millegrille2.com", 0, 10, 5000));
+        sante_urls.insert("https://b.millegrille2.com".to_string(), sante("https://b.millegrille2.com", 0, 10, 1000));
+
+        let mut urls = vec!["https://a.millegrille2.com".to_string(), "https://b.millegrille2.com".to_string()];
+        urls.sort_by_key(|u| cle_tri_sante_url(sante_urls.get(u)));
+        assert_eq!(vec!["https://b.millegrille2.com".to_string(), "https://a.millegrille2.com".to_string()], urls);
+    }
+
+    #[test]
+    fn test_url_transmission() {
+        setup("test_url_transmission");
+        let mapping: DocMappingIdmg = convertir_bson_deserializable(doc! {
+            "dns": ["millegrille2.com"],
+            "urls": ["https://a.millegrille2.com", "https://b.millegrille2.com"],
+            "url": "https://a.millegrille2.com",
+        }).expect("convertir");
+
+        // Url du push utilise lorsque le postmaster ne fournit pas d'url
+        assert_eq!(Some("https://a.millegrille2.com".to_string()), url_transmission(None, &mapping));
+        assert_eq!(Some("https://b.millegrille2.com".to_string()),
+                   url_transmission(Some("https://b.millegrille2.com".to_string()), &mapping));
+    }
+
+    #[test]
+    fn test_destinataires_idmg_local() {
+        setup("test_destinataires_idmg_local");
+        let processing: DocOutgointProcessing = convertir_bson_deserializable(doc! {
+            "transaction_id": "zTransaction1",
+            "message_id": "zMessage1",
+            "destinataires": [
+                {"destinataire": "usager1@millegrille1.com", "user": "usager1", "dns": "millegrille1.com"},
+                {"destinataire": "usager2@millegrille2.com", "user": "usager2", "dns": "millegrille2.com"},
+                {"destinataire": "millegrille1.com", "dns": "millegrille1.com"},
+            ],
+            "idmgs_mapping": {"zIdmg1": {"dns": ["millegrille1.com"]}},
+        }).expect("convertir");
+        let mapping = processing.idmgs_mapping.as_ref().expect("mapping").get("zIdmg1").expect("idmg");
+
+        // Seuls les usagers du dns local sont livres par la livraison locale
+        let destinataires: Vec<String> = mapper_destinataires(&processing, mapping).into_iter()
+            .map(|d| d.destinataire)
+            .collect();
+        assert_eq!(vec!["usager1@millegrille1.com".to_string()], destinataires);
+    }
+}
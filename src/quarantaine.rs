@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use log::debug;
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::hachages::hacher_bytes;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::multibase::Base;
+use millegrilles_common_rust::multihash::Code;
+use millegrilles_common_rust::serde::Deserialize;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+
+#[derive(Clone, Debug, Deserialize)]
+struct DocProfilQuarantaine {
+    user_id: String,
+    quarantaine: ConfigurationQuarantaine,
+}
+
+/// Index aveugle d'une adresse. Le client calcule le meme index (sel du profil) pour les
+/// adresses de ses contacts, l'adresse en clair n'est jamais conservee par le serveur.
+pub fn calculer_index_adresse(sel: &str, adresse: &str) -> String {
+    let valeur = format!("{}:{}", sel, adresse.trim().to_lowercase());
+    hacher_bytes(valeur.as_bytes(), Some(Code::Blake2s256), Some(Base::Base58Btc))
+}
+
+/// Charge le sel d'index des usagers qui ont active la quarantaine.
+pub async fn charger_configurations_quarantaine<M, S>(middleware: &M, user_ids: &Vec<S>)
+    -> Result<HashMap<String, String>, Box<dyn Error>>
+    where M: MongoDao, S: AsRef<str>
+{
+    let mut configurations = HashMap::new();
+    let user_ids: Vec<&str> = user_ids.iter().map(|u| u.as_ref()).collect();
+    if user_ids.is_empty() {
+        return Ok(configurations)
+    }
+
+    let filtre = doc! {
+        CHAMP_USER_ID: {"$in": user_ids},
+        format!("{}.actif", CHAMP_QUARANTAINE): true,
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {CHAMP_USER_ID: 1, CHAMP_QUARANTAINE: 1})
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let profil: DocProfilQuarantaine = convertir_bson_deserializable(r?)?;
+        if let Some(sel) = profil.quarantaine.sel_index {
+            configurations.insert(profil.user_id, sel);
+        }
+    }
+
+    Ok(configurations)
+}
+
+/// Retourne true si l'index d'adresse correspond a un contact (non supprime) de l'usager.
+pub async fn verifier_contact_connu<M>(middleware: &M, user_id: &str, index_adresse: &str)
+    -> Result<bool, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_INDEX_ADRESSES: index_adresse,
+        CHAMP_SUPPRIME: false,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_CONTACTS)?;
+    let connu = collection.count_documents(filtre, None).await? > 0;
+    debug!("verifier_contact_connu Usager {} index {} connu : {}", user_id, index_adresse, connu);
+    Ok(connu)
+}
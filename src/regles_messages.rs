@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use log::debug;
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::serde::Deserialize;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+
+#[derive(Clone, Debug, Deserialize)]
+struct DocProfilReglesMessages {
+    user_id: String,
+    regles_messages: Vec<RegleMessage>,
+}
+
+/// Resultat de l'evaluation des regles pour un message.
+#[derive(Clone, Debug, Default)]
+pub struct ResultatReglesMessage {
+    pub labels: Vec<String>,
+    pub lu: bool,
+    pub archiver: bool,
+    pub etoile: bool,
+    pub supprimer: bool,
+    pub sans_notification: bool,
+}
+
+/// Charge les regles de messages des usagers. Les usagers sans regles sont absents du resultat.
+pub async fn charger_regles_messages<M, S>(middleware: &M, user_ids: &Vec<S>)
+    -> Result<HashMap<String, Vec<RegleMessage>>, Box<dyn Error>>
+    where M: MongoDao, S: AsRef<str>
+{
+    let mut regles = HashMap::new();
+    let user_ids: Vec<&str> = user_ids.iter().map(|u| u.as_ref()).collect();
+    if user_ids.is_empty() {
+        return Ok(regles)
+    }
+
+    let filtre = doc! {
+        CHAMP_USER_ID: {"$in": user_ids},
+        format!("{}.0", CHAMP_REGLES_MESSAGES): {"$exists": true},
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {CHAMP_USER_ID: 1, CHAMP_REGLES_MESSAGES: 1})
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let profil: DocProfilReglesMessages = convertir_bson_deserializable(r?)?;
+        regles.insert(profil.user_id, profil.regles_messages);
+    }
+
+    Ok(regles)
+}
+
+/// Evalue les regles dans l'ordre. L'evaluation depend uniquement des regles et des metadonnees
+/// du message (incluant l'estampille de la transaction), la regeneration donne donc le meme resultat.
+pub fn appliquer_regles_messages(regles: &Vec<RegleMessage>, metadonnees: &MetadonneesReception) -> ResultatReglesMessage {
+    let mut resultat = ResultatReglesMessage::default();
+    for regle in regles {
+        if regle.actif == Some(false) || ! regle.conditions.correspond(metadonnees) {
+            continue
+        }
+        debug!("appliquer_regles_messages Regle {:?} appliquee", regle.nom);
+
+        let actions = &regle.actions;
+        if let Some(labels) = actions.labels.as_ref() {
+            for label in labels {
+                if ! resultat.labels.contains(label) {
+                    resultat.labels.push(label.to_owned());
+                }
+            }
+        }
+        resultat.lu |= actions.lu == Some(true);
+        resultat.archiver |= actions.archiver == Some(true);
+        resultat.etoile |= actions.etoile == Some(true);
+        resultat.supprimer |= actions.supprimer == Some(true);
+        resultat.sans_notification |= actions.sans_notification == Some(true);
+
+        if regle.arreter == Some(true) {
+            break
+        }
+    }
+    resultat
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chiffrage::{CleChiffrageHandler, FormatChiffrage};
+use millegrilles_common_rust::chiffrage_cle::CommandeSauvegarderCle;
+use millegrilles_common_rust::chiffrage_ed25519::chiffrer_asymmetrique_ed25519;
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::common_messages::MessageReponse;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::formatteur_messages::{FormatteurMessage, MessageInterMillegrille, MessageMilleGrille};
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::middleware::{ChiffrageFactoryTrait, sauvegarder_traiter_transaction_serializable};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao, verifier_erreur_duplication_mongo};
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::multibase;
+use millegrilles_common_rust::multibase::Base;
+use millegrilles_common_rust::recepteur_messages::TypeMessage;
+use millegrilles_common_rust::serde::Deserialize;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use millegrilles_common_rust::verificateur::VerificateurMessage;
+
+use crate::constantes::*;
+use crate::gestionnaire::GestionnaireMessagerie;
+use crate::message_structs::*;
+use crate::pompe_messages::get_cle_message;
+
+#[derive(Clone, Debug, Deserialize)]
+struct DocProfilReglesTransfert {
+    user_id: String,
+    regles_transfert: Vec<RegleTransfert>,
+}
+
+/// Applique les regles de transfert des destinataires. Le message original est re-signe par le
+/// domaine sans etre dechiffre : le contenu et la reference de cle (dechiffrage) sont conserves,
+/// la cle est partagee avec la copie transferee via MaitreDesCles. La signature et l'expediteur
+/// d'origine sont conserves dans la provenance (transfert) du message.
+/// Appele apres la sauvegarde de la transaction recevoir, jamais lors d'une regeneration.
+pub async fn traiter_regles_transfert<M>(
+    middleware: &M, gestionnaire: &GestionnaireMessagerie, destinataires: &Vec<DestinataireInfo>,
+    message: &MessageMilleGrille, from: Option<&str>, type_envoi: Option<&str>,
+    fuuids: Option<&Vec<String>>, provenance: Option<&ProvenanceTransfert>
+)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
+{
+    if type_envoi == Some(TYPE_ENVOI_REPONSE_AUTOMATIQUE) {
+        // Ne pas transferer les reponses automatiques
+        return Ok(())
+    }
+
+    // La cle du message est signee avec le message et conservee d'un transfert a l'autre
+    let cle_message = match get_ref_cle_message(message) {
+        Some(inner) => inner,
+        None => {
+            debug!("traiter_regles_transfert Message {} sans information de dechiffrage, aucun transfert", message.id);
+            return Ok(())
+        }
+    };
+
+    let idmg_origine = match message.origine.as_ref() {
+        Some(inner) => inner.to_owned(),
+        None => middleware.idmg().to_owned()
+    };
+
+    let adresses: HashMap<&String, Option<&String>> = destinataires.iter()
+        .filter_map(|d| d.user_id.as_ref().map(|u| (u, d.adresse.as_ref())))
+        .collect();
+    let user_ids: Vec<&String> = adresses.keys().map(|u| *u).collect();
+    if user_ids.is_empty() {
+        return Ok(())
+    }
+
+    let filtre = doc! {
+        CHAMP_USER_ID: {"$in": user_ids},
+        format!("{}.0", CHAMP_REGLES_TRANSFERT): {"$exists": true},
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {CHAMP_USER_ID: 1, CHAMP_REGLES_TRANSFERT: 1})
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let profil: DocProfilReglesTransfert = convertir_bson_deserializable(r?)?;
+        let adresse_usager = match adresses.get(&profil.user_id).and_then(|a| a.map(|a| a.to_owned())) {
+            Some(inner) => inner,
+            None => {
+                debug!("traiter_regles_transfert Adresse inconnue pour usager {}, aucun transfert", profil.user_id);
+                continue
+            }
+        };
+
+        let chaine = preparer_chaine(provenance, adresse_usager.as_str());
+        let cibles = cibles_transfert(&profil.regles_transfert, &chaine, from, idmg_origine.as_str());
+        if cibles.is_empty() {
+            continue
+        }
+
+        // Detection de boucle : un usager ne transfere qu'une seule fois le meme message (cle).
+        // La chaine est fournie par l'expediteur (non signee), elle ne suffit pas.
+        if ! enregistrer_transfert(middleware, profil.user_id.as_str(), cle_message).await? {
+            info!("traiter_regles_transfert Message {} deja transfere par usager {}, boucle ignoree", message.id, profil.user_id);
+            continue
+        }
+
+        let provenance_transfert = ProvenanceTransfert {
+            message_id: provenance.map(|p| p.message_id.clone()).unwrap_or_else(|| message.id.clone()),
+            pubkey: provenance.map(|p| p.pubkey.clone()).unwrap_or_else(|| message.pubkey.clone()),
+            signature: provenance.map(|p| p.signature.clone()).unwrap_or_else(|| message.signature.clone()),
+            origine: provenance.map(|p| p.origine.clone()).unwrap_or_else(|| Some(idmg_origine.clone())),
+            from: provenance.map(|p| p.from.clone()).unwrap_or_else(|| from.map(|f| f.to_owned())),
+            chaine,
+        };
+
+        if let Err(e) = transferer(
+            middleware, gestionnaire, profil.user_id.as_str(), message, cibles, fuuids, type_envoi, provenance_transfert).await {
+            warn!("traiter_regles_transfert Erreur transfert message {} usager {} : {:?}", message.id, profil.user_id, e);
+            // Permettre un nouveau transfert du message
+            if let Err(e) = retirer_transfert(middleware, profil.user_id.as_str(), cle_message).await {
+                warn!("traiter_regles_transfert Erreur retrait transfert message {} : {:?}", message.id, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_ref_cle_message(message: &MessageMilleGrille) -> Option<&str> {
+    let dechiffrage = message.dechiffrage.as_ref()?;
+    match dechiffrage.hachage.as_ref() {
+        Some(inner) => Some(inner.as_str()),
+        None => dechiffrage.cle_id.as_ref().map(|c| c.as_str())  // Fallback, cle_id
+    }
+}
+
+/// Conserve le transfert d'un message (cle) par un usager. Retourne false si le message a deja
+/// ete transfere par cet usager.
+async fn enregistrer_transfert<M>(middleware: &M, user_id: &str, cle_message: &str)
+    -> Result<bool, Box<dyn Error>>
+    where M: MongoDao
+{
+    let document = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_CLE_MESSAGE: cle_message,
+        CHAMP_CREATION: Utc::now(),
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_TRANSFERTS)?;
+    match collection.insert_one(document, None).await {
+        Ok(_) => Ok(true),
+        Err(e) => match verifier_erreur_duplication_mongo(&*e.kind) {
+            true => Ok(false),
+            false => Err(e)?
+        }
+    }
+}
+
+async fn retirer_transfert<M>(middleware: &M, user_id: &str, cle_message: &str)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_CLE_MESSAGE: cle_message };
+    let collection = middleware.get_collection(NOM_COLLECTION_TRANSFERTS)?;
+    collection.delete_one(filtre, None).await?;
+    Ok(())
+}
+
+/// Retourne true si le message n'a ete livre a aucun destinataire (aucun code 2xx).
+pub fn livraison_echouee(destinataires: &HashMap<String, i32>) -> bool {
+    ! destinataires.is_empty() && destinataires.values().all(|code| ! (200..300).contains(code))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DocOutgoingTransfert {
+    user_id: Option<String>,
+    message: MessageMilleGrille,
+    transfert: Option<ProvenanceTransfert>,
+}
+
+/// Libere le transfert d'un message transfere dont la livraison a echoue (pompe). Le message
+/// pourra etre transfere de nouveau a la prochaine reception.
+pub async fn liberer_transfert_echec<M>(middleware: &M, message_id: &str)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { "message.id": message_id, "transfert": {"$ne": null} };
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+    let doc_outgoing: DocOutgoingTransfert = match collection.find_one(filtre, None).await? {
+        Some(d) => convertir_bson_deserializable(d)?,
+        None => return Ok(())  // Pas un message transfere
+    };
+    if doc_outgoing.transfert.is_none() {
+        return Ok(())
+    }
+    let user_id = match doc_outgoing.user_id.as_ref() {
+        Some(inner) => inner,
+        None => Err(format!("regles_transfert.liberer_transfert_echec Message transfere {} sans user_id", message_id))?
+    };
+    // La copie transferee conserve la reference de cle du message original
+    if let Some(cle_message) = get_ref_cle_message(&doc_outgoing.message) {
+        info!("liberer_transfert_echec Transfert message {} par usager {} echoue, transfert libere", message_id, user_id);
+        retirer_transfert(middleware, user_id.as_str(), cle_message).await?;
+    }
+    Ok(())
+}
+
+/// Chaine de transfert incluant l'adresse de l'usager courant.
+fn preparer_chaine(provenance: Option<&ProvenanceTransfert>, adresse_usager: &str) -> Vec<String> {
+    let mut chaine = match provenance {
+        Some(p) => p.chaine.clone(),
+        None => Vec::new()
+    };
+    chaine.push(adresse_usager.to_owned());
+    chaine
+}
+
+/// Retourne les adresses cibles des regles qui correspondent au message. Les cibles deja
+/// presentes dans la chaine de transfert sont retirees. La chaine n'est pas signee, elle sert
+/// uniquement a eviter un transfert inutile (voir enregistrer_transfert pour les boucles).
+fn cibles_transfert(regles: &Vec<RegleTransfert>, chaine: &Vec<String>, from: Option<&str>, idmg: &str) -> Vec<String> {
+    if chaine.len() > CONST_TRANSFERTS_MAX {
+        warn!("cibles_transfert Nombre maximal de transferts atteint ({:?}), aucun transfert", chaine);
+        return Vec::new()
+    }
+
+    let mut cibles: Vec<String> = Vec::new();
+    for regle in regles {
+        if ! regle.correspond(from, idmg) {
+            continue
+        }
+        let cible = &regle.adresse_cible;
+        if chaine.contains(cible) || from == Some(cible.as_str()) {
+            info!("cibles_transfert Boucle de transfert detectee vers {}, ignore", cible);
+            continue
+        }
+        if ! cibles.contains(cible) {
+            cibles.push(cible.to_owned());
+        }
+    }
+    cibles
+}
+
+async fn transferer<M>(
+    middleware: &M, gestionnaire: &GestionnaireMessagerie, user_id: &str, message: &MessageMilleGrille, cibles: Vec<String>,
+    fuuids: Option<&Vec<String>>, type_envoi: Option<&str>, provenance: ProvenanceTransfert
+)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage + ChiffrageFactoryTrait
+{
+    // Re-signer le message (nouvel id) avec le certificat du domaine
+    let message_inter = MessageInterMillegrille::try_from(message.clone())?;
+    let enveloppe_privee = middleware.get_enveloppe_signature();
+    let message_signe = MessageMilleGrille::new_signer(
+        enveloppe_privee.as_ref(), MessageKind::CommandeInterMillegrille, &message_inter,
+        Some(DOMAINE_NOM), Some(TRANSACTION_POSTER), None::<&str>, None::<i32>, true)?;
+
+    partager_cle_transfert(middleware, message, message_signe.id.as_str()).await?;
+
+    info!("regles_transfert.transferer Transfert message {} (original {}) vers {:?}",
+        message_signe.id, provenance.message_id, cibles);
+    let commande = CommandePoster {
+        message: message_signe,
+        destinataires: cibles,
+        bcc: None,
+        fuuids: fuuids.map(|f| f.to_owned()),
+        from: provenance.from.clone(),
+        type_envoi: type_envoi.map(|t| t.to_owned()),
+        transfert: Some(provenance),
+        thread: None,
+        user_id: Some(user_id.to_owned()),
+    };
+    sauvegarder_traiter_transaction_serializable(
+        middleware, &commande, gestionnaire, DOMAINE_NOM, TRANSACTION_POSTER).await?;
+
+    Ok(())
+}
+
+/// Partage la cle du message avec la copie transferee. La cle est chargee aupres de MaitreDesCles
+/// (permission du domaine) et sauvegardee pour le message transfere. Le transfert est annule si
+/// la cle n'est pas disponible, la copie serait illisible pour les cibles.
+async fn partager_cle_transfert<M>(middleware: &M, message: &MessageMilleGrille, message_id_transfert: &str)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + ChiffrageFactoryTrait
+{
+    let dechiffrage = match message.dechiffrage.as_ref() {
+        Some(inner) => inner,
+        None => Err(format!("regles_transfert.partager_cle_transfert Message {} sans information de dechiffrage", message.id))?
+    };
+    let hachage_bytes = match get_ref_cle_message(message) {
+        Some(inner) => inner,
+        None => Err(format!("regles_transfert.partager_cle_transfert Message {} sans reference de cle", message.id))?
+    };
+
+    let cle_secrete = get_cle_message(middleware, hachage_bytes).await?;
+
+    // Chiffrer la cle secrete pour les maitre des cles locaux
+    let mut cles = HashMap::new();
+    for k in middleware.get_publickeys_chiffrage() {
+        let cle_chiffree = chiffrer_asymmetrique_ed25519(&cle_secrete.0[..], &k.public_key)?;
+        let cle_str: String = multibase::encode(Base::Base64, &cle_chiffree[..]);
+        cles.insert(k.fingerprint, cle_str);
+    }
+
+    let mut identificateurs_document = HashMap::new();
+    identificateurs_document.insert("message".to_string(), "true".to_string());
+    identificateurs_document.insert("message_id".to_string(), message_id_transfert.to_string());
+
+    let commande = CommandeSauvegarderCle {
+        hachage_bytes: hachage_bytes.to_owned(),
+        domaine: DOMAINE_NOM.into(),
+        identificateurs_document,
+        cles,
+        format: FormatChiffrage::try_from(dechiffrage.format.as_str())?,
+        iv: None,
+        tag: None,
+        header: dechiffrage.header.clone(),
+        partition: None,
+        fingerprint_partitions: None,
+    };
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, COMMANDE_SAUVEGARDER_CLE)
+        .exchanges(vec![Securite::L4Secure])
+        .build();
+    match middleware.transmettre_commande(routage, &commande, true).await? {
+        Some(TypeMessage::Valide(inner)) => {
+            let reponse: MessageReponse = inner.message.parsed.map_contenu()?;
+            if reponse.ok != Some(true) {
+                Err(format!("regles_transfert.partager_cle_transfert Erreur sauvegarde cle (ok==false) pour transfert {}", message_id_transfert))?
+            }
+        },
+        _ => Err(format!("regles_transfert.partager_cle_transfert Aucune reponse de MaitreDesCles pour transfert {}", message_id_transfert))?
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_regles_transfert {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    #[test]
+    fn test_livraison_echouee() {
+        setup("test_livraison_echouee");
+        let mut destinataires = HashMap::new();
+        assert!(! livraison_echouee(&destinataires));
+
+        destinataires.insert("@cible1/millegrille1.com".to_string(), 404);
+        destinataires.insert("@cible2/millegrille2.com".to_string(), CODE_DNS_ECHEC);
+        assert!(livraison_echouee(&destinataires));
+
+        destinataires.insert("@cible3/millegrille1.com".to_string(), 201);
+        assert!(! livraison_echouee(&destinataires));
+    }
+
+    #[test]
+    fn test_cibles_transfert_boucle() {
+        setup("test_cibles_transfert_boucle");
+        let regles = vec![
+            RegleTransfert { adresse_cible: "@b/millegrille.com".into(), expediteurs: None, idmgs: None },
+            RegleTransfert { adresse_cible: "@c/millegrille.com".into(), expediteurs: None, idmgs: None },
+        ];
+        let chaine = vec!["@b/millegrille.com".to_string(), "@a/millegrille.com".to_string()];
+        let cibles = cibles_transfert(&regles, &chaine, Some("@x/millegrille.com"), "zIdmg");
+        assert_eq!(vec!["@c/millegrille.com".to_string()], cibles);
+
+        // Chaine trop longue, aucun transfert
+        let chaine = (0..=CONST_TRANSFERTS_MAX).map(|i| format!("@u{}/millegrille.com", i)).collect();
+        assert!(cibles_transfert(&regles, &chaine, None, "zIdmg").is_empty());
+    }
+}
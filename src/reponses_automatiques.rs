@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::formatteur_messages::{FormatteurMessage, MessageInterMillegrille, MessageMilleGrille};
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serializable;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::serde::Deserialize;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use millegrilles_common_rust::verificateur::VerificateurMessage;
+
+use crate::constantes::*;
+use crate::gestionnaire::GestionnaireMessagerie;
+use crate::message_structs::*;
+
+/// Intervalle par defaut entre deux reponses au meme expediteur (jours).
+const INTERVALLE_REPONSE_DEFAUT_JOURS: i64 = 7;
+
+#[derive(Clone, Debug, Deserialize)]
+struct DocProfilReponseAutomatique {
+    user_id: String,
+    reponse_automatique: ConfigurationReponseAutomatique,
+}
+
+/// Retourne true si le message recu peut recevoir une reponse automatique. Les envois
+/// automatises (type_envoi), les messages systeme (certificat sans user_id, incluant les
+/// reponses automatiques signees par le domaine) et les messages sans expediteur declare
+/// sont ignores.
+fn message_admissible(certificat_usager: bool, from: Option<&str>, type_envoi: Option<&str>) -> bool {
+    certificat_usager && type_envoi.is_none() && from.is_some()
+}
+
+/// Emet les reponses automatiques des destinataires qui ont un repondeur actif. Appele apres la
+/// sauvegarde de la transaction recevoir (jamais lors d'une regeneration), les destinataires
+/// sont ceux qui ont recu le message dans leur boite (hors quarantaine et messages supprimes).
+pub async fn traiter_reponses_automatiques<M>(
+    middleware: &M, gestionnaire: &GestionnaireMessagerie, destinataires: &Vec<DestinataireInfo>,
+    message_id: &str, certificat_usager: bool, from: Option<&str>, type_envoi: Option<&str>
+)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    if ! message_admissible(certificat_usager, from, type_envoi) {
+        debug!("traiter_reponses_automatiques Message {} non admissible pour reponse automatique", message_id);
+        return Ok(())
+    }
+    let expediteur = match from {
+        Some(inner) => inner,
+        None => return Ok(())
+    };
+
+    let now = Utc::now();
+
+    let adresses: HashMap<&String, Option<&String>> = destinataires.iter()
+        .filter_map(|d| d.user_id.as_ref().map(|u| (u, d.adresse.as_ref())))
+        .collect();
+    let user_ids: Vec<&String> = adresses.keys().map(|u| *u).collect();
+    if user_ids.is_empty() {
+        return Ok(())
+    }
+
+    let filtre = doc! {
+        CHAMP_USER_ID: {"$in": user_ids},
+        format!("{}.actif", CHAMP_REPONSE_AUTOMATIQUE): true,
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {CHAMP_USER_ID: 1, CHAMP_REPONSE_AUTOMATIQUE: 1})
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let profil: DocProfilReponseAutomatique = convertir_bson_deserializable(r?)?;
+        let adresse_usager = adresses.get(&profil.user_id).and_then(|a| a.map(|a| a.to_owned()));
+        if let Err(e) = repondre(middleware, gestionnaire, &profil, adresse_usager, expediteur, &now).await {
+            warn!("traiter_reponses_automatiques Erreur reponse automatique usager {} : {:?}", profil.user_id, e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn repondre<M>(
+    middleware: &M, gestionnaire: &GestionnaireMessagerie, profil: &DocProfilReponseAutomatique,
+    adresse_usager: Option<String>, expediteur: &str, now: &DateTime<Utc>
+)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + ValidateurX509 + VerificateurMessage
+{
+    let config = &profil.reponse_automatique;
+    let user_id = profil.user_id.as_str();
+
+    // Verifier la periode active
+    let ts_now = now.timestamp();
+    if let Some(debut) = config.debut {
+        if ts_now < debut { return Ok(()) }
+    }
+    if let Some(fin) = config.fin {
+        if ts_now > fin { return Ok(()) }
+    }
+
+    let modele = match config.message.as_ref() {
+        Some(inner) => inner,
+        None => Err(format!("reponses_automatiques.repondre Message modele absent pour usager {}", user_id))?
+    };
+
+    // Une seule reponse par expediteur par intervalle
+    let intervalle = Duration::days(config.intervalle_jours.unwrap_or(INTERVALLE_REPONSE_DEFAUT_JOURS));
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_ADRESSE: expediteur,
+        "derniere_reponse": {"$gt": *now - intervalle},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_REPONSES_AUTOMATIQUES)?;
+    if collection.count_documents(filtre, None).await? > 0 {
+        debug!("reponses_automatiques.repondre Reponse deja emise a {} pour usager {}", expediteur, user_id);
+        return Ok(())
+    }
+
+    // Re-signer le message modele (nouvel id) avec le certificat du domaine
+    let message_inter = MessageInterMillegrille::try_from(modele.clone())?;
+    let enveloppe_privee = middleware.get_enveloppe_signature();
+    let message_signe = MessageMilleGrille::new_signer(
+        enveloppe_privee.as_ref(), MessageKind::CommandeInterMillegrille, &message_inter,
+        Some(DOMAINE_NOM), Some(TYPE_ENVOI_REPONSE_AUTOMATIQUE), None::<&str>, None::<i32>, true)?;
+
+    info!("reponses_automatiques.repondre Reponse automatique de {} vers {}", user_id, expediteur);
+    let commande = CommandePoster {
+        message: message_signe,
+        destinataires: vec![expediteur.to_owned()],
+        bcc: None,
+        fuuids: None,
+        from: adresse_usager,
+        type_envoi: Some(TYPE_ENVOI_REPONSE_AUTOMATIQUE.to_string()),
+        transfert: None,
+        thread: None,
+        user_id: Some(user_id.to_owned()),
+    };
+    sauvegarder_traiter_transaction_serializable(
+        middleware, &commande, gestionnaire, DOMAINE_NOM, TRANSACTION_POSTER).await?;
+
+    // Conserver la date de reponse uniquement lorsque la reponse est postee
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_ADRESSE: expediteur };
+    let ops = doc! {
+        "$set": {"derniere_reponse": now},
+        "$setOnInsert": {CHAMP_CREATION: now},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, Some(options)).await?;
+
+    Ok(())
+}
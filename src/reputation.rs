@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use log::{debug, info};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::Document;
+use millegrilles_common_rust::chrono::{DateTime, TimeZone, Utc};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+use crate::politique_federation::charger_politique_federation;
+
+pub const COMPTEUR_RECUS: &str = "recus";
+pub const COMPTEUR_ENVOYES: &str = "envoyes";
+pub const COMPTEUR_ABUS: &str = "abus";
+pub const COMPTEUR_BLOQUES: &str = "bloques";
+pub const COMPTEUR_ECHECS_ENTRANTS: &str = "echecs_entrants";
+pub const COMPTEUR_ECHECS_SORTANTS: &str = "echecs_sortants";
+pub const COMPTEUR_ANOMALIES_CERTIFICAT: &str = "anomalies_certificat";
+
+/// Demi-vie des compteurs. Un evenement compte pour moitie apres cette periode.
+const DEMI_VIE_JOURS: f64 = 30.0;
+/// Date de reference (2023-01-01) des compteurs ponderes. Un increment est conserve multiplie par
+/// 2^(age/demi-vie) depuis cette date, ce qui permet un $inc atomique. La decroissance est
+/// appliquee a la lecture (valeur conservee * facteur_decroissance(age)).
+const REFERENCE_DECROISSANCE_SECONDES: i64 = 1672531200;
+
+/// Penalites du score (sur 100).
+const PENALITE_BLOQUES: f64 = 50.0;
+const PENALITE_ECHECS_ENTRANTS: f64 = 20.0;
+const PENALITE_ECHECS_SORTANTS: f64 = 15.0;
+const PENALITE_PAR_ABUS: f64 = 5.0;
+const PENALITE_PAR_ANOMALIE_CERTIFICAT: f64 = 10.0;
+
+fn facteur_decroissance(secondes: f64) -> f64 {
+    let demi_vie = DEMI_VIE_JOURS * 86400.0;
+    (0.5 as f64).powf(f64::max(secondes, 0.0) / demi_vie)
+}
+
+/// Secondes ecoulees depuis la date de reference des compteurs ponderes.
+fn age_reference(date: &DateTime<Utc>) -> f64 {
+    (*date - Utc.timestamp(REFERENCE_DECROISSANCE_SECONDES, 0)).num_seconds() as f64
+}
+
+/// Poids d'un increment a la date (inverse de la decroissance depuis la reference).
+fn poids_increment(date: &DateTime<Utc>) -> f64 {
+    1.0 / facteur_decroissance(age_reference(date))
+}
+
+fn appliquer_decroissance(compteurs: &mut CompteursReputation, facteur: f64) {
+    compteurs.recus *= facteur;
+    compteurs.envoyes *= facteur;
+    compteurs.abus *= facteur;
+    compteurs.bloques *= facteur;
+    compteurs.echecs_entrants *= facteur;
+    compteurs.echecs_sortants *= facteur;
+    compteurs.anomalies_certificat *= facteur;
+}
+
+fn compteur_connu(compteur: &str) -> bool {
+    match compteur {
+        COMPTEUR_RECUS | COMPTEUR_ENVOYES | COMPTEUR_ABUS | COMPTEUR_BLOQUES |
+        COMPTEUR_ECHECS_ENTRANTS | COMPTEUR_ECHECS_SORTANTS | COMPTEUR_ANOMALIES_CERTIFICAT => true,
+        _ => false
+    }
+}
+
+/// Score de 0 (mauvaise reputation) a 100. Les taux (bloques, echecs) sont relatifs au volume,
+/// les abus et anomalies de certificat sont penalises a l'unite.
+pub fn calculer_score(compteurs: &CompteursReputation) -> f64 {
+    let recus = f64::max(compteurs.recus, 1.0);
+    let transmissions = compteurs.envoyes + compteurs.echecs_sortants;
+
+    let mut score = 100.0;
+    score -= PENALITE_BLOQUES * f64::min(compteurs.bloques / recus, 1.0);
+    score -= PENALITE_ECHECS_ENTRANTS * f64::min(compteurs.echecs_entrants / recus, 1.0);
+    if transmissions > 0.0 {
+        score -= PENALITE_ECHECS_SORTANTS * (compteurs.echecs_sortants / transmissions);
+    }
+    score -= PENALITE_PAR_ABUS * compteurs.abus;
+    score -= PENALITE_PAR_ANOMALIE_CERTIFICAT * compteurs.anomalies_certificat;
+
+    f64::max(score, 0.0)
+}
+
+/// Met a jour les compteurs de reputation d'une millegrille tierce (jamais la millegrille locale).
+/// Les increments sont ponderes ($inc atomique), la decroissance est appliquee a la lecture.
+pub async fn maj_reputation<M>(middleware: &M, idmg: &str, increments: &[(&str, f64)])
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let now = Utc::now();
+    let poids = poids_increment(&now);
+    let filtre = doc! { "idmg": idmg };
+    let collection = middleware.get_collection(NOM_COLLECTION_REPUTATION)?;
+
+    let mut ops_inc = Document::new();
+    for (compteur, valeur) in increments {
+        if ! compteur_connu(compteur) {
+            debug!("maj_reputation Compteur de reputation inconnu : {}", compteur);
+            continue
+        }
+        ops_inc.insert(format!("compteurs.{}", compteur), *valeur * poids);
+    }
+
+    let ops = doc! {
+        "$inc": ops_inc,
+        "$set": {"derniere_maj": now},
+        "$setOnInsert": {CHAMP_CREATION: now},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let doc_reputation = match collection.find_one_and_update(filtre.clone(), ops, Some(options)).await? {
+        Some(d) => d,
+        None => Err(format!("reputation.maj_reputation Document reputation {} absent apres upsert", idmg))?
+    };
+
+    // Le score conserve sert au tri, il est recalcule a chaque lecture
+    let mut doc_reputation: DocReputationMillegrille = convertir_bson_deserializable(doc_reputation)?;
+    actualiser_reputation(&mut doc_reputation);
+    debug!("maj_reputation Idmg {} compteurs {:?} score {}", idmg, doc_reputation.compteurs, doc_reputation.score);
+    collection.update_one(filtre, doc! {"$set": {"score": doc_reputation.score}}, None).await?;
+
+    Ok(())
+}
+
+/// Enregistre une reception de la millegrille tierce avec le code de chaque destinataire.
+pub async fn enregistrer_reception_reputation<M>(middleware: &M, idmg: &str, codes: &HashMap<String, u32>)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let bloques = codes.values().filter(|c| **c as i32 == CODE_DESTINATAIRE_REFUSE).count() as f64;
+    let echecs = codes.values()
+        .filter(|c| match **c as i32 { 404 | CODE_QUOTA_DEPASSE => true, _ => false })
+        .count() as f64;
+    let total = f64::max(codes.len() as f64, 1.0);
+
+    // Les taux sont ramenes a un message recu
+    maj_reputation(middleware, idmg, &[
+        (COMPTEUR_RECUS, 1.0),
+        (COMPTEUR_BLOQUES, bloques / total),
+        (COMPTEUR_ECHECS_ENTRANTS, echecs / total),
+    ]).await
+}
+
+/// Charge la reputation d'une millegrille avec la decroissance appliquee a la date courante.
+pub async fn charger_reputation<M>(middleware: &M, idmg: &str)
+    -> Result<Option<DocReputationMillegrille>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_REPUTATION)?;
+    match collection.find_one(doc! {"idmg": idmg}, None).await? {
+        Some(d) => {
+            let mut doc_reputation: DocReputationMillegrille = convertir_bson_deserializable(d)?;
+            actualiser_reputation(&mut doc_reputation);
+            Ok(Some(doc_reputation))
+        },
+        None => Ok(None)
+    }
+}
+
+/// Convertit les compteurs ponderes conserves en compteurs a la date courante.
+fn actualiser_reputation(doc_reputation: &mut DocReputationMillegrille) {
+    let age = age_reference(&Utc::now());
+    appliquer_decroissance(&mut doc_reputation.compteurs, facteur_decroissance(age));
+    doc_reputation.score = calculer_score(&doc_reputation.compteurs);
+}
+
+/// Retourne true si la reputation de la millegrille est sous le seuil de quarantaine de la
+/// politique de federation.
+pub async fn verifier_quarantaine_reputation<M>(middleware: &M, idmg: &str) -> Result<bool, Box<dyn Error>>
+    where M: MongoDao
+{
+    let politique = charger_politique_federation(middleware).await?;
+    let seuil = match politique.seuil_quarantaine_reputation {
+        Some(inner) => inner,
+        None => return Ok(false)
+    };
+    match charger_reputation(middleware, idmg).await? {
+        Some(reputation) => {
+            let quarantaine = reputation.score < seuil;
+            if quarantaine {
+                info!("verifier_quarantaine_reputation Idmg {} score {} sous le seuil {}, quarantaine", idmg, reputation.score, seuil);
+            }
+            Ok(quarantaine)
+        },
+        None => Ok(false)
+    }
+}
+
+/// Liste les reputations, pires scores en premier.
+pub async fn charger_reputations<M>(middleware: &M, idmg: Option<&str>, limit: i64)
+    -> Result<Vec<DocReputationMillegrille>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = match idmg {
+        Some(i) => doc! { "idmg": i },
+        None => doc! {}
+    };
+    let options = FindOptions::builder()
+        .sort(doc! {"score": 1})
+        .limit(limit)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_REPUTATION)?;
+
+    let mut reputations = Vec::new();
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let mut doc_reputation: DocReputationMillegrille = convertir_bson_deserializable(r?)?;
+        actualiser_reputation(&mut doc_reputation);
+        reputations.push(doc_reputation);
+    }
+
+    Ok(reputations)
+}
+
+#[cfg(test)]
+mod test_reputation {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    const JOUR_SECONDES: f64 = 86400.0;
+
+    #[test]
+    fn test_facteur_decroissance() {
+        setup("test_facteur_decroissance");
+        assert_eq!(1.0, facteur_decroissance(0.0));
+        assert_eq!(0.5, facteur_decroissance(DEMI_VIE_JOURS * JOUR_SECONDES));
+        assert_eq!(0.25, facteur_decroissance(2.0 * DEMI_VIE_JOURS * JOUR_SECONDES));
+        // Age negatif (horloge), aucune decroissance
+        assert_eq!(1.0, facteur_decroissance(-JOUR_SECONDES));
+    }
+
+    #[test]
+    fn test_calculer_score_sans_evenement() {
+        setup("test_calculer_score_sans_evenement");
+        assert_eq!(100.0, calculer_score(&CompteursReputation::default()));
+    }
+
+    #[test]
+    fn test_calculer_score_taux() {
+        setup("test_calculer_score_taux");
+        let compteurs = CompteursReputation { recus: 10.0, bloques: 5.0, ..Default::default() };
+        assert_eq!(75.0, calculer_score(&compteurs));
+
+        let compteurs = CompteursReputation { envoyes: 3.0, echecs_sortants: 1.0, ..Default::default() };
+        assert_eq!(96.25, calculer_score(&compteurs));
+
+        // Le taux est plafonne a 100%
+        let compteurs = CompteursReputation { recus: 1.0, echecs_entrants: 5.0, ..Default::default() };
+        assert_eq!(80.0, calculer_score(&compteurs));
+    }
+
+    #[test]
+    fn test_calculer_score_penalites_unitaires() {
+        setup("test_calculer_score_penalites_unitaires");
+        let compteurs = CompteursReputation { abus: 2.0, ..Default::default() };
+        assert_eq!(90.0, calculer_score(&compteurs));
+
+        // Le score ne descend pas sous 0
+        let compteurs = CompteursReputation { anomalies_certificat: 20.0, ..Default::default() };
+        assert_eq!(0.0, calculer_score(&compteurs));
+    }
+}
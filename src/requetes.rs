@@ -30,6 +30,13 @@ use crate::gestionnaire::GestionnaireMessagerie;
 use crate::constantes::*;
 use crate::transactions::*;
 use crate::message_structs::*;
+use crate::conversations::charger_conversations;
+use crate::labels::charger_labels;
+use crate::limites_reception::charger_sources_reception;
+use crate::usage_boites::{calculer_usage_boite, charger_configuration_quotas, charger_usage_boite, compter_etoiles};
+use crate::reputation::charger_reputations;
+use crate::signalements::charger_signalements_externes;
+use crate::certificats_messages::charger_certificat_message;
 
 pub async fn consommer_requete<M>(middleware: &M, message: MessageValideAction, gestionnaire: &GestionnaireMessagerie) -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: ValidateurX509 + GenerateurMessages + MongoDao + VerificateurMessage
@@ -65,6 +72,12 @@ pub async fn consommer_requete<M>(middleware: &M, message: MessageValideAction,
                 REQUETE_GET_CLES_STREAM => requete_get_cles_stream(middleware, message, gestionnaire).await,
                 REQUETE_GET_CONFIGURATION_NOTIFICATIONS => requete_get_configuration_notifications(middleware, message, gestionnaire).await,
                 REQUETE_GET_CLEPUBLIQUE_WEBPUSH => requete_get_clepublique_webpush(middleware, message, gestionnaire).await,
+                REQUETE_GET_SOURCES_RECEPTION => requete_get_sources_reception(middleware, message).await,
+                REQUETE_GET_USAGE_BOITE => requete_get_usage_boite(middleware, message).await,
+                REQUETE_GET_REPUTATIONS => requete_get_reputations(middleware, message).await,
+                REQUETE_GET_SIGNALEMENTS => requete_get_signalements(middleware, message).await,
+                REQUETE_GET_LABELS => requete_get_labels(middleware, message).await,
+                REQUETE_GET_CONVERSATIONS => requete_get_conversations(middleware, message).await,
                 _ => {
                     error!("Message requete/action inconnue : '{}'. Message dropped.", message.action);
                     Ok(None)
@@ -124,10 +137,46 @@ async fn requete_get_messages<M>(middleware: &M, m: MessageValideAction, gestion
     //     filtre.insert("uuid_transaction", doc!{"$in": um});
     // }
 
+    // Les messages demandes par id sont retournes peu importe leur boite (quarantaine, archive)
+    // sauf si le filtre est explicitement fourni.
+    let par_message_ids = requete.message_ids.is_some();
     if let Some(um) = requete.message_ids {
         filtre.insert("message.id", doc!{"$in": um});
     }
 
+    if let Some(label) = requete.label {
+        filtre.insert(CHAMP_LABELS, label);
+    }
+
+    if let Some(thread) = requete.thread {
+        filtre.insert(CHAMP_THREAD_MESSAGE, thread);
+    }
+
+    match requete.etoile {
+        Some(true) => { filtre.insert(CHAMP_ETOILE, true); },
+        Some(false) => { filtre.insert(CHAMP_ETOILE, doc!{"$ne": true}); },
+        None => ()
+    }
+
+    if ! messages_envoyes {
+        // Les messages en quarantaine (demandes) sont exclus de la boite de reception
+        match requete.quarantaine {
+            Some(true) => { filtre.insert(CHAMP_QUARANTAINE, true); },
+            Some(false) => { filtre.insert(CHAMP_QUARANTAINE, doc!{"$ne": true}); },
+            None => if ! par_message_ids { filtre.insert(CHAMP_QUARANTAINE, doc!{"$ne": true}); }
+        }
+
+        match requete.boite.as_ref().map(|b| b.as_str()) {
+            None if par_message_ids => (),
+            None | Some(BOITE_RECEPTION) => { filtre.insert(CHAMP_ARCHIVE, doc!{"$ne": true}); },
+            Some(BOITE_ARCHIVE) => { filtre.insert(CHAMP_ARCHIVE, true); },
+            Some(BOITE_TOUS) => (),
+            Some(b) => {
+                return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "err": format!("Boite inconnue : {}", b)}), None)?))
+            }
+        }
+    }
+
     debug!("requete_get_messages Filtre {:?}", filtre);
 
     let collection = middleware.get_collection(nom_collection)?;
@@ -159,6 +208,9 @@ async fn requete_get_messages_attachments<M>(middleware: &M, m: MessageValideAct
         .build();
     let mut filtre = doc!{CHAMP_USER_ID: user_id};
 
+    // Les messages demandes par id sont retournes peu importe leur boite (quarantaine, archive)
+    // sauf si le filtre est explicitement fourni.
+    let par_message_ids = requete.message_ids.is_some();
     if let Some(um) = requete.message_ids {
         filtre.insert("message.id", doc!{"$in": um});
     }
@@ -240,7 +292,7 @@ async fn requete_get_reference_messages<M>(middleware: &M, m: MessageValideActio
 }
 
 async fn mapper_messages_curseur<M>(middleware: &M, mut curseur: Cursor<Document>, type_envoi: bool) -> Result<Value, Box<dyn Error>>
-    where M: ValidateurX509
+    where M: ValidateurX509 + MongoDao
 {
 
     let messages_value = match type_envoi {
@@ -259,21 +311,18 @@ async fn mapper_messages_curseur<M>(middleware: &M, mut curseur: Cursor<Document
             while let Some(fresult) = curseur.next().await {
                 let fcurseur = fresult?;
                 let mut message_db: MessageIncomingClient = convertir_bson_deserializable(fcurseur)?;
-                match middleware.get_certificat(message_db.message.pubkey.as_str()).await {
+                match charger_certificat_message(middleware, message_db.message.pubkey.as_str()).await? {
                     Some(inner) => {
-
-                        // Verifier si le certificat est inter-millegrille
-                        if let Some(ca) = inner.get_pem_ca()? {
-                            message_db.millegrille = Some(ca);
-                        }
-
-                        message_db.certificat = Some(inner.get_pem_vec_extracted());
-                        messages_mappes.push(message_db);
+                        // Certificat CA si le certificat est inter-millegrille
+                        message_db.millegrille = inner.millegrille;
+                        message_db.certificat = Some(inner.certificat);
                     },
                     None => {
                         debug!("mapper_messages_curseur Certificat absent pour message {}", message_db.message.id);
+                        message_db.certificat_indisponible = Some(true);
                     }
                 }
+                messages_mappes.push(message_db);
             }
 
             // Convertir fichiers en Value (serde pour reponse json)
@@ -877,4 +926,163 @@ async fn requete_get_clepublique_webpush<M>(middleware: &M, m: MessageValideActi
     };
 
     Ok(Some(reponse))
-}
\ No newline at end of file
+}
+
+async fn requete_get_sources_reception<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_sources_reception Message : {:?}", &m.message);
+    let requete: RequeteGetSourcesReception = m.message.get_msg().map_contenu()?;
+    debug!("requete_get_sources_reception parsed : {:?}", requete);
+
+    // Autorisation : 3.protege ou delegation globale (administrateur)
+    if m.verifier_exchanges(vec![Securite::L3Protege]) {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    }
+
+    let limit = match requete.limit {
+        Some(l) => l,
+        None => 25
+    };
+    let type_source = requete.type_source.as_ref().map(|t| t.as_str());
+
+    let sources = charger_sources_reception(middleware, type_source, limit).await?;
+    let reponse = json!({"ok": true, "sources": sources});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+async fn requete_get_reputations<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_reputations Message : {:?}", &m.message);
+    let requete: RequeteGetReputations = m.message.get_msg().map_contenu()?;
+    debug!("requete_get_reputations parsed : {:?}", requete);
+
+    // Autorisation : 3.protege ou delegation globale (administrateur)
+    if m.verifier_exchanges(vec![Securite::L3Protege]) {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    }
+
+    let limit = match requete.limit {
+        Some(l) => l,
+        None => 25
+    };
+    let idmg = requete.idmg.as_ref().map(|i| i.as_str());
+
+    let reputations = charger_reputations(middleware, idmg, limit).await?;
+    let reponse = json!({"ok": true, "reputations": reputations});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+async fn requete_get_signalements<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_signalements Message : {:?}", &m.message);
+    let requete: RequeteGetSignalements = m.message.get_msg().map_contenu()?;
+    debug!("requete_get_signalements parsed : {:?}", requete);
+
+    // Autorisation : 3.protege ou delegation globale (administrateur)
+    if m.verifier_exchanges(vec![Securite::L3Protege]) {
+        // Ok
+    } else if m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) {
+        // Ok
+    } else {
+        return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    }
+
+    let limit = match requete.limit {
+        Some(l) => l,
+        None => 25
+    };
+    let skip = match requete.skip {
+        Some(s) => s,
+        None => 0
+    };
+    let idmg = requete.idmg.as_ref().map(|i| i.as_str());
+
+    let signalements = charger_signalements_externes(middleware, idmg, limit, skip).await?;
+    let reponse = json!({"ok": true, "signalements": signalements});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+async fn requete_get_labels<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_labels Message : {:?}", &m.message);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let labels = charger_labels(middleware, user_id.as_str()).await?;
+    let reponse = json!({"ok": true, "labels": labels});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+async fn requete_get_conversations<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_conversations Message : {:?}", &m.message);
+    let requete: RequeteGetConversations = m.message.get_msg().map_contenu()?;
+    debug!("requete_get_conversations parsed : {:?}", requete);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let limit = match requete.limit {
+        Some(l) => l,
+        None => 50
+    };
+    let skip = match requete.skip {
+        Some(s) => s,
+        None => 0
+    };
+
+    let conversations = charger_conversations(middleware, user_id.as_str(), limit, skip).await?;
+    let reponse = json!({"ok": true, "conversations": conversations});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+async fn requete_get_usage_boite<M>(middleware: &M, m: MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao + VerificateurMessage,
+{
+    debug!("requete_get_usage_boite Message : {:?}", &m.message);
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return Ok(Some(middleware.formatter_reponse(json!({"ok": false, "msg": "Access denied"}), None)?))
+    };
+
+    let usage = match charger_usage_boite(middleware, user_id.as_str()).await? {
+        Some(inner) => inner,
+        None => calculer_usage_boite(middleware, user_id.as_str()).await?
+    };
+    let quotas = charger_configuration_quotas(middleware).await?;
+    let (etoiles_recus, etoiles_envoyes) = compter_etoiles(middleware, user_id.as_str()).await?;
+
+    let reponse = json!({
+        "ok": true,
+        "usage": &usage,
+        "taille_totale": usage.taille_totale(),
+        "quotas": &quotas,
+        "etoiles": {"recus": etoiles_recus, "envoyes": etoiles_envoyes},
+    });
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
@@ -0,0 +1,124 @@
+use std::error::Error;
+
+use log::{debug, info};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::constantes::Securite::L1Public;
+use millegrilles_common_rust::formatteur_messages::{FormatteurMessage, MessageMilleGrille};
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::messages_generiques::CommandePostmasterPoster;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+use crate::pompe_messages::get_fiche_application_idmg;
+
+pub const TYPE_SIGNALEMENT_LOCAL: &str = "local";
+pub const TYPE_SIGNALEMENT_EXTERNE: &str = "externe";
+
+/// Taille maximale (bytes) d'un signalement externe, incluant le message signale en preuve.
+pub const TAILLE_MAX_SIGNALEMENT: usize = 256 * 1024;
+/// Destinataire utilise pour la protection anti-rejeu des signalements externes.
+pub const DESTINATAIRE_REJEU_SIGNALEMENT: &str = "signalements";
+
+/// Identifiant deterministe du signalement local d'un message par un usager.
+pub fn get_signalement_id<S, T>(user_id: S, message_id: T) -> String
+    where S: AsRef<str>, T: AsRef<str>
+{
+    format!("{}/{}", user_id.as_ref(), message_id.as_ref())
+}
+
+/// Conserve un signalement. Le signalement_id est unique, la regeneration ne cree pas de doublon.
+pub async fn conserver_signalement<M>(middleware: &M, signalement: &DocSignalement)
+    -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_SIGNALEMENT_ID: &signalement.signalement_id };
+    let ops = doc! {
+        "$setOnInsert": convertir_to_bson(signalement)?,
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_SIGNALEMENTS)?;
+    collection.update_one(filtre, ops, Some(options)).await?;
+    Ok(())
+}
+
+/// Transmet un signalement signe a la millegrille d'origine du message via postmaster.
+pub async fn transmettre_signalement<M>(middleware: &M, idmg: &str, signalement: &SignalementAbus)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let fiche = match get_fiche_application_idmg(middleware, idmg).await? {
+        Some(inner) => inner,
+        None => Err(format!("signalements.transmettre_signalement Aucune fiche messagerie pour idmg {}", idmg))?
+    };
+
+    let enveloppe_privee = middleware.get_enveloppe_signature();
+    let message_signalement = MessageMilleGrille::new_signer(
+        enveloppe_privee.as_ref(), MessageKind::CommandeInterMillegrille, signalement,
+        Some(DOMAINE_NOM), Some(COMMANDE_RECEVOIR_SIGNALEMENT_EXTERNE), None::<&str>, None::<i32>, true)?;
+
+    let contenu_poster = CommandePostmasterPoster {
+        idmg: idmg.to_owned(),
+        message_id: message_signalement.id.clone(),
+        fiche,
+    };
+    let mut commande_postmaster = MessageMilleGrille::new_signer(
+        enveloppe_privee.as_ref(), MessageKind::Commande, &contenu_poster,
+        Some(DOMAINE_POSTMASTER), Some(TRANSACTION_POSTER), None::<&str>, None::<i32>, true)?;
+    commande_postmaster.ajouter_attachement("message", serde_json::to_value(message_signalement)?);
+
+    info!("transmettre_signalement Signalement message {} vers idmg {}", signalement.message_id, idmg);
+    let routage = RoutageMessageAction::builder(DOMAINE_POSTMASTER, TRANSACTION_POSTER)
+        .exchanges(vec![L1Public])
+        .build();
+    middleware.emettre_message_millegrille(
+        routage, true, TypeMessageOut::Commande, commande_postmaster).await?;
+
+    Ok(())
+}
+
+/// Liste les signalements recus de millegrilles tierces, plus recents en premier.
+pub async fn charger_signalements_externes<M>(middleware: &M, idmg: Option<&str>, limit: i64, skip: u64)
+    -> Result<Vec<DocSignalement>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let mut filtre = doc! { "type_signalement": TYPE_SIGNALEMENT_EXTERNE };
+    if let Some(idmg) = idmg {
+        filtre.insert("idmg", idmg);
+    }
+    let options = FindOptions::builder()
+        .sort(doc! {CHAMP_DATE_SIGNALEMENT: -1})
+        .limit(limit)
+        .skip(skip)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_SIGNALEMENTS)?;
+
+    let mut signalements = Vec::new();
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        signalements.push(convertir_bson_deserializable(r?)?);
+    }
+    debug!("charger_signalements_externes {} signalements", signalements.len());
+
+    Ok(signalements)
+}
+
+#[cfg(test)]
+mod test_signalements {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    #[test]
+    fn test_signalement_id() {
+        setup("test_signalement_id");
+        assert_eq!("zUsager1/zMessage1", get_signalement_id("zUsager1", "zMessage1"));
+        assert_ne!(get_signalement_id("zUsager1", "zMessage1"), get_signalement_id("zUsager2", "zMessage1"));
+    }
+}
@@ -17,7 +17,8 @@ use millegrilles_common_rust::formatteur_messages::{DateEpochSeconds, MessageMil
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::middleware::{map_msg_to_bson, map_serializable_to_bson, sauvegarder_traiter_transaction};
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao, verifier_erreur_duplication_mongo};
-use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, Hint, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::mongodb::error::ErrorKind;
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, Hint, InsertManyOptions, ReturnDocument, UpdateOptions};
 use millegrilles_common_rust::recepteur_messages::{MessageValideAction, TypeMessage};
 use millegrilles_common_rust::redis::ToRedisArgs;
 use millegrilles_common_rust::serde::{Deserialize, Serialize};
@@ -27,14 +28,18 @@ use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::verificateur::{ValidationOptions, VerificateurMessage};
 use crate::commandes::recevoir_notification;
 use crate::communs::url_to_mongokey;
+use crate::usage_boites::{DeltaUsageBoite, maj_usage_boites, modifier_incoming_usage};
+use crate::regles_messages::{appliquer_regles_messages, charger_regles_messages};
+use crate::quarantaine::{calculer_index_adresse, charger_configurations_quarantaine, verifier_contact_connu};
+use crate::certificats_messages::conserver_certificat_message;
+use crate::cles_expediteurs::{accepter_cle_expediteur, calculer_index_cle_expediteur, emettre_evenement_cle_changee, verifier_cle_expediteur};
+use crate::signalements::{conserver_signalement, get_signalement_id, TYPE_SIGNALEMENT_LOCAL};
 
 use crate::constantes::*;
 use crate::gestionnaire::GestionnaireMessagerie;
 use crate::message_structs::*;
 use crate::pompe_messages::{emettre_evenement_pompe, marquer_outgoing_resultat, PompeMessages, verifier_message_complete};
 
-const CHAMP_NOTIFICATIONS_ACTIVES: &str = "notifications_actives";
-const CHAMP_DERNIERE_NOTIFICATION: &str = "derniere_notification";
 const CHAMP_WEBPUSH_SUBSCRIPTIONS: &str = "webpush_subscriptions";
 
 pub async fn consommer_transaction<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnaireMessagerie)
@@ -52,8 +57,10 @@ where
         TRANSACTION_INITIALISER_PROFIL |
         TRANSACTION_MAJ_CONTACT |
         TRANSACTION_LU |
+        TRANSACTION_ETOILE |
         TRANSACTION_TRANSFERT_COMPLETE |
         TRANSACTION_SUPPRIMER_MESSAGES |
+        TRANSACTION_RESTAURER_MESSAGES |
         TRANSACTION_SUPPRIMER_CONTACTS |
         TRANSACTION_CONFIRMER_TRANMISSION_MILLEGRILLE |
         TRANSACTION_SAUVEGARDER_CLEWEBPUSH_NOTIFICATIONS |
@@ -61,7 +68,27 @@ where
         TRANSACTION_SAUVEGARDER_SUBSCRIPTION_WEBPUSH |
         TRANSACTION_RETIRER_SUBSCRIPTION_WEBPUSH |
         TRANSACTION_TRANSFERT_FICHIERS_COMPLETES |
-        TRANSACTION_NOTIFIER
+        TRANSACTION_NOTIFIER |
+        TRANSACTION_MAJ_FILTRE_EXPEDITEURS |
+        TRANSACTION_CONSERVER_CONFIGURATION_LIMITES_RECEPTION |
+        TRANSACTION_CONSERVER_CONFIGURATION_QUOTAS |
+        TRANSACTION_CONSERVER_CONFIGURATION_CORBEILLE |
+        TRANSACTION_CONSERVER_POLITIQUE_FEDERATION |
+        TRANSACTION_MAJ_REPONSE_AUTOMATIQUE |
+        TRANSACTION_MAJ_REGLES_TRANSFERT |
+        TRANSACTION_MAJ_REGLES_MESSAGES |
+        TRANSACTION_MAJ_QUARANTAINE |
+        TRANSACTION_TRAITER_QUARANTAINE |
+        TRANSACTION_TRAITER_QUARANTAINE_MESSAGES |
+        TRANSACTION_SIGNALER_MESSAGE |
+        TRANSACTION_ACCEPTER_CLE_EXPEDITEUR |
+        TRANSACTION_MAJ_LABEL |
+        TRANSACTION_SUPPRIMER_LABEL |
+        TRANSACTION_AJOUTER_LABEL_MESSAGES |
+        TRANSACTION_RETIRER_LABEL_MESSAGES |
+        TRANSACTION_ARCHIVER_MESSAGES |
+        TRANSACTION_DESARCHIVER_MESSAGES |
+        TRANSACTION_PURGER_MESSAGES
         => {
             match m.verifier_exchanges(vec![Securite::L4Secure]) {
                 true => Ok(()),
@@ -90,8 +117,10 @@ pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireMessagerie,
         TRANSACTION_INITIALISER_PROFIL => transaction_initialiser_profil(gestionnaire, middleware, transaction).await,
         TRANSACTION_MAJ_CONTACT => transaction_maj_contact(gestionnaire, middleware, transaction).await,
         TRANSACTION_LU => transaction_lu(gestionnaire, middleware, transaction).await,
+        TRANSACTION_ETOILE => transaction_etoile(gestionnaire, middleware, transaction).await,
         TRANSACTION_TRANSFERT_COMPLETE => transfert_complete(gestionnaire, middleware, transaction).await,
         TRANSACTION_SUPPRIMER_MESSAGES => supprimer_message(gestionnaire, middleware, transaction).await,
+        TRANSACTION_RESTAURER_MESSAGES => restaurer_messages(gestionnaire, middleware, transaction).await,
         TRANSACTION_SUPPRIMER_CONTACTS => supprimer_contacts(gestionnaire, middleware, transaction).await,
         TRANSACTION_CONFIRMER_TRANMISSION_MILLEGRILLE => confirmer_transmission_millegrille(gestionnaire, middleware, transaction).await,
         TRANSACTION_CONSERVER_CONFIGURATION_NOTIFICATIONS => conserver_configuration_notifications(gestionnaire, middleware, transaction).await,
@@ -101,6 +130,26 @@ pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireMessagerie,
         TRANSACTION_RETIRER_SUBSCRIPTION_WEBPUSH => retirer_subscription_webpush(gestionnaire, middleware, transaction).await,
         TRANSACTION_TRANSFERT_FICHIERS_COMPLETES => transfert_fichiers_completes(gestionnaire, middleware, transaction).await,
         TRANSACTION_NOTIFIER => conserver_notification(gestionnaire, middleware, transaction).await,
+        TRANSACTION_MAJ_FILTRE_EXPEDITEURS => transaction_maj_filtre_expediteurs(gestionnaire, middleware, transaction).await,
+        TRANSACTION_CONSERVER_CONFIGURATION_LIMITES_RECEPTION => conserver_configuration_limites_reception(gestionnaire, middleware, transaction).await,
+        TRANSACTION_CONSERVER_CONFIGURATION_QUOTAS => conserver_configuration_quotas(gestionnaire, middleware, transaction).await,
+        TRANSACTION_CONSERVER_CONFIGURATION_CORBEILLE => conserver_configuration_corbeille(gestionnaire, middleware, transaction).await,
+        TRANSACTION_CONSERVER_POLITIQUE_FEDERATION => conserver_politique_federation(gestionnaire, middleware, transaction).await,
+        TRANSACTION_MAJ_REPONSE_AUTOMATIQUE => transaction_maj_reponse_automatique(gestionnaire, middleware, transaction).await,
+        TRANSACTION_MAJ_REGLES_TRANSFERT => transaction_maj_regles_transfert(gestionnaire, middleware, transaction).await,
+        TRANSACTION_MAJ_REGLES_MESSAGES => transaction_maj_regles_messages(gestionnaire, middleware, transaction).await,
+        TRANSACTION_MAJ_QUARANTAINE => transaction_maj_quarantaine(gestionnaire, middleware, transaction).await,
+        TRANSACTION_TRAITER_QUARANTAINE => transaction_traiter_quarantaine(gestionnaire, middleware, transaction).await,
+        TRANSACTION_TRAITER_QUARANTAINE_MESSAGES => transaction_traiter_quarantaine_messages(gestionnaire, middleware, transaction).await,
+        TRANSACTION_SIGNALER_MESSAGE => transaction_signaler_message(gestionnaire, middleware, transaction).await,
+        TRANSACTION_ACCEPTER_CLE_EXPEDITEUR => transaction_accepter_cle_expediteur(gestionnaire, middleware, transaction).await,
+        TRANSACTION_MAJ_LABEL => transaction_maj_label(gestionnaire, middleware, transaction).await,
+        TRANSACTION_SUPPRIMER_LABEL => transaction_supprimer_label(gestionnaire, middleware, transaction).await,
+        TRANSACTION_AJOUTER_LABEL_MESSAGES => transaction_label_messages(gestionnaire, middleware, transaction, true).await,
+        TRANSACTION_RETIRER_LABEL_MESSAGES => transaction_label_messages(gestionnaire, middleware, transaction, false).await,
+        TRANSACTION_ARCHIVER_MESSAGES => transaction_archiver_messages(gestionnaire, middleware, transaction, true).await,
+        TRANSACTION_DESARCHIVER_MESSAGES => transaction_archiver_messages(gestionnaire, middleware, transaction, false).await,
+        TRANSACTION_PURGER_MESSAGES => transaction_purger_messages(gestionnaire, middleware, transaction).await,
         _ => Err(format!("core_backup.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.get_uuid_transaction(), action)),
     }
 }
@@ -122,6 +171,7 @@ async fn transaction_poster<M, T>(gestionnaire: &GestionnaireMessagerie, middlew
         Ok(t) => t,
         Err(e) => Err(format!("messagerie.transaction_poster Erreur conversion transaction : {:?}", e))?
     };
+    let user_id = resoudre_user_id_poster(user_id, transaction_poster.user_id.as_ref());
 
     // Utiliser le id du message (dans l'enveloppe poster) comme reference du message
     let message_id = transaction_poster.message.id.clone();
@@ -141,6 +191,17 @@ async fn transaction_poster<M, T>(gestionnaire: &GestionnaireMessagerie, middlew
         // "message_id": &message_id,
         "user_id": user_id.as_ref(),
         "fuuids": &transaction_poster.fuuids,
+        "bcc": &transaction_poster.bcc,
+        "from": &transaction_poster.from,
+        "type_envoi": &transaction_poster.type_envoi,
+        CHAMP_THREAD_MESSAGE: &transaction_poster.thread,
+        "transfert": match transaction_poster.transfert.as_ref() {
+            Some(t) => match convertir_to_bson(t) {
+                Ok(inner) => Some(inner),
+                Err(e) => Err(format!("transactions.transaction_poster Erreur conversion transfert en bson : {:?}", e))?
+            },
+            None => None
+        },
 
         // Flags
         "supprime": false,
@@ -163,7 +224,7 @@ async fn transaction_poster<M, T>(gestionnaire: &GestionnaireMessagerie, middlew
 
     // Ajouter map destinataires
     let mut map_destinataires = Map::new();
-    for dest in &transaction_poster.destinataires {
+    for dest in &transaction_poster.get_destinataires() {
         // Remplacer "." par "," pour supporter acces cles MongoDB
         map_destinataires.insert(dest.replace(".", ","), Value::Null);
     }
@@ -201,6 +262,7 @@ async fn transaction_poster<M, T>(gestionnaire: &GestionnaireMessagerie, middlew
             "destinataire": &dest,
             "user": user,
             "dns": dns_addr,
+            "bcc": transaction_poster.est_bcc(&dest),
             "processed": false,
             "result": None::<&str>,
         };
@@ -333,6 +395,24 @@ async fn traiter_outgoing_resolved<M>(middleware: &M, reponse: &ReponseTopologie
                 "$currentDate": {"last_processed": true},
             };
             collection.update_many(filtre, ops, None).await?;
+
+            // Recuperer les DNS en echec (retry long) qui sont maintenant resolus
+            let cle_dns = url_to_mongokey(dns)?;
+            let filtre = doc! {CHAMP_DNS_FAILURE: {"$all": [dns]}};
+            let ops = doc! {
+                "$set": {
+                    format!("idmgs_mapping.{}.push_count", idmg): 0,
+                    format!("idmgs_mapping.{}.next_push_time", idmg): ts_courant,
+                    format!("{}.{}.resolu", CHAMP_DNS_FAILURE_RETRY, cle_dns): Utc::now(),
+                },
+                "$addToSet": {
+                    format!("idmgs_mapping.{}.dns", idmg): dns,
+                    "idmgs_unprocessed": idmg,
+                },
+                "$pull": {CHAMP_DNS_FAILURE: dns},
+                "$currentDate": {"last_processed": true},
+            };
+            collection.update_many(filtre, ops, None).await?;
         }
     }
 
@@ -381,6 +461,10 @@ async fn transaction_recevoir<M, T>(gestionnaire: &GestionnaireMessagerie, middl
                     },
                     None => ()
                 }
+                // Conserver le certificat pour l'affichage du message a long terme
+                if let Err(e) = conserver_certificat_message(middleware, certificat.as_ref()).await {
+                    warn!("transactions.transaction_recevoir Erreur conservation certificat {} : {:?}", fingerprint, e);
+                }
                 message_recevoir_serialise.certificat = Some(certificat);
             },
             None => Err(format!("transactions.transaction_recevoir Erreur mapping message serialise, certificat introuvable : {}", fingerprint))?
@@ -455,85 +539,246 @@ async fn transaction_recevoir<M, T>(gestionnaire: &GestionnaireMessagerie, middl
         None => None
     };
 
+    // Taille du message pour l'usage de la boite
+    let taille_message = match serde_json::to_vec(&message_recevoir_serialise.parsed) {
+        Ok(inner) => Some(inner.len() as i64),
+        Err(e) => {
+            warn!("transaction_recevoir Erreur calcul taille message {} : {:?}", message_id, e);
+            None
+        }
+    };
+
+    // Regles de messages des usagers, evaluees sur les metadonnees visibles du message
+    let (regles_usagers, quarantaines_usagers) = {
+        let user_ids: Vec<&String> = destinataires.iter().filter_map(|d| d.user_id.as_ref()).collect();
+        let regles = match charger_regles_messages(middleware, &user_ids).await {
+            Ok(inner) => inner,
+            Err(e) => Err(format!("transactions.transaction_recevoir Erreur chargement regles messages : {:?}", e))?
+        };
+        let quarantaines = match charger_configurations_quarantaine(middleware, &user_ids).await {
+            Ok(inner) => inner,
+            Err(e) => Err(format!("transactions.transaction_recevoir Erreur chargement configuration quarantaine : {:?}", e))?
+        };
+        (regles, quarantaines)
+    };
+    let idmg_origine = match message_recevoir_serialise.parsed.origine.as_ref() {
+        Some(inner) => inner.to_owned(),
+        None => middleware.idmg().to_owned()
+    };
+    let estampille = transaction.get_estampille().to_owned();
+    let attachments_presents = match message_recevoir.fuuids.as_ref() {
+        Some(f) => ! f.is_empty(),
+        None => false
+    };
+    let mut usagers_sans_notification = HashSet::new();
+    let mut cles_changees = HashMap::new();
+
     let mut destinataires_resultat = HashMap::new();
-    let now: Bson = DateEpochSeconds::now().into();
     // let message_incoming: MessageIncoming = match message_recevoir_serialise.parsed.map_contenu() {
     //     Ok(inner) => inner,
     //     Err(e) => Err(format!("transactions.transaction_recevoir Erreur map vers MessageIncoming : {:?}", e))?
     // };
+
+    // Preparer un document par usager. L'index unique userid_message (user_id, message.id) empeche
+    // les doublons, une re-execution de la transaction produit des erreurs de duplication.
+    let mut documents_usagers = Vec::new();
+    let mut documents_bson = Vec::new();
     for d in destinataires.iter() {
         match d.user_id.as_ref() {
             Some(u) => {
-                // Sauvegarder message pour l'usager
                 debug!("transaction_recevoir Sauvegarder message pour usager : {}", u);
+                let resultat_regles = match regles_usagers.get(u) {
+                    Some(regles) => {
+                        let metadonnees = MetadonneesReception {
+                            from: message_recevoir.from.as_ref().map(|f| f.as_str()),
+                            idmg: idmg_origine.as_str(),
+                            certificat: message_recevoir_serialise.parsed.pubkey.as_str(),
+                            attachments: attachments_presents,
+                            date_reception: &estampille,
+                            adresse_destination: d.adresse.as_ref().map(|a| a.as_str()),
+                        };
+                        appliquer_regles_messages(regles, &metadonnees)
+                    },
+                    None => Default::default()
+                };
+                if resultat_regles.sans_notification || resultat_regles.supprimer {
+                    // Un message supprime par une regle n'est pas notifie
+                    usagers_sans_notification.insert(u.to_owned());
+                }
+
+                // Quarantaine des expediteurs absents des contacts de l'usager
+                let (mut quarantaine, index_expediteur) = match quarantaines_usagers.get(u) {
+                    Some(sel) => {
+                        let index_expediteur = message_recevoir.from.as_ref()
+                            .map(|f| calculer_index_adresse(sel.as_str(), f.as_str()));
+                        let connu = match index_expediteur.as_ref() {
+                            Some(i) => match verifier_contact_connu(middleware, u.as_str(), i.as_str()).await {
+                                Ok(inner) => inner,
+                                Err(e) => Err(format!("transactions.transaction_recevoir Erreur verification contact : {:?}", e))?
+                            },
+                            None => false
+                        };
+                        (! connu, index_expediteur)
+                    },
+                    None => (false, None)
+                };
+                if message_recevoir.quarantaine == Some(true) {
+                    // Quarantaine imposee a la reception (reputation de la millegrille source)
+                    quarantaine = true;
+                }
+                if quarantaine {
+                    debug!("transaction_recevoir Message {} en quarantaine pour usager {}", message_id, u);
+                    usagers_sans_notification.insert(u.to_owned());
+                }
+
+                // Cle epinglee de l'expediteur (trust on first use), messages tiers seulement
+                let mut cle_changee = None;
+                let mut index_cle_expediteur = None;
+                if let (false, Some(from)) = (message_local, message_recevoir.from.as_ref()) {
+                    let fingerprint = message_recevoir_serialise.parsed.pubkey.as_str();
+                    match verifier_cle_expediteur(middleware, u.as_str(), from.as_str(), fingerprint, &estampille).await {
+                        Ok(Some(fingerprint_precedent)) => {
+                            cle_changee = Some(true);
+                            index_cle_expediteur = Some(calculer_index_cle_expediteur(u.as_str(), from.as_str()));
+                            cles_changees.insert(u.to_owned(), fingerprint_precedent);
+                        },
+                        Ok(None) => (),
+                        Err(e) => Err(format!("transactions.transaction_recevoir Erreur verification cle expediteur : {:?}", e))?
+                    }
+                }
 
                 let message_document = DocumentIncoming {
                     message: message_recevoir_serialise.parsed.clone(),
                     user_id: u.to_owned(),
-                    supprime: false,
-                    lu: false,
+                    supprime: resultat_regles.supprimer,
+                    lu: resultat_regles.lu,
                     date_reception: DateEpochSeconds::now(),
                     date_ouverture: None,
                     fichiers: map_attachements.clone(),
                     fichiers_completes: attachements_recus,
                     niveau: None,
+                    taille: taille_message,
+                    transfert: message_recevoir.transfert.clone(),
+                    labels: match resultat_regles.labels.is_empty() {
+                        true => None,
+                        false => Some(resultat_regles.labels)
+                    },
+                    archive: match resultat_regles.archiver {
+                        true => Some(true),
+                        false => None
+                    },
+                    date_archive: match resultat_regles.archiver {
+                        true => Some(DateEpochSeconds::from(estampille.clone())),
+                        false => None
+                    },
+                    etoile: match resultat_regles.etoile {
+                        true => Some(true),
+                        false => None
+                    },
+                    quarantaine: match quarantaine {
+                        true => Some(true),
+                        false => None
+                    },
+                    index_expediteur,
+                    signale: None,
+                    cle_changee,
+                    index_cle_expediteur,
+                    thread: message_recevoir.thread.clone(),
                 };
 
-                {
-                    let message_bson = match convertir_to_bson(&message_document) {
-                        Ok(inner) => inner,
-                        Err(e) => Err(format!("transactions.transaction_recevoir Erreur message {}, echec conversion en bson : {:?}", uuid_transaction, e))?
-                    };
-
-                    debug!("transaction_recevoir Inserer message {:?}", message_bson);
-                    match collection.insert_one(message_bson, None).await {
-                        Ok(_r) => {
-                            // Marquer usager comme trouve et traite
-                            if let Some(adresse_usager) = d.adresse.as_ref() {
-                                destinataires_resultat.insert(adresse_usager.to_owned(), 201);  // Message cree pour usager
-                            }
-                        },
-                        Err(e) => {
-                            let erreur_duplication = verifier_erreur_duplication_mongo(&*e.kind);
-                            if erreur_duplication {
-                                warn!("transaction_recevoir Duplication message externe recu, on l'ignore : {:?}", message_document);
-                                if let Some(adresse_usager) = d.adresse.as_ref() {
-                                    destinataires_resultat.insert(adresse_usager.to_owned(), 200);  // Message deja traite
-                                }
-                                return middleware.reponse_ok();
+                // L'_id reste un ObjectId. L'index unique userid_message (user_id, message.id) detecte
+                // les messages deja livres.
+                let message_bson = match convertir_to_bson(&message_document) {
+                    Ok(inner) => inner,
+                    Err(e) => Err(format!("transactions.transaction_recevoir Erreur message {}, echec conversion en bson : {:?}", uuid_transaction, e))?
+                };
+
+                documents_bson.push(message_bson);
+                documents_usagers.push((d, message_document));
+            },
+            None => {
+                if let Some(adresse_usager) = d.adresse.as_ref() {
+                    destinataires_resultat.insert(adresse_usager.to_owned(), d.code.unwrap_or(404));  // Usager inconnu ou expediteur refuse
+                }
+            }
+        }
+    }
+
+    // Inserer tous les messages en une seule operation. Avec ordered=false, les duplications
+    // n'empechent pas l'insertion des autres usagers.
+    let mut index_dupliques = HashSet::new();
+    if ! documents_bson.is_empty() {
+        debug!("transaction_recevoir Inserer {} messages", documents_bson.len());
+        let options = InsertManyOptions::builder().ordered(false).build();
+        if let Err(e) = collection.insert_many(documents_bson, Some(options)).await {
+            let mut erreur_autre = true;
+            if let ErrorKind::BulkWrite(failure) = &*e.kind {
+                if failure.write_concern_error.is_none() {
+                    erreur_autre = false;
+                    if let Some(write_errors) = failure.write_errors.as_ref() {
+                        for err in write_errors {
+                            if err.code == CODE_ERREUR_DUPLICATION_MONGO {
+                                index_dupliques.insert(err.index);
                             } else {
-                                if let Some(adresse_usager) = d.adresse.as_ref() {
-                                    destinataires_resultat.insert(adresse_usager.to_owned(), 500);  // Erreur de traitement
-                                }
-                                Err(format!("transactions.transaction_recevoir Erreur insertion message {} pour usager {} : {:?}", uuid_transaction, u, e))?
+                                erreur_autre = true;
                             }
                         }
                     }
                 }
+            }
+            if erreur_autre {
+                Err(format!("transactions.transaction_recevoir Erreur insertion message {} : {:?}", uuid_transaction, e))?
+            }
+        }
+    }
 
-                // Evenement de nouveau message pour front-end, notifications
-                //if let Ok(m) = convertir_bson_deserializable::<MessageIncoming>(doc_user_reception) {
-                let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_NOUVEAU_MESSAGE)
-                    .exchanges(vec![L2Prive])
-                    .partition(u)
-                    .build();
+    let mut destinataires_nouveaux = Vec::new();
+    let mut deltas_usage = HashMap::new();
+    for (idx, (d, message_document)) in documents_usagers.into_iter().enumerate() {
+        let u = message_document.user_id.clone();
 
-                match middleware.get_certificat(message_recevoir_serialise.parsed.pubkey.as_str()).await {
-                    Some(inner) => {
-                        let mut evenement = MessageIncomingClient::from(message_document);
-                        evenement.certificat = Some(inner.get_pem_vec_extracted());
-                        middleware.emettre_evenement(routage, &evenement).await?;
-                    },
-                    None => {
-                        error!("transasctions.transaction_recevoir Erreur get_certificat {} du message {} pour emettre_evenement",
-                            message_recevoir_serialise.parsed.pubkey, message_recevoir_serialise.parsed.id);
-                    }
-                }
+        if index_dupliques.contains(&idx) {
+            warn!("transaction_recevoir Duplication message {} pour usager {}, deja livre", message_id, u);
+            if let Some(adresse_usager) = d.adresse.as_ref() {
+                destinataires_resultat.insert(adresse_usager.to_owned(), 200);  // Message deja traite
+            }
+            continue;
+        }
+
+        // Marquer usager comme trouve et traite
+        if let Some(adresse_usager) = d.adresse.as_ref() {
+            destinataires_resultat.insert(adresse_usager.to_owned(), 201);  // Message cree pour usager
+        }
+        destinataires_nouveaux.push(d.to_owned());
+        if ! message_document.supprime {
+            let delta = DeltaUsageBoite::message(
+                message_document.taille, message_document.fichiers.as_ref(), None);
+            deltas_usage.insert(u.clone(), delta);
+        }
+
+        if let Some(fingerprint_precedent) = cles_changees.get(&u) {
+            if let Err(e) = emettre_evenement_cle_changee(
+                middleware, u.as_str(), message_id.as_str(), message_recevoir_serialise.parsed.pubkey.as_str(),
+                fingerprint_precedent.as_str()).await {
+                warn!("transaction_recevoir Erreur emission evenement cle changee message {} : {:?}", message_id, e);
+            }
+        }
+
+        // Evenement de nouveau message pour front-end, notifications
+        let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_NOUVEAU_MESSAGE)
+            .exchanges(vec![L2Prive])
+            .partition(u)
+            .build();
+
+        match middleware.get_certificat(message_recevoir_serialise.parsed.pubkey.as_str()).await {
+            Some(inner) => {
+                let mut evenement = MessageIncomingClient::from(message_document);
+                evenement.certificat = Some(inner.get_pem_vec_extracted());
+                middleware.emettre_evenement(routage, &evenement).await?;
             },
             None => {
-                if let Some(adresse_usager) = d.adresse.as_ref() {
-                    destinataires_resultat.insert(adresse_usager.to_owned(), 404);  // Usager inconnu
-                }
+                error!("transasctions.transaction_recevoir Erreur get_certificat {} du message {} pour emettre_evenement",
+                    message_recevoir_serialise.parsed.pubkey, message_recevoir_serialise.parsed.id);
             }
         }
     }
@@ -565,9 +810,24 @@ async fn transaction_recevoir<M, T>(gestionnaire: &GestionnaireMessagerie, middl
     //     }
     // }
 
-    if let Err(e) = emettre_notifications(
-        middleware, &destinataires, message_id.as_str()).await {
-        warn!("transaction_recevoir Erreur emission notifications : {:?}", e);
+    if ! destinataires_nouveaux.is_empty() {
+        // Retirer les usagers dont une regle supprime la notification. Un changement de cle
+        // de l'expediteur est toujours notifie.
+        let destinataires_notification: Vec<DestinataireInfo> = destinataires_nouveaux.iter()
+            .filter(|d| match d.user_id.as_ref() {
+                Some(u) => ! usagers_sans_notification.contains(u) || cles_changees.contains_key(u),
+                None => false
+            })
+            .map(|d| d.to_owned())
+            .collect();
+        if ! destinataires_notification.is_empty() {
+            if let Err(e) = emettre_notifications(
+                middleware, &destinataires_notification, message_id.as_str()).await {
+                warn!("transaction_recevoir Erreur emission notifications : {:?}", e);
+            }
+        }
+
+        maj_usage_boites(middleware, &deltas_usage).await;
     }
 
     let reponse = json!({"ok": true , "usagers": &destinataires_resultat});
@@ -577,6 +837,15 @@ async fn transaction_recevoir<M, T>(gestionnaire: &GestionnaireMessagerie, middl
     }
 }
 
+/// Usager proprietaire du message poste. Le user_id du certificat a priorite, celui de la commande
+/// est utilise pour un message signe par le domaine (certificat sans user_id).
+fn resoudre_user_id_poster(user_id_certificat: Option<String>, user_id_commande: Option<&String>) -> Option<String> {
+    match user_id_certificat {
+        Some(inner) => Some(inner),
+        None => user_id_commande.map(|u| u.to_owned())
+    }
+}
+
 pub async fn emettre_notifications<M>(middleware: &M, usagers: &Vec<DestinataireInfo>, uuid_message: &str)
     -> Result<(), Box<dyn Error>>
     where M: GenerateurMessages + MongoDao + ValidateurX509
@@ -837,185 +1106,1150 @@ async fn transaction_lu<M, T>(gestionnaire: &GestionnaireMessagerie, middleware:
     middleware.reponse_ok()
 }
 
-async fn transfert_complete<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+async fn transaction_maj_filtre_expediteurs<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
     where
         M: GenerateurMessages + MongoDao + ValidateurX509,
         T: Transaction
 {
-    debug!("transfert_complete Consommer transaction : {:?}", &transaction);
+    debug!("transaction_maj_filtre_expediteurs Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
 
-    let transaction_mappee = match transaction.convertir::<TransactionTransfertComplete>() {
+    let transaction_filtre: TransactionMajFiltreExpediteurs = match transaction.clone().convertir::<TransactionMajFiltreExpediteurs>() {
         Ok(t) => t,
-        Err(e) => Err(format!("transactions.transfert_complete Erreur conversion transaction : {:?}", e))?
+        Err(e) => Err(format!("transactions.transaction_maj_filtre_expediteurs Erreur conversion transaction : {:?}", e))?
     };
 
-    let uuid_message = transaction_mappee.message_id.as_str();
-    let filtre = doc! {CHAMP_UUID_MESSAGE: uuid_message};
-    let mut unset_ops = doc!{};
-    if let Some(m) = transaction_mappee.message_complete {
-        if m {
-            unset_ops.insert("dns_unresolved", true);
-            unset_ops.insert("idmgs_unprocessed", true);
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_maj_filtre_expediteurs Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_maj_filtre_expediteurs user_id manquant du certificat"))?
         }
-    }
+    };
 
-    if let Some(a) = transaction_mappee.attachments_completes {
-        if a {
-            unset_ops.insert("idmgs_attachments_unprocessed", true);
-        }
-    }
+    let filtre_bson = match convertir_to_bson(&transaction_filtre.filtre) {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("transactions.transaction_maj_filtre_expediteurs Erreur conversion filtre {} en bson : {:?}", uuid_transaction, e))?
+    };
 
-    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let filtre = doc! {CHAMP_USER_ID: &user_id};
     let ops = doc! {
-        "$unset": unset_ops,
-        "$currentDate": {CHAMP_LAST_PROCESSED: true},
+        "$set": {CHAMP_FILTRE_EXPEDITEURS: filtre_bson},
+        "$currentDate": {CHAMP_MODIFICATION: true},
     };
-    let options = FindOneAndUpdateOptions::builder()
-        .return_document(ReturnDocument::After)
-        .build();
-
-    let outgoing_processing: DocOutgointProcessing = match collection.find_one_and_update(filtre, ops, options).await {
-        Ok(r) => match r {
-            Some(d) => match convertir_bson_deserializable(d) {
-                Ok(d) => d,
-                Err(e) => Err(format!("transactions.transfert_complete Erreur conversion resultat pour transfert complete {} : {:?}", uuid_message, e))?
-            },
-            None => {
-                // Le doc n'existe pas, probablement un vieux message durant regeneration.
-                return Ok(None)
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => {
+            if r.matched_count != 1 {
+                match middleware.formatter_reponse(json!({"ok": false, "code": 404, "err": "Profil usager inconnu"}), None) {
+                    Ok(r) => return Ok(Some(r)),
+                    Err(e) => Err(format!("transactions.transaction_maj_filtre_expediteurs Erreur preparation reponse : {:?}", e))?
+                }
             }
         },
-        Err(e) => Err(format!("transactions.transfert_complete Erreur update pour transfert complete {} : {:?}", uuid_message, e))?
-    };
-
-    // Mettre usagers completes dans outgoing
-    if let Some(destinataires) = &outgoing_processing.destinataires {
-        debug!("transactions.transfert_complete Marquer destinataires pour message {} : {:?}", uuid_message, destinataires);
-        let mut set_ops = doc!{};
-        for destinataire in destinataires {
-            if let Some(result) = destinataire.result {
-                let destinataire_key = destinataire.destinataire.replace(".", ",");  // Mapping key dans table mongo
-                set_ops.insert(format!("destinataires.{}", destinataire_key), result);
-            }
-        }
-        if set_ops.len() > 0 {
-            let filtre = doc! {"message.id": uuid_message};
-            let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
-            let ops = doc! {
-                "$set": set_ops,
-                "$currentDate": {CHAMP_MODIFICATION: true}
-            };
-            if let Err(e) = collection.update_one(filtre, ops, None).await {
-                error!("transactions.transfert_complete Erreur update destinataires : {}", uuid_message);
-            }
-        }
+        Err(e) => Err(format!("transactions.transaction_maj_filtre_expediteurs Erreur maj profil {} : {:?}", user_id, e))?
     }
 
-    let message_complete = verifier_message_complete(middleware, &outgoing_processing);
-    if message_complete {
-        debug!("transfert_complete Conserve flag message complete dans outgoing");
-        let filtre = doc!{
-            "message.id": uuid_message,
-            "user_id": outgoing_processing.user_id.as_ref(),
-        };
-        let ops = doc!{
-            "$set": {"transfert_complete": true},
-            "$currentDate": {CHAMP_MODIFICATION: true}
-        };
-        let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
-        match collection.update_one(filtre, ops, None).await {
-            Ok(_r) => (),
-            Err(e) => error!("transactions.transfert_complete Erreur update message outgoing : {:?}", e)
-        };
-
-        debug!("transfert_complete Emettre evenement message complete");
-        if let Some(user_id) = outgoing_processing.user_id {
-            let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_CONFIRMER_MESSAGE_COMPLETE)
-                .exchanges(vec![Securite::L2Prive])
-                .partition(user_id.clone())
-                .build();
-            let message = ConfirmerMessageComplete { user_id, message_id: uuid_message.to_owned() };
-            middleware.emettre_evenement(routage, &message).await?;
-        }
-    }
-
-    Ok(None)
+    middleware.reponse_ok()
 }
 
-async fn supprimer_message<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+async fn transaction_maj_reponse_automatique<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
     where
         M: GenerateurMessages + MongoDao + ValidateurX509,
         T: Transaction
 {
-    debug!("supprimer_message Consommer transaction : {:?}", &transaction);
+    debug!("transaction_maj_reponse_automatique Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
 
-    let message_id = transaction.get_uuid_transaction().to_owned();
-    let user_id = match transaction.get_enveloppe_certificat() {
-        Some(e) => match e.get_user_id()?.to_owned() {
-            Some(u) => u,
-            None => Err(format!("transactions.supprimer_message Certificat sans user_id, transaction {} invalide", message_id))?
-        },
-        None => Err(format!("transactions.supprimer_message Message sans certificat, transaction {} invalide", message_id))?
+    let transaction_reponse: TransactionMajReponseAutomatique = match transaction.clone().convertir::<TransactionMajReponseAutomatique>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_maj_reponse_automatique Erreur conversion transaction : {:?}", e))?
     };
 
-    let transaction_mappee = match transaction.convertir::<TransactionSupprimerMessage>() {
-        Ok(t) => t,
-        Err(e) => Err(format!("transactions.supprimer_message Erreur conversion transaction : {:?}", e))?
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_maj_reponse_automatique Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_maj_reponse_automatique user_id manquant du certificat"))?
+        }
     };
 
-    let message_ids = transaction_mappee.message_ids;
+    let reponse_bson = match convertir_to_bson(&transaction_reponse.reponse_automatique) {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("transactions.transaction_maj_reponse_automatique Erreur conversion {} en bson : {:?}", uuid_transaction, e))?
+    };
 
-    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": {"$in": &message_ids}};
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let filtre = doc! {CHAMP_USER_ID: &user_id};
     let ops = doc! {
-        "$set": { CHAMP_SUPPRIME: true },
-        "$currentDate": { CHAMP_MODIFICATION: true },
+        "$set": {CHAMP_REPONSE_AUTOMATIQUE: reponse_bson},
+        "$currentDate": {CHAMP_MODIFICATION: true},
     };
-
-    debug!("supprimer_message filtre : {:?}, ops: {:?}", filtre, ops);
-
-    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
-    match collection.update_many(filtre, ops, None).await {
-        Ok(r) => debug!("supprimer_message Resultat : {:?}", r),
-        Err(e) => Err(format!("transactions.supprimer_message Erreur update pour transfert complete {} : {:?}", message_id, e))?
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => {
+            if r.matched_count != 1 {
+                match middleware.formatter_reponse(json!({"ok": false, "code": 404, "err": "Profil usager inconnu"}), None) {
+                    Ok(r) => return Ok(Some(r)),
+                    Err(e) => Err(format!("transactions.transaction_maj_reponse_automatique Erreur preparation reponse : {:?}", e))?
+                }
+            }
+        },
+        Err(e) => Err(format!("transactions.transaction_maj_reponse_automatique Erreur maj profil {} : {:?}", user_id, e))?
     }
 
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MESSAGES_SUPPRIMES)
-        .exchanges(vec![L2Prive])
-        .partition(&user_id)
-        .build();
-    let evenement_supprime = json!({
-        "message_ids": &message_ids,
-    });
-    middleware.emettre_evenement(routage, &evenement_supprime).await?;
-
     middleware.reponse_ok()
 }
 
-async fn supprimer_contacts<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+async fn transaction_maj_regles_transfert<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
     where
         M: GenerateurMessages + MongoDao + ValidateurX509,
         T: Transaction
 {
-    debug!("supprimer_contacts Consommer transaction : {:?}", &transaction);
-
+    debug!("transaction_maj_regles_transfert Consommer transaction : {:?}", &transaction);
     let uuid_transaction = transaction.get_uuid_transaction().to_owned();
-    let user_id = match transaction.get_enveloppe_certificat() {
-        Some(e) => match e.get_user_id()?.to_owned() {
-            Some(u) => u,
-            None => Err(format!("transactions.supprimer_contacts Certificat sans user_id, transaction {} invalide", uuid_transaction))?
-        },
-        None => Err(format!("transactions.supprimer_contacts Message sans certificat, transaction {} invalide", uuid_transaction))?
-    };
 
-    let transaction_mappee = match transaction.convertir::<TransactionSupprimerContacts>() {
+    let transaction_regles: TransactionMajReglesTransfert = match transaction.clone().convertir::<TransactionMajReglesTransfert>() {
         Ok(t) => t,
-        Err(e) => Err(format!("transactions.supprimer_contacts Erreur conversion transaction : {:?}", e))?
+        Err(e) => Err(format!("transactions.transaction_maj_regles_transfert Erreur conversion transaction : {:?}", e))?
     };
 
-    let uuid_contacts = transaction_mappee.uuid_contacts;
-    let filtre = doc! {CHAMP_USER_ID: &user_id, CHAMP_UUID_CONTACT: {"$in": &uuid_contacts}};
-    let ops = doc! {
-        "$set": { CHAMP_SUPPRIME: true },
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_maj_regles_transfert Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_maj_regles_transfert user_id manquant du certificat"))?
+        }
+    };
+
+    let mut regles_bson = Array::new();
+    for regle in transaction_regles.regles.iter() {
+        match convertir_to_bson(regle) {
+            Ok(inner) => regles_bson.push(Bson::Document(inner)),
+            Err(e) => Err(format!("transactions.transaction_maj_regles_transfert Erreur conversion {} en bson : {:?}", uuid_transaction, e))?
+        }
+    }
+
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let filtre = doc! {CHAMP_USER_ID: &user_id};
+    let ops = doc! {
+        "$set": {CHAMP_REGLES_TRANSFERT: regles_bson},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => {
+            if r.matched_count != 1 {
+                match middleware.formatter_reponse(json!({"ok": false, "code": 404, "err": "Profil usager inconnu"}), None) {
+                    Ok(r) => return Ok(Some(r)),
+                    Err(e) => Err(format!("transactions.transaction_maj_regles_transfert Erreur preparation reponse : {:?}", e))?
+                }
+            }
+        },
+        Err(e) => Err(format!("transactions.transaction_maj_regles_transfert Erreur maj profil {} : {:?}", user_id, e))?
+    }
+
+    middleware.reponse_ok()
+}
+
+async fn transaction_maj_regles_messages<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_maj_regles_messages Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_regles: TransactionMajReglesMessages = match transaction.clone().convertir::<TransactionMajReglesMessages>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_maj_regles_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_maj_regles_messages Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_maj_regles_messages user_id manquant du certificat"))?
+        }
+    };
+
+    let mut regles_bson = Array::new();
+    for regle in transaction_regles.regles.iter() {
+        match convertir_to_bson(regle) {
+            Ok(inner) => regles_bson.push(Bson::Document(inner)),
+            Err(e) => Err(format!("transactions.transaction_maj_regles_messages Erreur conversion {} en bson : {:?}", uuid_transaction, e))?
+        }
+    }
+
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let filtre = doc! {CHAMP_USER_ID: &user_id};
+    let ops = doc! {
+        "$set": {CHAMP_REGLES_MESSAGES: regles_bson},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => {
+            if r.matched_count != 1 {
+                match middleware.formatter_reponse(json!({"ok": false, "code": 404, "err": "Profil usager inconnu"}), None) {
+                    Ok(r) => return Ok(Some(r)),
+                    Err(e) => Err(format!("transactions.transaction_maj_regles_messages Erreur preparation reponse : {:?}", e))?
+                }
+            }
+        },
+        Err(e) => Err(format!("transactions.transaction_maj_regles_messages Erreur maj profil {} : {:?}", user_id, e))?
+    }
+
+    middleware.reponse_ok()
+}
+
+async fn transaction_maj_quarantaine<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_maj_quarantaine Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_quarantaine: TransactionMajQuarantaine = match transaction.clone().convertir::<TransactionMajQuarantaine>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_maj_quarantaine Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_maj_quarantaine Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_maj_quarantaine user_id manquant du certificat"))?
+        }
+    };
+
+    let quarantaine_bson = match convertir_to_bson(&transaction_quarantaine.quarantaine) {
+        Ok(inner) => inner,
+        Err(e) => Err(format!("transactions.transaction_maj_quarantaine Erreur conversion {} en bson : {:?}", uuid_transaction, e))?
+    };
+
+    let collection = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let filtre = doc! {CHAMP_USER_ID: &user_id};
+    let ops = doc! {
+        "$set": {CHAMP_QUARANTAINE: quarantaine_bson},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    match collection.update_one(filtre, ops, None).await {
+        Ok(r) => {
+            if r.matched_count != 1 {
+                match middleware.formatter_reponse(json!({"ok": false, "code": 404, "err": "Profil usager inconnu"}), None) {
+                    Ok(r) => return Ok(Some(r)),
+                    Err(e) => Err(format!("transactions.transaction_maj_quarantaine Erreur preparation reponse : {:?}", e))?
+                }
+            }
+        },
+        Err(e) => Err(format!("transactions.transaction_maj_quarantaine Erreur maj profil {} : {:?}", user_id, e))?
+    }
+
+    middleware.reponse_ok()
+}
+
+async fn transaction_traiter_quarantaine<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_traiter_quarantaine Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_quarantaine: TransactionTraiterQuarantaine = match transaction.clone().convertir::<TransactionTraiterQuarantaine>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_traiter_quarantaine Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_traiter_quarantaine Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_traiter_quarantaine user_id manquant du certificat"))?
+        }
+    };
+
+    let index_expediteur = transaction_quarantaine.index_expediteur.as_str();
+
+    // Ajouter l'expediteur accepte aux contacts
+    if let Some(contact) = transaction_quarantaine.contact.as_ref() {
+        let uuid_contact = match contact.uuid_contact.as_ref() {
+            Some(inner) => inner.to_owned(),
+            None => uuid_transaction.clone()
+        };
+        let mut doc_contact = match convertir_to_bson(contact) {
+            Ok(d) => d,
+            Err(e) => Err(format!("transactions.transaction_traiter_quarantaine Erreur conversion contact en bson : {:?}", e))?
+        };
+        doc_contact.remove("uuid_contact");
+        doc_contact.remove(CHAMP_INDEX_ADRESSES);
+        let filtre = doc! {CHAMP_USER_ID: &user_id, "uuid_contact": &uuid_contact};
+        let ops = doc! {
+            "$set": doc_contact,
+            "$addToSet": {CHAMP_INDEX_ADRESSES: index_expediteur},
+            "$setOnInsert": {CHAMP_CREATION: chrono::Utc::now(), CHAMP_SUPPRIME: false},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        let collection = middleware.get_collection(NOM_COLLECTION_CONTACTS)?;
+        if let Err(e) = collection.update_one(filtre, ops, Some(options)).await {
+            Err(format!("transactions.transaction_traiter_quarantaine Erreur ajout contact {} : {:?}", uuid_contact, e))?
+        }
+    }
+
+    // Liberer (accepter) ou supprimer (refuser) les messages en quarantaine de l'expediteur
+    let filtre = doc! {CHAMP_USER_ID: &user_id, CHAMP_INDEX_EXPEDITEUR: index_expediteur, CHAMP_QUARANTAINE: true};
+    let modifies = traiter_messages_quarantaine(middleware, user_id.as_str(), filtre, transaction_quarantaine.accepter).await?;
+    debug!("transaction_traiter_quarantaine Usager {} expediteur {} accepter {} : {} messages",
+        user_id, index_expediteur, transaction_quarantaine.accepter, modifies);
+
+    let reponse = json!({"ok": true, "messages": modifies});
+    match middleware.formatter_reponse(&reponse, None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_traiter_quarantaine Erreur formattage reponse : {:?}", e))?
+    }
+}
+
+async fn transaction_traiter_quarantaine_messages<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_traiter_quarantaine_messages Consommer transaction : {:?}", &transaction);
+    let transaction_quarantaine: TransactionTraiterQuarantaineMessages = match transaction.clone().convertir::<TransactionTraiterQuarantaineMessages>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_traiter_quarantaine_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_traiter_quarantaine_messages Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_traiter_quarantaine_messages user_id manquant du certificat"))?
+        }
+    };
+
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": {"$in": &transaction_quarantaine.message_ids}, CHAMP_QUARANTAINE: true};
+    let modifies = traiter_messages_quarantaine(middleware, user_id.as_str(), filtre, transaction_quarantaine.accepter).await?;
+    debug!("transaction_traiter_quarantaine_messages Usager {} messages {:?} accepter {} : {} messages",
+        user_id, transaction_quarantaine.message_ids, transaction_quarantaine.accepter, modifies);
+
+    let reponse = json!({"ok": true, "messages": modifies});
+    match middleware.formatter_reponse(&reponse, None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_traiter_quarantaine_messages Erreur formattage reponse : {:?}", e))?
+    }
+}
+
+/// Libere (accepter) ou supprime (refuser) les messages en quarantaine du filtre. Retourne le
+/// nombre de messages modifies.
+async fn traiter_messages_quarantaine<M>(middleware: &M, user_id: &str, mut filtre: Document, accepter: bool)
+    -> Result<u64, String>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    let modifies = match accepter {
+        true => {
+            let ops = doc! {
+                "$unset": {CHAMP_QUARANTAINE: true},
+                "$currentDate": {CHAMP_MODIFICATION: true},
+            };
+            match collection.update_many(filtre, ops, None).await {
+                Ok(r) => r.modified_count,
+                Err(e) => Err(format!("transactions.traiter_messages_quarantaine Erreur maj messages usager {} : {:?}", user_id, e))?
+            }
+        },
+        false => {
+            // Les messages refuses sont retires de l'usage de la boite
+            filtre.insert(CHAMP_SUPPRIME, false);
+            let ops = doc! {
+                "$set": {CHAMP_SUPPRIME: true},
+                "$unset": {CHAMP_QUARANTAINE: true},
+                "$currentDate": {CHAMP_MODIFICATION: true},
+            };
+            match modifier_incoming_usage(middleware, filtre, ops, -1).await {
+                Ok((compte, deltas)) => {
+                    maj_usage_boites(middleware, &deltas).await;
+                    compte
+                },
+                Err(e) => Err(format!("transactions.traiter_messages_quarantaine Erreur maj messages usager {} : {:?}", user_id, e))?
+            }
+        }
+    };
+
+    Ok(modifies)
+}
+
+async fn transaction_signaler_message<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_signaler_message Consommer transaction : {:?}", &transaction);
+    let estampille = transaction.get_estampille();
+
+    let transaction_signaler: TransactionSignalerMessage = match transaction.clone().convertir::<TransactionSignalerMessage>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_signaler_message Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_signaler_message Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_signaler_message user_id manquant du certificat"))?
+        }
+    };
+
+    let message_id = transaction_signaler.message_id.as_str();
+    let date_signalement = DateEpochSeconds::from(estampille.clone());
+
+    // Marquer le message signale
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": message_id};
+    let ops = doc! {
+        "$set": {CHAMP_SIGNALE: true, CHAMP_DATE_SIGNALEMENT: estampille},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    let doc_incoming: DocumentIncoming = match collection.find_one_and_update(filtre, ops, Some(options)).await {
+        Ok(d) => match d {
+            Some(d) => match convertir_bson_deserializable(d) {
+                Ok(d) => d,
+                Err(e) => Err(format!("transactions.transaction_signaler_message Erreur conversion message {} : {:?}", message_id, e))?
+            },
+            None => {
+                let reponse = json!({"ok": false, "code": 404, "err": "Message inconnu"});
+                match middleware.formatter_reponse(&reponse, None) {
+                    Ok(r) => return Ok(Some(r)),
+                    Err(e) => Err(format!("transactions.transaction_signaler_message Erreur formattage reponse : {:?}", e))?
+                }
+            }
+        },
+        Err(e) => Err(format!("transactions.transaction_signaler_message Erreur maj message {} : {:?}", message_id, e))?
+    };
+
+    // Conserver le signalement local avec le message original (signe) comme preuve
+    let signalement = DocSignalement {
+        signalement_id: get_signalement_id(user_id.as_str(), message_id),
+        type_signalement: TYPE_SIGNALEMENT_LOCAL.to_string(),
+        user_id: Some(user_id.clone()),
+        idmg: doc_incoming.message.origine.clone(),
+        message_id: message_id.to_owned(),
+        raison: transaction_signaler.raison,
+        message: doc_incoming.message,
+        date_signalement,
+    };
+    if let Err(e) = conserver_signalement(middleware, &signalement).await {
+        Err(format!("transactions.transaction_signaler_message Erreur conservation signalement {} : {:?}", message_id, e))?
+    }
+
+    Ok(middleware.reponse_ok()?)
+}
+
+async fn transaction_accepter_cle_expediteur<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_accepter_cle_expediteur Consommer transaction : {:?}", &transaction);
+
+    let transaction_accepter: TransactionAccepterCleExpediteur = match transaction.clone().convertir::<TransactionAccepterCleExpediteur>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_accepter_cle_expediteur Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = {
+        let certificat = match transaction.get_enveloppe_certificat() {
+            Some(c) => c,
+            None => Err(format!("transactions.transaction_accepter_cle_expediteur Certificat invalide/non charge"))?
+        };
+        match certificat.get_user_id()? {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_accepter_cle_expediteur user_id manquant du certificat"))?
+        }
+    };
+
+    let message_id = transaction_accepter.message_id.as_str();
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": message_id};
+    let doc_incoming: DocumentIncoming = match collection.find_one(filtre, None).await {
+        Ok(Some(d)) => match convertir_bson_deserializable(d) {
+            Ok(d) => d,
+            Err(e) => Err(format!("transactions.transaction_accepter_cle_expediteur Erreur conversion message {} : {:?}", message_id, e))?
+        },
+        Ok(None) => {
+            let reponse = json!({"ok": false, "code": 404, "err": "Message inconnu"});
+            match middleware.formatter_reponse(&reponse, None) {
+                Ok(r) => return Ok(Some(r)),
+                Err(e) => Err(format!("transactions.transaction_accepter_cle_expediteur Erreur formattage reponse : {:?}", e))?
+            }
+        },
+        Err(e) => Err(format!("transactions.transaction_accepter_cle_expediteur Erreur chargement message {} : {:?}", message_id, e))?
+    };
+
+    // La cle du message remplace la cle epinglee de l'expediteur de ce message seulement
+    let index_cle_expediteur = match doc_incoming.index_cle_expediteur.as_ref() {
+        Some(inner) => inner.as_str(),
+        None => {
+            let reponse = json!({"ok": false, "code": 400, "err": "Message sans changement de cle d'expediteur"});
+            match middleware.formatter_reponse(&reponse, None) {
+                Ok(r) => return Ok(Some(r)),
+                Err(e) => Err(format!("transactions.transaction_accepter_cle_expediteur Erreur formattage reponse : {:?}", e))?
+            }
+        }
+    };
+    let fingerprint = doc_incoming.message.pubkey.as_str();
+    if let Err(e) = accepter_cle_expediteur(middleware, user_id.as_str(), index_cle_expediteur, fingerprint).await {
+        Err(format!("transactions.transaction_accepter_cle_expediteur Erreur acceptation cle {} : {:?}", fingerprint, e))?
+    }
+
+    // Retirer le flag des messages recus de cet expediteur avec cette cle
+    let filtre = doc! {
+        CHAMP_USER_ID: &user_id,
+        CHAMP_INDEX_CLE_EXPEDITEUR: index_cle_expediteur,
+        "message.pubkey": fingerprint,
+        CHAMP_CLE_CHANGEE: true,
+    };
+    let ops = doc! {
+        "$unset": {CHAMP_CLE_CHANGEE: true},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let resultat = match collection.update_many(filtre, ops, None).await {
+        Ok(r) => r,
+        Err(e) => Err(format!("transactions.transaction_accepter_cle_expediteur Erreur maj messages usager {} : {:?}", user_id, e))?
+    };
+
+    let reponse = json!({"ok": true, "messages": resultat.modified_count});
+    match middleware.formatter_reponse(&reponse, None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_accepter_cle_expediteur Erreur formattage reponse : {:?}", e))?
+    }
+}
+
+async fn transaction_maj_label<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_maj_label Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_label: TransactionMajLabel = match transaction.clone().convertir::<TransactionMajLabel>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_maj_label Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_maj_label Certificat sans user_id, transaction {} invalide", uuid_transaction))?
+        },
+        None => Err(format!("transactions.transaction_maj_label Message sans certificat, transaction {} invalide", uuid_transaction))?
+    };
+
+    let label_id = match transaction_label.label_id {
+        Some(inner) => inner,
+        None => uuid_transaction
+    };
+
+    let filtre = doc! {CHAMP_USER_ID: &user_id, CHAMP_LABEL_ID: &label_id};
+    let ops = doc! {
+        "$set": {
+            CHAMP_NOM: &transaction_label.nom,
+            "couleur": &transaction_label.couleur,
+            "ordre": transaction_label.ordre,
+        },
+        "$setOnInsert": {CHAMP_CREATION: chrono::Utc::now()},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_LABELS)?;
+    if let Err(e) = collection.update_one(filtre, ops, Some(options)).await {
+        Err(format!("transactions.transaction_maj_label Erreur maj label {} : {:?}", label_id, e))?
+    }
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MAJ_LABEL)
+        .exchanges(vec![L2Prive])
+        .partition(&user_id)
+        .build();
+    let evenement = json!({
+        "label_id": &label_id,
+        "nom": &transaction_label.nom,
+        "couleur": &transaction_label.couleur,
+        "ordre": transaction_label.ordre,
+    });
+    middleware.emettre_evenement(routage, &evenement).await?;
+
+    let reponse = json!({"ok": true, "label_id": label_id});
+    match middleware.formatter_reponse(&reponse, None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_maj_label Erreur formattage reponse : {:?}", e))?
+    }
+}
+
+async fn transaction_supprimer_label<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_supprimer_label Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_label: TransactionSupprimerLabel = match transaction.clone().convertir::<TransactionSupprimerLabel>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_supprimer_label Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_supprimer_label Certificat sans user_id, transaction {} invalide", uuid_transaction))?
+        },
+        None => Err(format!("transactions.transaction_supprimer_label Message sans certificat, transaction {} invalide", uuid_transaction))?
+    };
+
+    let label_id = transaction_label.label_id.as_str();
+    let collection = middleware.get_collection(NOM_COLLECTION_LABELS)?;
+    if let Err(e) = collection.delete_one(doc! {CHAMP_USER_ID: &user_id, CHAMP_LABEL_ID: label_id}, None).await {
+        Err(format!("transactions.transaction_supprimer_label Erreur suppression label {} : {:?}", label_id, e))?
+    }
+
+    // Retirer le label des messages recus et envoyes
+    let filtre = doc! {CHAMP_USER_ID: &user_id, CHAMP_LABELS: label_id};
+    let ops = doc! {
+        "$pull": {CHAMP_LABELS: label_id},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    for nom_collection in [NOM_COLLECTION_INCOMING, NOM_COLLECTION_OUTGOING] {
+        let collection = middleware.get_collection(nom_collection)?;
+        if let Err(e) = collection.update_many(filtre.clone(), ops.clone(), None).await {
+            Err(format!("transactions.transaction_supprimer_label Erreur retrait label {} de {} : {:?}", label_id, nom_collection, e))?
+        }
+    }
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MAJ_LABEL)
+        .exchanges(vec![L2Prive])
+        .partition(&user_id)
+        .build();
+    let evenement = json!({"label_id": label_id, "supprime": true});
+    middleware.emettre_evenement(routage, &evenement).await?;
+
+    middleware.reponse_ok()
+}
+
+/// Ajoute (ajouter == true) ou retire un label sur plusieurs messages.
+async fn transaction_label_messages<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T, ajouter: bool) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_label_messages Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_label: TransactionLabelMessages = match transaction.clone().convertir::<TransactionLabelMessages>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_label_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_label_messages Certificat sans user_id, transaction {} invalide", uuid_transaction))?
+        },
+        None => Err(format!("transactions.transaction_label_messages Message sans certificat, transaction {} invalide", uuid_transaction))?
+    };
+
+    let label_id = transaction_label.label_id.as_str();
+    let message_ids = &transaction_label.message_ids;
+    let messages_envoyes = transaction_label.messages_envoyes == Some(true);
+
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": {"$in": message_ids.clone()}};
+    let ops = match ajouter {
+        true => doc! {
+            "$addToSet": {CHAMP_LABELS: label_id},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        },
+        false => doc! {
+            "$pull": {CHAMP_LABELS: label_id},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        }
+    };
+    let nom_collection = match messages_envoyes {
+        true => NOM_COLLECTION_OUTGOING,
+        false => NOM_COLLECTION_INCOMING
+    };
+    let collection = middleware.get_collection(nom_collection)?;
+    let resultat = match collection.update_many(filtre, ops, None).await {
+        Ok(r) => r,
+        Err(e) => Err(format!("transactions.transaction_label_messages Erreur maj label {} messages : {:?}", label_id, e))?
+    };
+    debug!("transaction_label_messages Label {} ajouter {} : {} messages", label_id, ajouter, resultat.modified_count);
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_LABELS_MESSAGES)
+        .exchanges(vec![L2Prive])
+        .partition(&user_id)
+        .build();
+    let evenement = json!({
+        "label_id": label_id,
+        "message_ids": message_ids,
+        "ajouter": ajouter,
+        "messages_envoyes": messages_envoyes,
+    });
+    middleware.emettre_evenement(routage, &evenement).await?;
+
+    let reponse = json!({"ok": true, "messages": resultat.modified_count});
+    match middleware.formatter_reponse(&reponse, None) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) => Err(format!("transactions.transaction_label_messages Erreur formattage reponse : {:?}", e))?
+    }
+}
+
+async fn transfert_complete<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transfert_complete Consommer transaction : {:?}", &transaction);
+
+    let transaction_mappee = match transaction.convertir::<TransactionTransfertComplete>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transfert_complete Erreur conversion transaction : {:?}", e))?
+    };
+
+    let uuid_message = transaction_mappee.message_id.as_str();
+    let filtre = doc! {CHAMP_UUID_MESSAGE: uuid_message};
+    let mut unset_ops = doc!{};
+    if let Some(m) = transaction_mappee.message_complete {
+        if m {
+            unset_ops.insert("dns_unresolved", true);
+            unset_ops.insert("idmgs_unprocessed", true);
+        }
+    }
+
+    if let Some(a) = transaction_mappee.attachments_completes {
+        if a {
+            unset_ops.insert("idmgs_attachments_unprocessed", true);
+        }
+    }
+
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING_PROCESSING)?;
+    let ops = doc! {
+        "$unset": unset_ops,
+        "$currentDate": {CHAMP_LAST_PROCESSED: true},
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+
+    let outgoing_processing: DocOutgointProcessing = match collection.find_one_and_update(filtre, ops, options).await {
+        Ok(r) => match r {
+            Some(d) => match convertir_bson_deserializable(d) {
+                Ok(d) => d,
+                Err(e) => Err(format!("transactions.transfert_complete Erreur conversion resultat pour transfert complete {} : {:?}", uuid_message, e))?
+            },
+            None => {
+                // Le doc n'existe pas, probablement un vieux message durant regeneration.
+                return Ok(None)
+            }
+        },
+        Err(e) => Err(format!("transactions.transfert_complete Erreur update pour transfert complete {} : {:?}", uuid_message, e))?
+    };
+
+    // Mettre usagers completes dans outgoing
+    if let Some(destinataires) = &outgoing_processing.destinataires {
+        debug!("transactions.transfert_complete Marquer destinataires pour message {} : {:?}", uuid_message, destinataires);
+        let mut set_ops = doc!{};
+        for destinataire in destinataires {
+            if let Some(result) = destinataire.result {
+                let destinataire_key = destinataire.destinataire.replace(".", ",");  // Mapping key dans table mongo
+                set_ops.insert(format!("destinataires.{}", destinataire_key), result);
+            }
+        }
+        if set_ops.len() > 0 {
+            let filtre = doc! {"message.id": uuid_message};
+            let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+            let ops = doc! {
+                "$set": set_ops,
+                "$currentDate": {CHAMP_MODIFICATION: true}
+            };
+            if let Err(e) = collection.update_one(filtre, ops, None).await {
+                error!("transactions.transfert_complete Erreur update destinataires : {}", uuid_message);
+            }
+        }
+    }
+
+    let message_complete = verifier_message_complete(middleware, &outgoing_processing);
+    if message_complete {
+        debug!("transfert_complete Conserve flag message complete dans outgoing");
+        let filtre = doc!{
+            "message.id": uuid_message,
+            "user_id": outgoing_processing.user_id.as_ref(),
+        };
+        let ops = doc!{
+            "$set": {"transfert_complete": true},
+            "$currentDate": {CHAMP_MODIFICATION: true}
+        };
+        let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+        match collection.update_one(filtre, ops, None).await {
+            Ok(_r) => (),
+            Err(e) => error!("transactions.transfert_complete Erreur update message outgoing : {:?}", e)
+        };
+
+        debug!("transfert_complete Emettre evenement message complete");
+        if let Some(user_id) = outgoing_processing.user_id {
+            let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_CONFIRMER_MESSAGE_COMPLETE)
+                .exchanges(vec![Securite::L2Prive])
+                .partition(user_id.clone())
+                .build();
+            let message = ConfirmerMessageComplete { user_id, message_id: uuid_message.to_owned() };
+            middleware.emettre_evenement(routage, &message).await?;
+        }
+    }
+
+    Ok(None)
+}
+
+async fn transaction_etoile<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_etoile Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_etoile: TransactionEtoile = match transaction.clone().convertir::<TransactionEtoile>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_etoile Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_etoile Certificat sans user_id, transaction {} invalide", uuid_transaction))?
+        },
+        None => Err(format!("transactions.transaction_etoile Message sans certificat, transaction {} invalide", uuid_transaction))?
+    };
+
+    let flag_etoile = transaction_etoile.etoile;
+    let message_ids = transaction_etoile.message_ids;
+    let messages_envoyes = transaction_etoile.messages_envoyes == Some(true);
+
+    let nom_collection = match messages_envoyes {
+        true => NOM_COLLECTION_OUTGOING,
+        false => NOM_COLLECTION_INCOMING
+    };
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": {"$in": &message_ids}};
+    let ops = doc! {
+        "$set": {CHAMP_ETOILE: flag_etoile},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+
+    let collection = middleware.get_collection(nom_collection)?;
+    match collection.update_many(filtre, ops, None).await {
+        Ok(r) => debug!("transaction_etoile Resultat : {:?}", r),
+        Err(e) => Err(format!("transactions.transaction_etoile Erreur maj flag etoile {} : {:?}", uuid_transaction, e))?
+    }
+
+    // Emettre evenement etoile
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MESSAGE_ETOILE)
+        .exchanges(vec![L2Prive])
+        .partition(&user_id)
+        .build();
+    let etoiles: HashMap<&String, bool> = message_ids.iter().map(|m| (m, flag_etoile)).collect();
+    let evenement_etoile = json!({
+        "etoiles": etoiles,
+        "messages_envoyes": messages_envoyes,
+    });
+    middleware.emettre_evenement(routage, &evenement_etoile).await?;
+
+    middleware.reponse_ok()
+}
+
+async fn supprimer_message<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("supprimer_message Consommer transaction : {:?}", &transaction);
+
+    let message_id = transaction.get_uuid_transaction().to_owned();
+    let date_supprime = transaction.get_estampille().to_owned();
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.supprimer_message Certificat sans user_id, transaction {} invalide", message_id))?
+        },
+        None => Err(format!("transactions.supprimer_message Message sans certificat, transaction {} invalide", message_id))?
+    };
+
+    let transaction_mappee = match transaction.convertir::<TransactionSupprimerMessage>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.supprimer_message Erreur conversion transaction : {:?}", e))?
+    };
+
+    let message_ids = transaction_mappee.message_ids;
+    let messages_envoyes = transaction_mappee.messages_envoyes == Some(true);
+
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": {"$in": &message_ids}, CHAMP_SUPPRIME: false};
+    let ops = doc! {
+        "$set": { CHAMP_SUPPRIME: true, CHAMP_DATE_SUPPRIME: date_supprime },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+
+    debug!("supprimer_message filtre : {:?}, ops: {:?}", filtre, ops);
+
+    let mut deltas_usage = HashMap::new();
+    match messages_envoyes {
+        true => {
+            let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+            match collection.update_many(filtre, ops, None).await {
+                Ok(r) => debug!("supprimer_message Resultat : {:?}", r),
+                Err(e) => Err(format!("transactions.supprimer_message Erreur update pour transfert complete {} : {:?}", message_id, e))?
+            }
+        },
+        false => {
+            // Les messages recus supprimes sont retires de l'usage de la boite
+            match modifier_incoming_usage(middleware, filtre, ops, -1).await {
+                Ok((compte, deltas)) => {
+                    debug!("supprimer_message {} messages supprimes", compte);
+                    deltas_usage = deltas;
+                },
+                Err(e) => Err(format!("transactions.supprimer_message Erreur update pour transfert complete {} : {:?}", message_id, e))?
+            }
+        }
+    }
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MESSAGES_SUPPRIMES)
+        .exchanges(vec![L2Prive])
+        .partition(&user_id)
+        .build();
+    let evenement_supprime = json!({
+        "message_ids": &message_ids,
+        "messages_envoyes": messages_envoyes,
+    });
+    middleware.emettre_evenement(routage, &evenement_supprime).await?;
+
+    maj_usage_boites(middleware, &deltas_usage).await;
+
+    middleware.reponse_ok()
+}
+
+/// Archive (archiver == true) ou desarchive des messages recus. Les messages archives sont
+/// retires de la boite de reception, ils conservent leurs fichiers et restent accessibles.
+async fn transaction_archiver_messages<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T, archiver: bool) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_archiver_messages Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_archiver: TransactionArchiverMessages = match transaction.clone().convertir::<TransactionArchiverMessages>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_archiver_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.transaction_archiver_messages Certificat sans user_id, transaction {} invalide", uuid_transaction))?
+        },
+        None => Err(format!("transactions.transaction_archiver_messages Message sans certificat, transaction {} invalide", uuid_transaction))?
+    };
+
+    let message_ids = transaction_archiver.message_ids;
+
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": {"$in": &message_ids}};
+    let ops = match archiver {
+        true => {
+            let date_archive: Bson = DateEpochSeconds::from(transaction.get_estampille().to_owned()).into();
+            doc! {
+                "$set": {CHAMP_ARCHIVE: true, CHAMP_DATE_ARCHIVE: date_archive},
+                "$currentDate": {CHAMP_MODIFICATION: true},
+            }
+        },
+        false => doc! {
+            "$unset": {CHAMP_ARCHIVE: true, CHAMP_DATE_ARCHIVE: true},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        }
+    };
+
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    match collection.update_many(filtre, ops, None).await {
+        Ok(r) => debug!("transaction_archiver_messages Resultat : {:?}", r),
+        Err(e) => Err(format!("transactions.transaction_archiver_messages Erreur update archive {} : {:?}", uuid_transaction, e))?
+    }
+
+    let action_evenement = match archiver {
+        true => EVENEMENT_MESSAGES_ARCHIVES,
+        false => EVENEMENT_MESSAGES_DESARCHIVES
+    };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, action_evenement)
+        .exchanges(vec![L2Prive])
+        .partition(&user_id)
+        .build();
+    let evenement = json!({
+        "message_ids": &message_ids,
+    });
+    middleware.emettre_evenement(routage, &evenement).await?;
+
+    middleware.reponse_ok()
+}
+
+async fn restaurer_messages<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("restaurer_messages Consommer transaction : {:?}", &transaction);
+
+    let message_id = transaction.get_uuid_transaction().to_owned();
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.restaurer_messages Certificat sans user_id, transaction {} invalide", message_id))?
+        },
+        None => Err(format!("transactions.restaurer_messages Message sans certificat, transaction {} invalide", message_id))?
+    };
+
+    let transaction_mappee = match transaction.convertir::<TransactionRestaurerMessages>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.restaurer_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let message_ids = transaction_mappee.message_ids;
+    let messages_envoyes = transaction_mappee.messages_envoyes == Some(true);
+
+    let filtre = doc! {CHAMP_USER_ID: &user_id, "message.id": {"$in": &message_ids}, CHAMP_SUPPRIME: true};
+    let ops = doc! {
+        "$set": { CHAMP_SUPPRIME: false },
+        "$unset": { CHAMP_DATE_SUPPRIME: true },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+
+    let mut deltas_usage = HashMap::new();
+    match messages_envoyes {
+        true => {
+            let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+            match collection.update_many(filtre, ops, None).await {
+                Ok(r) => debug!("restaurer_messages Resultat : {:?}", r),
+                Err(e) => Err(format!("transactions.restaurer_messages Erreur update {} : {:?}", message_id, e))?
+            }
+        },
+        false => {
+            // Les messages recus restaures sont remis dans l'usage de la boite
+            match modifier_incoming_usage(middleware, filtre, ops, 1).await {
+                Ok((compte, deltas)) => {
+                    debug!("restaurer_messages {} messages restaures", compte);
+                    deltas_usage = deltas;
+                },
+                Err(e) => Err(format!("transactions.restaurer_messages Erreur update {} : {:?}", message_id, e))?
+            }
+        }
+    }
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MESSAGES_RESTAURES)
+        .exchanges(vec![L2Prive])
+        .partition(&user_id)
+        .build();
+    let evenement_restaure = json!({
+        "message_ids": &message_ids,
+        "messages_envoyes": messages_envoyes,
+    });
+    middleware.emettre_evenement(routage, &evenement_restaure).await?;
+
+    maj_usage_boites(middleware, &deltas_usage).await;
+
+    middleware.reponse_ok()
+}
+
+/// Supprime definitivement les messages purges de la corbeille. Seuls les messages encore
+/// supprimes sont retires (un message restaure avant la purge est conserve).
+async fn transaction_purger_messages<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("transaction_purger_messages Consommer transaction : {:?}", &transaction);
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+
+    let transaction_mappee = match transaction.convertir::<TransactionPurgerMessages>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_purger_messages Erreur conversion transaction : {:?}", e))?
+    };
+
+    let collection_incoming = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    let collection_outgoing = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+
+    // Les messages supprimes ne comptent pas dans l'usage des boites, aucune mise a jour requise
+    for message in &transaction_mappee.messages {
+        let filtre = doc! {
+            CHAMP_USER_ID: &message.user_id,
+            "message.id": &message.message_id,
+            CHAMP_SUPPRIME: true,
+        };
+        let collection = match message.messages_envoyes {
+            true => &collection_outgoing,
+            false => &collection_incoming,
+        };
+        match collection.delete_one(filtre, None).await {
+            Ok(r) => debug!("transaction_purger_messages Message {} : {:?}", message.message_id, r),
+            Err(e) => Err(format!("transactions.transaction_purger_messages Erreur purge message {} (transaction {}) : {:?}", message.message_id, uuid_transaction, e))?
+        }
+    }
+
+    middleware.reponse_ok()
+}
+
+async fn supprimer_contacts<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T) -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("supprimer_contacts Consommer transaction : {:?}", &transaction);
+
+    let uuid_transaction = transaction.get_uuid_transaction().to_owned();
+    let user_id = match transaction.get_enveloppe_certificat() {
+        Some(e) => match e.get_user_id()?.to_owned() {
+            Some(u) => u,
+            None => Err(format!("transactions.supprimer_contacts Certificat sans user_id, transaction {} invalide", uuid_transaction))?
+        },
+        None => Err(format!("transactions.supprimer_contacts Message sans certificat, transaction {} invalide", uuid_transaction))?
+    };
+
+    let transaction_mappee = match transaction.convertir::<TransactionSupprimerContacts>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.supprimer_contacts Erreur conversion transaction : {:?}", e))?
+    };
+
+    let uuid_contacts = transaction_mappee.uuid_contacts;
+    let filtre = doc! {CHAMP_USER_ID: &user_id, CHAMP_UUID_CONTACT: {"$in": &uuid_contacts}};
+    let ops = doc! {
+        "$set": { CHAMP_SUPPRIME: true },
         "$currentDate": {CHAMP_MODIFICATION: true},
     };
 
@@ -1138,6 +2372,174 @@ async fn conserver_configuration_notifications<M, T>(gestionnaire: &Gestionnaire
     middleware.reponse_ok()
 }
 
+async fn conserver_configuration_limites_reception<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("conserver_configuration_limites_reception Consommer transaction : {:?}", &transaction);
+    let transaction_mappee = match transaction.convertir::<TransactionConserverConfigurationLimitesReception>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.conserver_configuration_limites_reception Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc!{ CHAMP_CONFIG_KEY: CONFIG_KEY_LIMITES_RECEPTION };
+    let set_on_insert = doc!{
+        CHAMP_CREATION: Utc::now(),
+        CHAMP_CONFIG_KEY: CONFIG_KEY_LIMITES_RECEPTION,
+    };
+
+    let set_ops = match convertir_to_bson(transaction_mappee) {
+        Ok(d) => d,
+        Err(e) => Err(format!("transactions.conserver_configuration_limites_reception Erreur conversion limites a bson : {:?}", e))?
+    };
+
+    let ops = doc! {
+        "$set": set_ops,
+        "$setOnInsert": set_on_insert,
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+
+    let options = UpdateOptions::builder()
+        .upsert(true)
+        .build();
+
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    match collection.update_one(filtre, ops, Some(options)).await {
+        Ok(_d) => (),
+        Err(e) => Err(format!("transactions.conserver_configuration_limites_reception Erreur sauvegarde configuration : {:?}", e))?
+    }
+
+    middleware.reponse_ok()
+}
+
+async fn conserver_configuration_quotas<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("conserver_configuration_quotas Consommer transaction : {:?}", &transaction);
+    let transaction_mappee = match transaction.convertir::<TransactionConserverConfigurationQuotas>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.conserver_configuration_quotas Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc!{ CHAMP_CONFIG_KEY: CONFIG_KEY_QUOTAS_BOITES };
+    let set_on_insert = doc!{
+        CHAMP_CREATION: Utc::now(),
+        CHAMP_CONFIG_KEY: CONFIG_KEY_QUOTAS_BOITES,
+    };
+
+    let set_ops = match convertir_to_bson(transaction_mappee) {
+        Ok(d) => d,
+        Err(e) => Err(format!("transactions.conserver_configuration_quotas Erreur conversion quotas a bson : {:?}", e))?
+    };
+
+    let ops = doc! {
+        "$set": set_ops,
+        "$setOnInsert": set_on_insert,
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+
+    let options = UpdateOptions::builder()
+        .upsert(true)
+        .build();
+
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    match collection.update_one(filtre, ops, Some(options)).await {
+        Ok(_d) => (),
+        Err(e) => Err(format!("transactions.conserver_configuration_quotas Erreur sauvegarde configuration : {:?}", e))?
+    }
+
+    middleware.reponse_ok()
+}
+
+async fn conserver_configuration_corbeille<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("conserver_configuration_corbeille Consommer transaction : {:?}", &transaction);
+    let transaction_mappee = match transaction.convertir::<TransactionConserverConfigurationCorbeille>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.conserver_configuration_corbeille Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc!{ CHAMP_CONFIG_KEY: CONFIG_KEY_CORBEILLE };
+    let set_on_insert = doc!{
+        CHAMP_CREATION: Utc::now(),
+        CHAMP_CONFIG_KEY: CONFIG_KEY_CORBEILLE,
+    };
+
+    let set_ops = match convertir_to_bson(transaction_mappee) {
+        Ok(d) => d,
+        Err(e) => Err(format!("transactions.conserver_configuration_corbeille Erreur conversion configuration corbeille a bson : {:?}", e))?
+    };
+
+    let ops = doc! {
+        "$set": set_ops,
+        "$setOnInsert": set_on_insert,
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+
+    let options = UpdateOptions::builder()
+        .upsert(true)
+        .build();
+
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    match collection.update_one(filtre, ops, Some(options)).await {
+        Ok(_d) => (),
+        Err(e) => Err(format!("transactions.conserver_configuration_corbeille Erreur sauvegarde configuration : {:?}", e))?
+    }
+
+    middleware.reponse_ok()
+}
+
+async fn conserver_politique_federation<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T)
+    -> Result<Option<MessageMilleGrille>, String>
+    where
+        M: GenerateurMessages + MongoDao + ValidateurX509,
+        T: Transaction
+{
+    debug!("conserver_politique_federation Consommer transaction : {:?}", &transaction);
+    let transaction_mappee = match transaction.convertir::<TransactionConserverPolitiqueFederation>() {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.conserver_politique_federation Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc!{ CHAMP_CONFIG_KEY: CONFIG_KEY_POLITIQUE_FEDERATION };
+    let set_on_insert = doc!{
+        CHAMP_CREATION: Utc::now(),
+        CHAMP_CONFIG_KEY: CONFIG_KEY_POLITIQUE_FEDERATION,
+    };
+
+    let set_ops = match convertir_to_bson(transaction_mappee) {
+        Ok(d) => d,
+        Err(e) => Err(format!("transactions.conserver_politique_federation Erreur conversion politique a bson : {:?}", e))?
+    };
+
+    let ops = doc! {
+        "$set": set_ops,
+        "$setOnInsert": set_on_insert,
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+
+    let options = UpdateOptions::builder()
+        .upsert(true)
+        .build();
+
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    match collection.update_one(filtre, ops, Some(options)).await {
+        Ok(_d) => (),
+        Err(e) => Err(format!("transactions.conserver_politique_federation Erreur sauvegarde configuration : {:?}", e))?
+    }
+
+    middleware.reponse_ok()
+}
+
 async fn sauvegarder_clewebpush_notifications<M, T>(gestionnaire: &GestionnaireMessagerie, middleware: &M, transaction: T)
     -> Result<Option<MessageMilleGrille>, String>
     where
@@ -1456,4 +2858,26 @@ async fn conserver_notification<M, T>(gestionnaire: &GestionnaireMessagerie, mid
     }
 
     Ok(middleware.reponse_ok()?)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test_transactions {
+    use crate::test_setup::setup;
+
+    use super::*;
+
+    #[test]
+    fn test_user_id_poster_domaine() {
+        setup("test_user_id_poster_domaine");
+        // Reponse automatique : certificat du domaine (sans user_id), usager dans la commande
+        let user_id = "zUsager1".to_string();
+        assert_eq!(Some(user_id.clone()), resoudre_user_id_poster(None, Some(&user_id)));
+    }
+
+    #[test]
+    fn test_user_id_poster_certificat_prioritaire() {
+        setup("test_user_id_poster_certificat_prioritaire");
+        let autre = "zAutre".to_string();
+        assert_eq!(Some("zUsager1".to_string()), resoudre_user_id_poster(Some("zUsager1".into()), Some(&autre)));
+        assert_eq!(None, resoudre_user_id_poster(None, None));
+    }
+}
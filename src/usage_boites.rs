@@ -0,0 +1,351 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::constantes::Securite::L2Prive;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json::json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::constantes::*;
+use crate::message_structs::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DocUsageIncoming {
+    user_id: String,
+    taille: Option<i64>,
+    fichiers: Option<HashMap<String, bool>>,
+    tailles_fichiers: Option<HashMap<String, i64>>,
+}
+
+/// Charge les quotas de boites configures. Aucun quota n'est applique si la configuration est absente.
+pub async fn charger_configuration_quotas<M>(middleware: &M)
+    -> Result<TransactionConserverConfigurationQuotas, Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_CONFIG_KEY: CONFIG_KEY_QUOTAS_BOITES };
+    let collection = middleware.get_collection(NOM_COLLECTION_CONFIGURATION)?;
+    match collection.find_one(filtre, None).await? {
+        Some(d) => Ok(convertir_bson_deserializable(d)?),
+        None => Ok(TransactionConserverConfigurationQuotas {
+            quota_souple_octets: None,
+            quota_dur_octets: None,
+            quota_souple_messages: None,
+            quota_dur_messages: None,
+        })
+    }
+}
+
+pub async fn charger_usage_boite<M>(middleware: &M, user_id: &str) -> Result<Option<DocUsageBoite>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_USAGE_BOITES)?;
+    match collection.find_one(doc! {CHAMP_USER_ID: user_id}, None).await? {
+        Some(d) => Ok(Some(convertir_bson_deserializable(d)?)),
+        None => Ok(None)
+    }
+}
+
+/// Compte les messages avec etoile (non supprimes) de l'usager. Retourne (recus, envoyes).
+pub async fn compter_etoiles<M>(middleware: &M, user_id: &str) -> Result<(u64, u64), Box<dyn Error>>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_SUPPRIME: false, CHAMP_ETOILE: true };
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+    let recus = collection.count_documents(filtre.clone(), None).await?;
+    let collection = middleware.get_collection(NOM_COLLECTION_OUTGOING)?;
+    let envoyes = collection.count_documents(filtre, None).await?;
+    Ok((recus, envoyes))
+}
+
+/// Variation de l'usage d'une boite. Les fichiers sont comptes par message (un fichier attache
+/// a deux messages compte deux fois), ce qui permet de maintenir l'usage par increments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeltaUsageBoite {
+    pub messages: i64,
+    pub taille_messages: i64,
+    pub fuuids: i64,
+    pub taille_fuuids: i64,
+}
+
+impl DeltaUsageBoite {
+    /// Usage d'un message recu.
+    pub fn message(taille: Option<i64>, fichiers: Option<&HashMap<String, bool>>, tailles_fichiers: Option<&HashMap<String, i64>>) -> Self {
+        let fuuids = match fichiers {
+            Some(inner) => inner.len() as i64,
+            None => 0
+        };
+        let taille_fuuids = match (fichiers, tailles_fichiers) {
+            (Some(fichiers), Some(tailles)) => fichiers.keys().filter_map(|f| tailles.get(f)).sum(),
+            _ => 0
+        };
+        DeltaUsageBoite { messages: 1, taille_messages: taille.unwrap_or(0), fuuids, taille_fuuids }
+    }
+
+    fn incoming(doc_incoming: &DocUsageIncoming) -> Self {
+        DeltaUsageBoite::message(doc_incoming.taille, doc_incoming.fichiers.as_ref(), doc_incoming.tailles_fichiers.as_ref())
+    }
+
+    pub fn ajouter(&mut self, autre: &DeltaUsageBoite) {
+        self.messages += autre.messages;
+        self.taille_messages += autre.taille_messages;
+        self.fuuids += autre.fuuids;
+        self.taille_fuuids += autre.taille_fuuids;
+    }
+
+    pub fn inverser(&self) -> DeltaUsageBoite {
+        DeltaUsageBoite {
+            messages: -self.messages,
+            taille_messages: -self.taille_messages,
+            fuuids: -self.fuuids,
+            taille_fuuids: -self.taille_fuuids,
+        }
+    }
+
+    pub fn est_vide(&self) -> bool {
+        self == &DeltaUsageBoite::default()
+    }
+}
+
+/// Recalcule l'usage de la boite de l'usager a partir des messages recus (non supprimes).
+/// L'usage est normalement maintenu par increments (incrementer_usage_boite). Le calcul complet
+/// sert a l'initialisation et a l'entretien (correction des ecarts, regeneration).
+pub async fn calculer_usage_boite<M>(middleware: &M, user_id: &str) -> Result<DocUsageBoite, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("calculer_usage_boite Calculer usage boite usager {}", user_id);
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_SUPPRIME: false };
+    let options = FindOptions::builder()
+        .projection(doc! {CHAMP_USER_ID: 1, "taille": 1, CHAMP_FICHIERS: 1, CHAMP_TAILLES_FICHIERS: 1})
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+
+    let mut total = DeltaUsageBoite::default();
+    let mut curseur = collection.find(filtre, Some(options)).await?;
+    while let Some(r) = curseur.next().await {
+        let doc_incoming: DocUsageIncoming = convertir_bson_deserializable(r?)?;
+        total.ajouter(&DeltaUsageBoite::incoming(&doc_incoming));
+    }
+
+    let ops = doc! {
+        "$set": {
+            "messages": total.messages,
+            "taille_messages": total.taille_messages,
+            "fuuids": total.fuuids,
+            "taille_fuuids": total.taille_fuuids,
+        },
+        "$setOnInsert": {CHAMP_USER_ID: user_id, CHAMP_CREATION: Utc::now()},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_USAGE_BOITES)?;
+    let usage: DocUsageBoite = match collection.find_one_and_update(doc! {CHAMP_USER_ID: user_id}, ops, Some(options)).await? {
+        Some(d) => convertir_bson_deserializable(d)?,
+        None => Err(format!("usage_boites.calculer_usage_boite Usage boite {} non sauvegarde", user_id))?
+    };
+
+    verifier_avertissement_quota(middleware, usage).await
+}
+
+/// Applique une variation a l'usage de la boite de l'usager. L'usage est recalcule au complet
+/// lorsque la boite n'a pas encore de document d'usage.
+pub async fn incrementer_usage_boite<M>(middleware: &M, user_id: &str, delta: &DeltaUsageBoite) -> Result<DocUsageBoite, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("incrementer_usage_boite Usager {} : {:?}", user_id, delta);
+    let ops = doc! {
+        "$inc": {
+            "messages": delta.messages,
+            "taille_messages": delta.taille_messages,
+            "fuuids": delta.fuuids,
+            "taille_fuuids": delta.taille_fuuids,
+        },
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_USAGE_BOITES)?;
+    match collection.find_one_and_update(doc! {CHAMP_USER_ID: user_id}, ops, Some(options)).await? {
+        Some(d) => {
+            let usage: DocUsageBoite = convertir_bson_deserializable(d)?;
+            verifier_avertissement_quota(middleware, usage).await
+        },
+        None => calculer_usage_boite(middleware, user_id).await
+    }
+}
+
+/// Applique des variations a l'usage de plusieurs boites. Les erreurs sont journalisees sans
+/// interrompre le traitement (l'entretien corrige les ecarts).
+pub async fn maj_usage_boites<M>(middleware: &M, deltas: &HashMap<String, DeltaUsageBoite>)
+    where M: GenerateurMessages + MongoDao
+{
+    for (user_id, delta) in deltas {
+        if delta.est_vide() {
+            continue;
+        }
+        if let Err(e) = incrementer_usage_boite(middleware, user_id.as_str(), delta).await {
+            warn!("maj_usage_boites Erreur maj usage boite {} : {:?}", user_id, e);
+        }
+    }
+}
+
+/// Modifie les messages recus un a la fois (find_one_and_update) et retourne la variation
+/// d'usage par usager, calculee a partir de l'etat precedent de chaque message. Retourne aussi
+/// le nombre de messages modifies.
+/// Les ops doivent retirer le document du filtre (e.g. changer CHAMP_SUPPRIME), sinon le
+/// meme document serait traite indefiniment. Le signe est negatif pour un retrait de la boite.
+pub async fn modifier_incoming_usage<M>(middleware: &M, filtre: Document, ops: Document, signe: i64)
+    -> Result<(u64, HashMap<String, DeltaUsageBoite>), Box<dyn Error>>
+    where M: MongoDao
+{
+    let options = FindOneAndUpdateOptions::builder()
+        .projection(doc! {CHAMP_USER_ID: 1, "taille": 1, CHAMP_FICHIERS: 1, CHAMP_TAILLES_FICHIERS: 1})
+        .return_document(ReturnDocument::Before)
+        .build();
+    let collection = middleware.get_collection(NOM_COLLECTION_INCOMING)?;
+
+    let mut compte = 0;
+    let mut deltas: HashMap<String, DeltaUsageBoite> = HashMap::new();
+    while let Some(d) = collection.find_one_and_update(filtre.clone(), ops.clone(), Some(options.clone())).await? {
+        let doc_incoming: DocUsageIncoming = convertir_bson_deserializable(d)?;
+        let mut delta = DeltaUsageBoite::incoming(&doc_incoming);
+        if signe < 0 {
+            delta = delta.inverser();
+        }
+        deltas.entry(doc_incoming.user_id).or_default().ajouter(&delta);
+        compte += 1;
+    }
+
+    Ok((compte, deltas))
+}
+
+/// Conserve l'etat d'avertissement du quota souple. Lors du passage au-dela du quota, emet
+/// l'evenement pour le front-end et une notification (email, web push) a l'usager.
+async fn verifier_avertissement_quota<M>(middleware: &M, mut usage: DocUsageBoite) -> Result<DocUsageBoite, Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    let quotas = charger_configuration_quotas(middleware).await?;
+    let depasse = quotas.depasse_souple(&usage);
+    if usage.avertissement_quota == Some(depasse) {
+        return Ok(usage)
+    }
+    usage.avertissement_quota = Some(depasse);
+
+    // Filtre sur l'etat precedent, une seule mise a jour concurrente emet l'avertissement
+    let filtre = doc! { CHAMP_USER_ID: &usage.user_id, CHAMP_AVERTISSEMENT_QUOTA: {"$ne": depasse} };
+    let ops = doc! { "$set": {CHAMP_AVERTISSEMENT_QUOTA: depasse} };
+    let collection = middleware.get_collection(NOM_COLLECTION_USAGE_BOITES)?;
+    let resultat = collection.update_one(filtre, ops, None).await?;
+
+    if depasse && resultat.modified_count > 0 {
+        info!("verifier_avertissement_quota Quota souple depasse pour usager {} : {:?}", usage.user_id, usage);
+        let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_AVERTISSEMENT_QUOTA)
+            .exchanges(vec![L2Prive])
+            .partition(&usage.user_id)
+            .build();
+        let evenement = json!({"usage": &usage, "quotas": &quotas});
+        middleware.emettre_evenement(routage, &evenement).await?;
+
+        if let Err(e) = ajouter_notification_quota(middleware, usage.user_id.as_str()).await {
+            warn!("verifier_avertissement_quota Erreur notification quota usager {} : {:?}", usage.user_id, e);
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Ajoute l'avertissement de quota aux notifications en attente de l'usager (si les notifications
+/// sont actives). L'avertissement est emis avec les prochaines notifications de messages.
+async fn ajouter_notification_quota<M>(middleware: &M, user_id: &str) -> Result<(), Box<dyn Error>>
+    where M: MongoDao
+{
+    let collection_profils = middleware.get_collection(NOM_COLLECTION_PROFILS)?;
+    let filtre = doc! {CHAMP_USER_ID: user_id, CHAMP_NOTIFICATIONS_ACTIVES: true};
+    if collection_profils.count_documents(filtre, None).await? == 0 {
+        debug!("ajouter_notification_quota Notifications inactives pour usager {}", user_id);
+        return Ok(())
+    }
+
+    let ops = doc! {
+        "$setOnInsert": {
+            CHAMP_CREATION: Utc::now(),
+            CHAMP_USER_ID: user_id,
+            CHAMP_EXPIRATION_LOCK_NOTIFICATIONS: Utc::now(),
+        },
+        "$set": {
+            CHAMP_AVERTISSEMENT_QUOTA: true,
+            CHAMP_NOTIFICATIONS_PENDING: true,
+        },
+        "$currentDate": {
+            CHAMP_MODIFICATION: true,
+            CHAMP_DERNIERE_NOTIFICATION: true,
+        }
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(NOM_COLLECTION_NOTIFICATIONS_OUTGOING)?;
+    collection.update_one(doc! {CHAMP_USER_ID: user_id}, ops, Some(options)).await?;
+
+    Ok(())
+}
+
+/// Entretien : recalcule l'usage de toutes les boites pour corriger les ecarts des increments.
+pub async fn entretien_usage_boites<M>(middleware: &M) -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("entretien_usage_boites Debut");
+    let mut user_ids = HashSet::new();
+    for nom_collection in [NOM_COLLECTION_INCOMING, NOM_COLLECTION_USAGE_BOITES] {
+        let collection = middleware.get_collection(nom_collection)?;
+        for u in collection.distinct(CHAMP_USER_ID, doc! {}, None).await? {
+            if let Some(u) = u.as_str() {
+                user_ids.insert(u.to_owned());
+            }
+        }
+    }
+
+    for user_id in user_ids {
+        if let Err(e) = calculer_usage_boite(middleware, user_id.as_str()).await {
+            warn!("entretien_usage_boites Erreur calcul usage boite {} : {:?}", user_id, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Retire les destinataires dont la boite a atteint le quota dur (user_id: None) avec le
+/// code CODE_QUOTA_DEPASSE. L'expediteur recoit un resultat de non-livraison.
+pub async fn verifier_quotas_destinataires<M>(middleware: &M, destinataires: Vec<DestinataireInfo>)
+    -> Result<Vec<DestinataireInfo>, Box<dyn Error>>
+    where M: MongoDao
+{
+    let quotas = charger_configuration_quotas(middleware).await?;
+    if quotas.quota_dur_octets.is_none() && quotas.quota_dur_messages.is_none() {
+        return Ok(destinataires)
+    }
+
+    let mut resultat = Vec::new();
+    for mut d in destinataires.into_iter() {
+        if let Some(user_id) = d.user_id.as_ref() {
+            if let Some(usage) = charger_usage_boite(middleware, user_id.as_str()).await? {
+                if quotas.depasse_dur(&usage) {
+                    info!("verifier_quotas_destinataires Quota dur atteint pour usager {}, message refuse", user_id);
+                    d.user_id = None;
+                    d.code = Some(CODE_QUOTA_DEPASSE);
+                }
+            }
+        }
+        resultat.push(d);
+    }
+
+    Ok(resultat)
+}